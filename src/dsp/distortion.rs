@@ -0,0 +1,124 @@
+//! Per-voice waveshaper and bitcrusher.
+//!
+//! Both stages can be placed before or after the voice filter. They are part of the voice path, so
+//! they run at the oversampled rate, which keeps the harmonics they generate from aliasing.
+
+use crate::{ parameters::PARAMETERS, wave_math::TAU, * };
+
+/// Highest bit depth of the bitcrusher, which leaves the signal practically untouched.
+pub const MAX_CRUSH_BITS: u8 = 16;
+pub const MIN_CRUSH_RATE: f32 = 200.0;
+pub const MAX_CRUSH_RATE: f32 = 44100.0;
+
+/// Cutoff of the DC blocker following the asymmetric curves on the wet signal, in Hz.
+const DC_BLOCKER_CUTOFF: f32 = 10.0;
+/// Offset of the tube curve's operating point, producing even harmonics.
+const TUBE_BIAS: f32 = 0.3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShaperCurve {
+    Tanh,
+    HardClip,
+    Foldback,
+    Tube,
+}
+
+impl ShaperCurve {
+    pub fn from_parameter(value: f32) -> Self {
+        match PARAMETERS[DISTORTION_SHAPE].step(value) {
+            0 => ShaperCurve::Tanh,
+            1 => ShaperCurve::HardClip,
+            2 => ShaperCurve::Foldback,
+            _ => ShaperCurve::Tube,
+        }
+    }
+
+    fn shape(self, input: f32) -> f32 {
+        match self {
+            ShaperCurve::Tanh => input.tanh(),
+            ShaperCurve::HardClip => input.clamp(-1.0, 1.0),
+            // reflects the signal back at +-1 as often as needed
+            ShaperCurve::Foldback => 1.0 - (((input + 1.0).rem_euclid(4.0)) - 2.0).abs(),
+            ShaperCurve::Tube => (input + TUBE_BIAS).tanh() - TUBE_BIAS.tanh(),
+        }
+    }
+}
+
+/// Whether a stage is placed between the mixer and the filter, or after the filter.
+pub fn is_pre_filter(value: f32) -> bool {
+    value < 0.5
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Waveshaper {
+    dc_input: f32,
+    dc_output: f32,
+}
+
+impl Waveshaper {
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn process(&mut self, input: f32, p: &[f32], sample_rate: f32) -> f32 {
+        let curve = ShaperCurve::from_parameter(p[DISTORTION_SHAPE]);
+        let gain = 1.0 + p[DISTORTION_DRIVE] * MAX_DISTORTION_DRIVE;
+        let mix = p[DISTORTION_MIX];
+
+        let shaped = curve.shape(input * gain);
+
+        // the asymmetric curve leaves an offset behind, which is removed from the wet signal only
+        // so that the dry signal passes through untouched
+        let coefficient = 1.0 - (TAU * DC_BLOCKER_CUTOFF) / sample_rate;
+        self.dc_output = shaped - self.dc_input + coefficient * self.dc_output;
+        self.dc_input = shaped;
+
+        input + (self.dc_output - input) * mix
+    }
+}
+
+/// Bit depth and sample rate reduction.
+#[derive(Debug, Clone, Default)]
+pub struct Bitcrusher {
+    /// Progress towards the next held sample, from 0 to 1.
+    phase: f32,
+    held: f32,
+}
+
+impl Bitcrusher {
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn process(&mut self, input: f32, p: &[f32], sample_rate: f32) -> f32 {
+        let step = 2.0 / PARAMETERS[CRUSH_BITS].plain(p[CRUSH_BITS]).exp2();
+        let increment = PARAMETERS[CRUSH_RATE].plain(p[CRUSH_RATE]) / sample_rate;
+
+        self.phase += increment;
+        if self.phase >= 1.0 {
+            self.phase -= self.phase.floor();
+            self.held = (input / step).round() * step;
+        }
+        input + (self.held - input) * p[CRUSH_MIX]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parameters::default_values;
+
+    #[test]
+    fn dry_mix_passes_the_signal_through() {
+        let mut p = default_values();
+        p[DISTORTION_MIX] = 0.0;
+        p[DISTORTION_DRIVE] = 1.0;
+        p[DISTORTION_SHAPE] = 1.0;
+        let mut shaper = Waveshaper::default();
+        for i in 0..1000 {
+            // a sine with an offset, which the DC blocker would remove
+            let input = 0.5 + 0.4 * (i as f32 * 0.01).sin();
+            assert_eq!(shaper.process(input, &p, 44100.0), input);
+        }
+    }
+}
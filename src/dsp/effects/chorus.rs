@@ -0,0 +1,43 @@
+//! Stereo chorus. Each channel runs through its own modulated delay line, with the modulation of
+//! the right channel a quarter cycle behind the left one for a wide ensemble sound.
+
+use super::DelayLine;
+use crate::{ parameters::PARAMETERS, wave_math::TAU, * };
+
+/// Delay around which the modulation swings, in seconds.
+const BASE_DELAY: f32 = 0.007;
+/// Modulation depth at full depth setting, in seconds.
+const MAX_MOD_DELAY: f32 = 0.008;
+
+pub struct Chorus {
+    sample_rate: f32,
+    lines: [DelayLine; 2],
+    /// LFO phase in cycles.
+    phase: f32,
+}
+
+impl Chorus {
+    pub fn new(sample_rate: f32) -> Self {
+        let length = ((BASE_DELAY + MAX_MOD_DELAY) * sample_rate) as usize + 2;
+        Self {
+            sample_rate,
+            lines: [DelayLine::new(length), DelayLine::new(length)],
+            phase: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, frame: [f32; 2], parameter: &[f32]) -> [f32; 2] {
+        let depth = parameter[CHORUS_DEPTH] * MAX_MOD_DELAY;
+        let mut wet = [0.0; 2];
+        for (channel, line) in self.lines.iter_mut().enumerate() {
+            let lfo = ((self.phase + (channel as f32) * 0.25) * TAU).sin();
+            let delay = (BASE_DELAY + depth * 0.5 * (1.0 + lfo)) * self.sample_rate;
+            wet[channel] = line.read(delay);
+            line.write(frame[channel]);
+        }
+
+        let rate = PARAMETERS[CHORUS_RATE].plain(parameter[CHORUS_RATE]);
+        self.phase = (self.phase + rate / self.sample_rate).fract();
+        wet
+    }
+}
@@ -0,0 +1,39 @@
+//! Ping-pong delay. The mono sum of the input enters on the left, and every repeat bounces over
+//! to the other side. The delay time is either free in seconds, or synced to the host tempo as a
+//! note division.
+
+use super::DelayLine;
+use crate::{ dsp::transport::Transport, parameters::PARAMETERS, * };
+
+pub struct PingPongDelay {
+    sample_rate: f32,
+    lines: [DelayLine; 2],
+}
+
+impl PingPongDelay {
+    pub fn new(sample_rate: f32) -> Self {
+        let length = (MAX_DELAY_TIME * sample_rate) as usize + 2;
+        Self { sample_rate, lines: [DelayLine::new(length), DelayLine::new(length)] }
+    }
+
+    pub fn process(
+        &mut self,
+        frame: [f32; 2],
+        parameter: &[f32],
+        transport: &Transport
+    ) -> [f32; 2] {
+        let time = if parameter[DELAY_SYNC] >= 0.5 {
+            transport.synced_seconds(parameter[DELAY_TIME])
+        } else {
+            PARAMETERS[DELAY_TIME].plain(parameter[DELAY_TIME])
+        };
+        let delay = time.min(MAX_DELAY_TIME) * self.sample_rate;
+        let feedback = parameter[DELAY_FEEDBACK] * MAX_DELAY_FEEDBACK;
+
+        let left = self.lines[0].read(delay);
+        let right = self.lines[1].read(delay);
+        self.lines[0].write((frame[0] + frame[1]) * 0.5 + right * feedback);
+        self.lines[1].write(left * feedback);
+        [left, right]
+    }
+}
@@ -0,0 +1,211 @@
+//! Master bus effects, applied to the stereo sum of all voices.
+//!
+//! The chain holds one chorus, one ping-pong delay and one reverb. Each effect has its own bypass
+//! switch and wet/dry mix, and the order in which they are run can be changed at any time. Delay
+//! buffers are allocated whenever the sample rate changes, never while processing.
+
+use super::transport::Transport;
+use crate::*;
+
+mod chorus;
+use chorus::Chorus;
+
+mod delay;
+use delay::PingPongDelay;
+
+mod reverb;
+use reverb::Reverb;
+
+pub const NUM_EFFECTS: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EffectKind {
+    Chorus,
+    Delay,
+    Reverb,
+}
+
+impl EffectKind {
+    /// Inverse of `kind as usize`.
+    pub fn from_index(index: usize) -> Option<Self> {
+        DEFAULT_EFFECT_ORDER.get(index).copied()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EffectKind::Chorus => "Chorus",
+            EffectKind::Delay => "Delay",
+            EffectKind::Reverb => "Reverb",
+        }
+    }
+}
+
+pub const DEFAULT_EFFECT_ORDER: [EffectKind; NUM_EFFECTS] = [
+    EffectKind::Chorus,
+    EffectKind::Delay,
+    EffectKind::Reverb,
+];
+
+/// An effect order is only valid if every effect appears exactly once.
+pub fn is_valid_effect_order(order: &[EffectKind; NUM_EFFECTS]) -> bool {
+    DEFAULT_EFFECT_ORDER.iter().all(|kind| order.contains(kind))
+}
+
+pub struct EffectChain {
+    order: [EffectKind; NUM_EFFECTS],
+    chorus: Chorus,
+    delay: PingPongDelay,
+    reverb: Reverb,
+}
+
+impl EffectChain {
+    pub fn new(sample_rate: f32) -> Self {
+        Self {
+            order: DEFAULT_EFFECT_ORDER,
+            chorus: Chorus::new(sample_rate),
+            delay: PingPongDelay::new(sample_rate),
+            reverb: Reverb::new(sample_rate),
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        let order = self.order;
+        *self = Self::new(sample_rate);
+        self.order = order;
+    }
+
+    pub fn set_order(&mut self, order: [EffectKind; NUM_EFFECTS]) {
+        if is_valid_effect_order(&order) {
+            self.order = order;
+        }
+    }
+
+    pub fn process(
+        &mut self,
+        frame: [f32; 2],
+        parameter: &[f32],
+        transport: &Transport
+    ) -> [f32; 2] {
+        let mut frame = frame;
+        for kind in self.order.iter() {
+            let (bypass, mix) = match kind {
+                EffectKind::Chorus => (CHORUS_BYPASS, CHORUS_MIX),
+                EffectKind::Delay => (DELAY_BYPASS, DELAY_MIX),
+                EffectKind::Reverb => (REVERB_BYPASS, REVERB_MIX),
+            };
+            if parameter[bypass] >= 0.5 {
+                continue;
+            }
+
+            let wet = match kind {
+                EffectKind::Chorus => self.chorus.process(frame, parameter),
+                EffectKind::Delay => self.delay.process(frame, parameter, transport),
+                EffectKind::Reverb => self.reverb.process(frame, parameter),
+            };
+            let mix = parameter[mix];
+            frame = [
+                frame[0] * (1.0 - mix) + wet[0] * mix,
+                frame[1] * (1.0 - mix) + wet[1] * mix,
+            ];
+        }
+        frame
+    }
+}
+
+/// Circular buffer with fractional delay reads.
+pub struct DelayLine {
+    buffer: Vec<f32>,
+    write_pos: usize,
+}
+
+impl DelayLine {
+    pub fn new(length: usize) -> Self {
+        Self { buffer: vec![0.0; length.max(2)], write_pos: 0 }
+    }
+
+    /// Read the sample written `delay` samples ago, linearly interpolated.
+    pub fn read(&self, delay: f32) -> f32 {
+        let len = self.buffer.len();
+        let delay = delay.clamp(1.0, (len - 1) as f32);
+        let pos = (self.write_pos as f32) - delay + (len as f32);
+        let idx = (pos as usize) % len;
+        let fract = pos - pos.floor();
+        let current = self.buffer[idx];
+        let next = self.buffer[(idx + 1) % len];
+        current + (next - current) * fract
+    }
+
+    pub fn write(&mut self, sample: f32) {
+        self.buffer[self.write_pos] = sample;
+        self.write_pos = (self.write_pos + 1) % self.buffer.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parameters::default_values;
+
+    #[test]
+    fn delay_line_interpolates_and_wraps_around() {
+        let mut line = DelayLine::new(8);
+        // more samples than the buffer holds, so reads cross the end of the buffer
+        for i in 0..20 {
+            line.write(i as f32);
+        }
+        for delay in 1..8 {
+            assert_eq!(line.read(delay as f32), (20 - delay) as f32);
+        }
+        assert_eq!(line.read(1.5), 18.5);
+        assert_eq!(line.read(3.25), 16.75);
+        // interpolates between the last slot of the buffer and the first one
+        assert_eq!(line.read(4.5), 15.5);
+        // delays are limited to the buffer
+        assert_eq!(line.read(0.0), 19.0);
+        assert_eq!(line.read(100.0), 13.0);
+    }
+
+    #[test]
+    fn bypassed_effects_leave_the_signal_alone() {
+        let parameter = default_values();
+        let transport = Transport::default();
+        let mut chain = EffectChain::new(44100.0);
+        for i in 0..1000 {
+            let frame = [(i as f32 * 0.05).sin(), (i as f32 * 0.07).cos()];
+            assert_eq!(chain.process(frame, &parameter, &transport), frame);
+        }
+    }
+
+    #[test]
+    fn runs_effects_in_the_chosen_order() {
+        let mut parameter = default_values();
+        parameter[DELAY_BYPASS] = 0.0;
+        parameter[REVERB_BYPASS] = 0.0;
+        let transport = Transport::default();
+        let order = [EffectKind::Reverb, EffectKind::Delay, EffectKind::Chorus];
+
+        let mut reordered = EffectChain::new(44100.0);
+        reordered.set_order(order);
+        // orders missing an effect are ignored
+        reordered.set_order([EffectKind::Reverb, EffectKind::Reverb, EffectKind::Chorus]);
+        assert_eq!(reordered.order, order);
+        let mut default = EffectChain::new(44100.0);
+        let mut reverb = Reverb::new(44100.0);
+        let mut delay = PingPongDelay::new(44100.0);
+
+        let mix = |dry: [f32; 2], wet: [f32; 2], mix: f32| {
+            [dry[0] * (1.0 - mix) + wet[0] * mix, dry[1] * (1.0 - mix) + wet[1] * mix]
+        };
+        let mut differs = false;
+        for i in 0..44100 {
+            let input = if i == 0 { [1.0, 0.5] } else { [0.0, 0.0] };
+            let reverbed = mix(input, reverb.process(input, &parameter), parameter[REVERB_MIX]);
+            let delayed = delay.process(reverbed, &parameter, &transport);
+            let expected = mix(reverbed, delayed, parameter[DELAY_MIX]);
+
+            assert_eq!(reordered.process(input, &parameter, &transport), expected);
+            differs |= default.process(input, &parameter, &transport) != expected;
+        }
+        assert!(differs);
+    }
+}
@@ -0,0 +1,105 @@
+//! Algorithmic reverb after the Freeverb design: eight parallel lowpass-feedback comb filters
+//! followed by four series allpass filters per channel. The right channel uses slightly longer
+//! delay lines than the left one to decorrelate the two sides.
+
+use crate::*;
+
+/// Comb filter lengths in samples at 44.1 kHz.
+const COMB_TUNING: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+/// Allpass filter lengths in samples at 44.1 kHz.
+const ALLPASS_TUNING: [usize; 4] = [556, 441, 341, 225];
+/// Extra length of the right channel's delay lines at 44.1 kHz.
+const STEREO_SPREAD: usize = 23;
+
+const ALLPASS_FEEDBACK: f32 = 0.5;
+const INPUT_GAIN: f32 = 0.015;
+const OUTPUT_GAIN: f32 = 3.0;
+const MIN_ROOM_FEEDBACK: f32 = 0.7;
+const MAX_ROOM_FEEDBACK: f32 = 0.98;
+const MAX_DAMPING: f32 = 0.4;
+
+struct Comb {
+    buffer: Vec<f32>,
+    pos: usize,
+    filter_store: f32,
+}
+
+impl Comb {
+    fn new(length: usize) -> Self {
+        Self { buffer: vec![0.0; length.max(1)], pos: 0, filter_store: 0.0 }
+    }
+
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let output = self.buffer[self.pos];
+        self.filter_store = output * (1.0 - damping) + self.filter_store * damping;
+        self.buffer[self.pos] = input + self.filter_store * feedback;
+        self.pos = (self.pos + 1) % self.buffer.len();
+        output
+    }
+}
+
+struct Allpass {
+    buffer: Vec<f32>,
+    pos: usize,
+}
+
+impl Allpass {
+    fn new(length: usize) -> Self {
+        Self { buffer: vec![0.0; length.max(1)], pos: 0 }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let buffered = self.buffer[self.pos];
+        self.buffer[self.pos] = input + buffered * ALLPASS_FEEDBACK;
+        self.pos = (self.pos + 1) % self.buffer.len();
+        buffered - input
+    }
+}
+
+pub struct Reverb {
+    combs: [Vec<Comb>; 2],
+    allpasses: [Vec<Allpass>; 2],
+}
+
+impl Reverb {
+    pub fn new(sample_rate: f32) -> Self {
+        let scale = |length: usize| ((length as f32) * sample_rate / 44100.0) as usize;
+        let channel = |spread: usize| {
+            (
+                COMB_TUNING.iter()
+                    .map(|length| Comb::new(scale(length + spread)))
+                    .collect(),
+                ALLPASS_TUNING.iter()
+                    .map(|length| Allpass::new(scale(length + spread)))
+                    .collect(),
+            )
+        };
+        let (left_combs, left_allpasses) = channel(0);
+        let (right_combs, right_allpasses) = channel(STEREO_SPREAD);
+
+        Self {
+            combs: [left_combs, right_combs],
+            allpasses: [left_allpasses, right_allpasses],
+        }
+    }
+
+    pub fn process(&mut self, frame: [f32; 2], parameter: &[f32]) -> [f32; 2] {
+        let feedback =
+            MIN_ROOM_FEEDBACK + parameter[REVERB_SIZE] * (MAX_ROOM_FEEDBACK - MIN_ROOM_FEEDBACK);
+        let damping = parameter[REVERB_DAMPING] * MAX_DAMPING;
+        let input = (frame[0] + frame[1]) * INPUT_GAIN;
+
+        let mut wet = [0.0; 2];
+        for (channel, output) in wet.iter_mut().enumerate() {
+            let mut signal: f32 = self.combs[channel]
+                .iter_mut()
+                .map(|comb| comb.process(input, feedback, damping))
+                .sum();
+            for allpass in self.allpasses[channel].iter_mut() {
+                signal = allpass.process(signal);
+            }
+            *output = signal * OUTPUT_GAIN;
+        }
+        wet
+    }
+}
@@ -0,0 +1,101 @@
+//! Linear ADSR envelope generator, used for both the amplitude and filter envelopes of a voice.
+
+use crate::MIN_ENV_ATTACK_TIME;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EnvelopeStage {
+    Off,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+/// Envelope segment times in seconds and sustain level from 0 to 1.
+pub struct EnvelopeSettings {
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    pub stage: EnvelopeStage,
+    pub level: f32,
+}
+
+impl Envelope {
+    pub fn default() -> Envelope {
+        Envelope { stage: EnvelopeStage::Off, level: 0.0 }
+    }
+
+    /// Start a new note from silence.
+    pub fn start(&mut self) {
+        self.stage = EnvelopeStage::Attack;
+        self.level = 0.0;
+    }
+
+    /// Return to the attack phase from the current level.
+    pub fn retrigger(&mut self) {
+        self.stage = EnvelopeStage::Attack;
+    }
+
+    pub fn release(&mut self) {
+        if self.stage != EnvelopeStage::Off {
+            self.stage = EnvelopeStage::Release;
+        }
+    }
+
+    pub fn is_off(&self) -> bool {
+        self.stage == EnvelopeStage::Off
+    }
+
+    pub fn is_released(&self) -> bool {
+        self.stage == EnvelopeStage::Release
+    }
+
+    /// Advance the envelope by one sample and return its new level.
+    pub fn next(&mut self, settings: &EnvelopeSettings, sample_rate: f32) -> f32 {
+        let time_per_sample = 1.0 / sample_rate;
+
+        match self.stage {
+            EnvelopeStage::Off => (), //do nothing
+            EnvelopeStage::Attack => {
+                // grow volume slope /
+                if self.level < 1.0 {
+                    let slope_up = time_per_sample / settings.attack.max(MIN_ENV_ATTACK_TIME);
+                    self.level = (self.level + slope_up).min(1.0);
+                } else {
+                    // attack complete, set state to decay
+                    self.stage = EnvelopeStage::Decay;
+                    self.level = 1.0;
+                }
+            }
+            EnvelopeStage::Decay => {
+                if self.level > settings.sustain {
+                    // reduce volume slope \
+                    let slope_down = time_per_sample / settings.decay.max(MIN_ENV_ATTACK_TIME);
+                    self.level -= slope_down;
+                } else {
+                    // decay done
+                    self.stage = EnvelopeStage::Sustain;
+                }
+            }
+            EnvelopeStage::Sustain => {
+                self.level = settings.sustain;
+            }
+            EnvelopeStage::Release => {
+                if self.level > 0.0 {
+                    let slope_down = time_per_sample / settings.release.max(MIN_ENV_ATTACK_TIME);
+                    self.level -= slope_down;
+                } else {
+                    // voice done
+                    self.level = 0.0;
+                    self.stage = EnvelopeStage::Off;
+                }
+            }
+        }
+        self.level.max(0.0)
+    }
+}
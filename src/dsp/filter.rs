@@ -0,0 +1,47 @@
+//! Per-voice lowpass filter fed by the oscillator mixer.
+
+use crate::wave_math::TAU;
+
+/// Input gain at full drive.
+const MAX_DRIVE_GAIN: f32 = 10.0;
+
+/// Feedback gain at full resonance. A four pole cascade self-oscillates at 4.
+const MAX_RESONANCE_FEEDBACK: f32 = 3.9;
+
+/// A cascade of one to four one-pole lowpass stages with resonance feedback from the last active
+/// stage. The feedback path is soft-clipped to keep high resonance settings stable, and the input
+/// can be driven into saturation.
+#[derive(Debug, Clone, Default)]
+pub struct LadderFilter {
+    stages: [f32; 4],
+}
+
+impl LadderFilter {
+    pub fn reset(&mut self) {
+        self.stages = [0.0; 4];
+    }
+
+    pub fn process(
+        &mut self,
+        input: f32,
+        cutoff: f32,
+        resonance: f32,
+        poles: usize,
+        drive: f32,
+        sample_rate: f32
+    ) -> f32 {
+        let driven = (input * (1.0 + drive * MAX_DRIVE_GAIN)).tanh();
+        let input = input + (driven - input) * drive;
+        let poles = poles.clamp(1, self.stages.len());
+        let cutoff = cutoff.min(sample_rate * 0.45);
+        let coefficient = 1.0 - (-TAU * cutoff / sample_rate).exp();
+        let feedback = (resonance * MAX_RESONANCE_FEEDBACK * self.stages[poles - 1]).tanh();
+
+        let mut signal = input - feedback;
+        for stage in self.stages.iter_mut().take(poles) {
+            *stage += coefficient * (signal - *stage);
+            signal = *stage;
+        }
+        signal
+    }
+}
@@ -0,0 +1,104 @@
+//! Per-voice modulation LFOs.
+//!
+//! Every voice owns its own instance of each LFO. With retriggering enabled, an LFO restarts at
+//! the start phase on every note-on, so each note's vibrato or PWM starts the same way. Otherwise
+//! all voices follow the shared free running or bar-locked phase. A fade-in (delay followed by a
+//! linear rise) is applied from note-on in both modes.
+
+use super::{ modulation::ModSource, transport::Transport };
+use crate::{ parameters::PARAMETERS, wave_math::*, * };
+
+pub const NUM_VOICE_LFOS: usize = 4;
+
+/// Parameters and modulation source belonging to one LFO.
+pub struct LfoConfig {
+    pub source: ModSource,
+    /// Waveform parameter, `None` for a fixed sine.
+    shape: Option<usize>,
+    rate: usize,
+    sync: usize,
+}
+
+pub const VOICE_LFOS: [LfoConfig; NUM_VOICE_LFOS] = [
+    LfoConfig {
+        source: ModSource::PulseWidthLfo,
+        shape: None,
+        rate: PULSE_WIDTH_MOD_FREQ,
+        sync: PULSE_WIDTH_MOD_SYNC,
+    },
+    LfoConfig {
+        source: ModSource::PhaseShiftLfo,
+        shape: Some(PHASE_SHIFT_MOD_SHAPE),
+        rate: PHASE_SHIFT_MOD_FREQ,
+        sync: PHASE_SHIFT_MOD_SYNC,
+    },
+    LfoConfig {
+        source: ModSource::PitchLfo,
+        shape: Some(PITCH_MOD_SHAPE),
+        rate: PITCH_MOD_FREQ,
+        sync: PITCH_MOD_SYNC,
+    },
+    LfoConfig {
+        source: ModSource::CutoffLfo,
+        shape: Some(FILTER_CUTOFF_MOD_SHAPE),
+        rate: FILTER_CUTOFF_MOD_FREQ,
+        sync: FILTER_CUTOFF_MOD_SYNC,
+    },
+];
+
+#[derive(Debug, Clone, Default)]
+pub struct VoiceLfo {
+    /// Phase in cycles since the last retrigger, from 0 to 1.
+    phase: f32,
+}
+
+impl VoiceLfo {
+    pub fn retrigger(&mut self, start_phase: f32) {
+        self.phase = start_phase;
+    }
+
+    /// Unit amplitude output of the LFO at the current sample. Retriggered LFOs advance their own
+    /// phase, the others are derived from the shared `time` or the host transport.
+    pub fn next(
+        &mut self,
+        config: &LfoConfig,
+        p: &[f32],
+        transport: &Transport,
+        time: f32,
+        sample_rate: f32
+    ) -> f32 {
+        let shape = config.shape.map_or(0, |shape| PARAMETERS[shape].step(p[shape]));
+        let synced = p[config.sync] >= 0.5;
+        let freq = PARAMETERS[config.rate].plain(p[config.rate]);
+
+        let cycles = if p[LFO_RETRIGGER] >= 0.5 {
+            let cycles = self.phase;
+            let increment = if synced {
+                transport.synced_increment(p[config.rate], sample_rate)
+            } else {
+                freq / sample_rate
+            };
+            self.phase = (self.phase + increment).fract();
+            cycles
+        } else if synced {
+            transport.synced_cycles(p[config.rate]) + p[LFO_START_PHASE]
+        } else {
+            time * freq + p[LFO_START_PHASE]
+        };
+
+        lfo(shape, cycles, 1.0, 1.0)
+    }
+}
+
+/// Gain of the LFO fade-in, `age` seconds after note-on.
+pub fn fade_in(age: f32, p: &[f32]) -> f32 {
+    let delay = PARAMETERS[LFO_FADE_DELAY].plain(p[LFO_FADE_DELAY]);
+    let rise = PARAMETERS[LFO_FADE_RISE].plain(p[LFO_FADE_RISE]);
+    if age < delay {
+        0.0
+    } else if age < delay + rise {
+        (age - delay) / rise
+    } else {
+        1.0
+    }
+}
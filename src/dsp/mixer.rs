@@ -0,0 +1,61 @@
+//! The oscillator mixer. Every oscillator of a voice is routed through its own mixer channel with
+//! a level and a pan position, and the resulting stereo pair feeds the voice filter.
+
+use std::f32::consts::{ FRAC_PI_2, SQRT_2 };
+
+use crate::*;
+
+/// Number of oscillator sources feeding the mixer.
+pub const NUM_MIXER_SOURCES: usize = 6;
+
+/// Mixer channel order, as used for the `sources` array passed to `Mixer::mix`.
+pub const MIX_NOISE: usize = 0;
+pub const MIX_SINE: usize = 1;
+pub const MIX_PULSE: usize = 2;
+pub const MIX_SAWTOOTH: usize = 3;
+pub const MIX_SUB: usize = 4;
+pub const MIX_WAVETABLE: usize = 5;
+
+/// `(level, pan)` parameter indexes of each mixer channel.
+const CHANNEL_PARAMETERS: [(usize, usize); NUM_MIXER_SOURCES] = [
+    (NOISE_AMP, NOISE_PAN),
+    (SINE_AMP, SINE_PAN),
+    (PULSE_AMP, PULSE_PAN),
+    (SAWTOOTH_AMP, SAWTOOTH_PAN),
+    (SUB_AMP, SUB_PAN),
+    (WAVETABLE_AMP, WAVETABLE_PAN),
+];
+
+/// Per-channel gains for the left and right outputs, derived from the level and pan parameters.
+pub struct Mixer {
+    gains: [[f32; 2]; NUM_MIXER_SOURCES],
+}
+
+impl Mixer {
+    pub fn new(parameter: &[f32]) -> Self {
+        let mut gains = [[0.0; 2]; NUM_MIXER_SOURCES];
+        for (gain, (level_idx, pan_idx)) in gains.iter_mut().zip(CHANNEL_PARAMETERS.iter()) {
+            let level = parameter[*level_idx];
+            let (left, right) = pan_gains(parameter[*pan_idx]);
+            *gain = [level * left, level * right];
+        }
+        Self { gains }
+    }
+
+    /// Sum one sample of every source into the given output channel (0 = left, 1 = right).
+    pub fn mix(&self, sources: &[f32; NUM_MIXER_SOURCES], channel: usize) -> f32 {
+        sources
+            .iter()
+            .zip(self.gains.iter())
+            .map(|(source, gain)| source * gain[channel])
+            .sum()
+    }
+}
+
+/// Constant-power pan law, normalized so that a centered source keeps unity gain on both sides.
+///
+/// `pan` goes from 0 (hard left) over 0.5 (center) to 1 (hard right).
+pub fn pan_gains(pan: f32) -> (f32, f32) {
+    let angle = pan.clamp(0.0, 1.0) * FRAC_PI_2;
+    (angle.cos() * SQRT_2, angle.sin() * SQRT_2)
+}
@@ -1,248 +1,684 @@
 //! The plugin's digital signal processing is fully implemented within this module.
 //!
-//! All updates to input parameters are received through message passing to avoid thread locking
-//! during audio processing. In particular, note that parameter smoothing is considered within the
-//! scope of audio processing rather than state management. This module uses the `SmoothedRange`
-//! struct to ensure that parameters are consistently and efficiently interpolated while minimizing
-//! the number of messages passed.
-
-use crate::{ plugin_state::StateUpdate, * };
-use std::{ sync::mpsc::Receiver };
+//! All updates to input parameters are received by polling a lock-free store and event queue to
+//! avoid thread locking during audio processing. In particular, note that parameter smoothing is
+//! considered within the scope of audio processing rather than state management. This module uses
+//! the `SmoothedRange` struct to ensure that parameters are consistently and efficiently
+//! interpolated while minimizing the number of messages passed.
+
+use crate::{
+    parameter_store::{ ParameterStore, Reader },
+    parameters::{ default_values, PARAMETERS },
+    plugin_state::{ Retired, StateUpdate },
+    spsc::{ Consumer, Producer },
+    tuning::{ mts, TuningTable },
+    *,
+};
+use std::sync::Arc;
 use crate::{ wave_math::* };
-use vst::{ buffer::AudioBuffer };
-
-#[derive(Debug, Clone, PartialEq)]
-enum VoiceState {
-    Off,
-    Attack,
-    Decay,
-    Sustain,
-    Release,
-}
+use vst::{ api::TimeInfo, buffer::AudioBuffer };
+
+pub mod distortion;
+use distortion::{ is_pre_filter, Bitcrusher, Waveshaper };
+
+pub mod effects;
+use effects::EffectChain;
+
+mod envelope;
+use envelope::{ Envelope, EnvelopeSettings };
+
+mod filter;
+use filter::LadderFilter;
+
+mod lfo;
+use lfo::{ fade_in, VoiceLfo, NUM_VOICE_LFOS, VOICE_LFOS };
+
+mod mixer;
+use mixer::*;
+
+pub mod mpe;
+use mpe::{ pitch_bend_semitones, ChannelRole, ChannelState, MpeSettings, NUM_CHANNELS, SLIDE_CC };
+
+pub mod oversampling;
+use oversampling::{ Downsampler, OversamplingSettings, MAX_OVERSAMPLING };
+
+mod transport;
+pub use transport::{ time_info_mask, NOTE_DIVISIONS, note_division };
+use transport::Transport;
+
+pub mod modulation;
+use modulation::{ ModMatrix, ModSource, ModSourceValues };
+
+pub mod morph;
+use morph::Morph;
+
+pub mod wavetable;
+use wavetable::Wavetable;
 
 #[derive(Debug, Clone)]
 struct Voice {
-    state: VoiceState,
+    /// MIDI channel the note was played on. Together with `note`, this identifies the note.
+    channel: u8,
     note: u8,
-    amplitude: f32,
+    velocity: f32,
+    /// Polyphonic aftertouch, from 0 to 1.
+    pressure: f32,
+    amp_envelope: Envelope,
+    filter_envelope: Envelope,
+    filters: [LadderFilter; 2], // one per output channel
+    shapers: [Waveshaper; 2],
+    crushers: [Bitcrusher; 2],
+    lfos: [VoiceLfo; NUM_VOICE_LFOS],
+    /// Time since note-on in seconds.
+    age: f32,
+    /// Parameter values after applying the modulation matrix for this voice.
+    modulated: Vec<f32>,
 }
 
 impl Voice {
     fn default() -> Voice {
-        Voice { state: VoiceState::Off, note: 0, amplitude: 0.0 }
+        Voice {
+            channel: 0,
+            note: 0,
+            velocity: 0.0,
+            pressure: 0.0,
+            amp_envelope: Envelope::default(),
+            filter_envelope: Envelope::default(),
+            filters: [LadderFilter::default(), LadderFilter::default()],
+            shapers: [Waveshaper::default(), Waveshaper::default()],
+            crushers: [Bitcrusher::default(), Bitcrusher::default()],
+            lfos: Default::default(),
+            age: 0.0,
+            modulated: default_values(),
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        !self.amp_envelope.is_off()
+    }
+
+    fn plays(&self, channel: u8, note: u8) -> bool {
+        self.channel == channel && self.note == note && self.is_active()
+    }
+
+    /// Restart the LFO fade-in, and the LFO phases if retriggering is enabled.
+    fn restart_lfos(&mut self, parameter: &[f32]) {
+        self.age = 0.0;
+        if parameter[LFO_RETRIGGER] >= 0.5 {
+            for lfo in self.lfos.iter_mut() {
+                lfo.retrigger(parameter[LFO_START_PHASE]);
+            }
+        }
     }
 }
+
+/// Read the envelope settings starting at the `attack` parameter index. All envelopes share the
+/// attack, decay, sustain, release parameter order.
+fn envelope_settings(parameter: &[f32], attack: usize) -> EnvelopeSettings {
+    EnvelopeSettings {
+        attack: PARAMETERS[attack].plain(parameter[attack]),
+        decay: PARAMETERS[attack + 1].plain(parameter[attack + 1]),
+        sustain: parameter[attack + 2],
+        release: PARAMETERS[attack + 3].plain(parameter[attack + 3]),
+    }
+}
+
 /// Handles all audio processing algorithms for the plugin.
 pub(super) struct PluginDsp {
     sample_rate: f32,
     time: f32,
-    voices: Vec<Voice>, //state, note, duration, amplitude
+    transport: Transport,
+    voices: Vec<Voice>,
     parameter: Vec<f32>,
-    messages_from_params: Receiver<StateUpdate>,
+    mod_matrix: ModMatrix,
+    /// Patches to morph between when the morph parameter is modulated, if both slots are loaded.
+    morph: Option<Arc<Morph>>,
+    /// Mod wheel position, from 0 to 1.
+    mod_wheel: f32,
+    mpe: MpeSettings,
+    channels: [ChannelState; NUM_CHANNELS],
+    wavetable: Arc<Wavetable>,
+    tuning: TuningTable,
+    effects: EffectChain,
+    oversampling: OversamplingSettings,
+    /// Whether the host is currently rendering offline.
+    offline: bool,
+    downsamplers: [Downsampler; 2],
+    /// Parameter values set by the host and the editor, polled at the start of every block.
+    parameters: Arc<ParameterStore>,
+    messages_from_params: Consumer<StateUpdate>,
+    /// Hands replaced wavetables, tunings and morphs back to the `PluginState`, so they aren't
+    /// freed on the audio thread.
+    retired: Producer<Retired>,
 }
 
 impl PluginDsp {
-    pub fn new(incoming_messages: Receiver<StateUpdate>) -> Self {
+    pub fn new(
+        parameters: Arc<ParameterStore>,
+        incoming_messages: Consumer<StateUpdate>,
+        retired: Producer<Retired>
+    ) -> Self {
         Self {
             time: 0.0,
             sample_rate: 44100.0,
+            transport: Transport::default(),
             voices: vec![Voice::default(); NUM_VOICES as usize],
-            parameter: vec![0.0; NUM_PARAMETERS as usize],
+            parameter: parameters.values(),
+            mod_matrix: ModMatrix::default(),
+            morph: None,
+            mod_wheel: 0.0,
+            mpe: MpeSettings::default(),
+            channels: [ChannelState::default(); NUM_CHANNELS],
+            wavetable: Wavetable::default_table(),
+            tuning: TuningTable::default(),
+            effects: EffectChain::new(44100.0),
+            oversampling: OversamplingSettings::default(),
+            offline: false,
+            downsamplers: [
+                Downsampler::new(OversamplingSettings::default().realtime),
+                Downsampler::new(OversamplingSettings::default().realtime),
+            ],
+            parameters,
             messages_from_params: incoming_messages,
+            retired,
         }
     }
 
     pub fn set_sample_rate(&mut self, rate: f32) {
         self.sample_rate = rate;
+        self.effects.set_sample_rate(rate);
     }
 
-    pub fn note_on(&mut self, note: u8) {
+    /// Switch between the realtime and offline oversampling settings.
+    pub fn set_offline(&mut self, offline: bool) {
+        self.offline = offline;
+        self.update_oversampling();
+    }
+
+    fn update_oversampling(&mut self) {
+        let oversampling = if self.offline {
+            self.oversampling.offline
+        } else {
+            self.oversampling.realtime
+        };
+        if self.downsamplers[0].oversampling() != oversampling {
+            self.downsamplers = [Downsampler::new(oversampling), Downsampler::new(oversampling)];
+        }
+    }
+
+    pub fn note_on(&mut self, channel: u8, note: u8, velocity: u8) {
+        if velocity == 0 {
+            self.note_off(channel, note);
+            return;
+        }
+        if self.mpe.channel_role(channel) == ChannelRole::Ignored || !self.tuning.is_mapped(note) {
+            return;
+        }
+        let velocity = f32::from(velocity) / 127.0;
         // find if same note is already playing
         for i in 1..self.voices.len() {
-            if self.voices[i].note == note && self.voices[i].state != VoiceState::Off {
-                self.voices[i].state = VoiceState::Attack; // return to attack phase
+            if self.voices[i].plays(channel, note) {
+                // return to attack phase
+                self.voices[i].velocity = velocity;
+                self.voices[i].amp_envelope.retrigger();
+                self.voices[i].filter_envelope.retrigger();
+                self.voices[i].restart_lfos(&self.parameter);
                 return;
             }
         }
         // find free note slot
         for i in 1..self.voices.len() {
-            if self.voices[i].state == VoiceState::Off {
-                self.voices[i].state = VoiceState::Attack; // goto attack phase
-                self.voices[i].note = note;
-                self.voices[i].amplitude = 0.0; // amplitude to 0
+            if !self.voices[i].is_active() {
+                let voice = &mut self.voices[i];
+                voice.channel = channel;
+                voice.note = note;
+                voice.velocity = velocity;
+                voice.pressure = 0.0;
+                voice.amp_envelope.start();
+                voice.filter_envelope.start();
+                voice.filters.iter_mut().for_each(LadderFilter::reset);
+                voice.shapers.iter_mut().for_each(Waveshaper::reset);
+                voice.crushers.iter_mut().for_each(Bitcrusher::reset);
+                voice.modulated.copy_from_slice(&self.parameter);
+                voice.restart_lfos(&self.parameter);
                 break;
             }
         }
     }
 
-    pub fn note_off(&mut self, note: u8) {
+    pub fn note_off(&mut self, channel: u8, note: u8) {
         for i in 1..self.voices.len() {
-            if self.voices[i].note == note && self.voices[i].state != VoiceState::Off {
-                self.voices[i].state = VoiceState::Release;
+            if self.voices[i].plays(channel, note) && !self.voices[i].amp_envelope.is_released() {
+                self.voices[i].amp_envelope.release();
+                self.voices[i].filter_envelope.release();
                 break;
             }
         }
     }
 
-    fn adsr_for_voice(&mut self, i: usize) -> f32 {
-        let time_per_sample = 1.0 / self.sample_rate;
+    /// Handle a system exclusive message. Only MIDI Tuning Standard messages are understood, all
+    /// others are ignored.
+    pub fn process_sysex(&mut self, payload: &[u8]) {
+        mts::apply_sysex(&mut self.tuning, payload);
+    }
 
-        match self.voices[i].state {
-            VoiceState::Off => 0.0, //do nothing
-            VoiceState::Attack => {
-                // grow volume slope /
-                if self.voices[i].amplitude < 1.0 {
-                    let attack_time = self.parameter[AMP_ATTACK as usize] * MAX_ENV_ATTACK_TIME;
-                    let slope_up: f32 = time_per_sample / attack_time;
-                    self.voices[i].amplitude += slope_up;
-                } else {
-                    // attack complete, set state to decay
-                    self.voices[i].state = VoiceState::Decay;
-                    self.voices[i].amplitude = 1.0;
-                }
-                self.voices[i].amplitude
-            }
-            VoiceState::Decay => {
-                let sustain_level: f32 = self.parameter[AMP_SUSTAIN_LEVEL as usize];
-                if self.voices[i].amplitude > sustain_level {
-                    // reduce volume slope \
-                    let decay_time: f32 = self.parameter[AMP_DECAY as usize] * MAX_ENV_DECAY_TIME;
-                    let slope_down: f32 = time_per_sample / decay_time;
-                    self.voices[i].amplitude -= slope_down;
-                } else {
-                    // decay done
-                    self.voices[i].state = VoiceState::Sustain;
-                }
-                self.voices[i].amplitude
-            }
-            VoiceState::Sustain => {
-                let sustain_level: f32 = self.parameter[AMP_SUSTAIN_LEVEL as usize];
-                self.voices[i].amplitude = sustain_level;
-                self.voices[i].amplitude
-            }
-            VoiceState::Release => {
-                if self.voices[i].amplitude > 0.0 {
-                    let release_time = self.parameter[AMP_RELEASE as usize] * MAX_ENV_RELEASE_TIME;
-                    let slope_down: f32 = time_per_sample / release_time;
-                    self.voices[i].amplitude -= slope_down;
-                } else {
-                    // voice done
-                    self.voices[i].amplitude = 0.0;
-                    self.voices[i].state = VoiceState::Off;
+    /// Release every note, e.g. when the channel layout changes.
+    fn release_all(&mut self) {
+        for voice in self.voices.iter_mut() {
+            voice.amp_envelope.release();
+            voice.filter_envelope.release();
+        }
+        self.channels = [ChannelState::default(); NUM_CHANNELS];
+    }
+
+    pub fn control_change(&mut self, channel: u8, controller: u8, value: u8) {
+        if self.mpe.channel_role(channel) == ChannelRole::Ignored {
+            return;
+        }
+        match controller {
+            MOD_WHEEL_CC => {
+                self.mod_wheel = f32::from(value) / 127.0;
+            }
+            SLIDE_CC => {
+                self.channels[channel as usize].slide = f32::from(value) / 127.0;
+            }
+            _ => (),
+        }
+    }
+
+    /// Whether channel-wide messages such as Program Change should be followed on `channel`.
+    pub fn is_master_channel(&self, channel: u8) -> bool {
+        self.mpe.channel_role(channel) == ChannelRole::Master
+    }
+
+    pub fn set_channel_pressure(&mut self, channel: u8, value: u8) {
+        if self.mpe.channel_role(channel) != ChannelRole::Ignored {
+            self.channels[channel as usize].pressure = f32::from(value) / 127.0;
+        }
+    }
+
+    pub fn set_poly_pressure(&mut self, channel: u8, note: u8, value: u8) {
+        for voice in self.voices.iter_mut() {
+            if voice.plays(channel, note) {
+                voice.pressure = f32::from(value) / 127.0;
+            }
+        }
+    }
+
+    pub fn set_pitch_bend(&mut self, channel: u8, value: u16) {
+        let range = match self.mpe.channel_role(channel) {
+            ChannelRole::Master => self.mpe.master_pitch_bend_range,
+            ChannelRole::Member(_) => self.mpe.pitch_bend_range,
+            ChannelRole::Ignored => {
+                return;
+            }
+        };
+        self.channels[channel as usize].pitch_bend = pitch_bend_semitones(value, range);
+    }
+
+    /// Expression of the channel a voice is playing on, combined with the master channel of its
+    /// zone.
+    fn voice_expression(&self, channel: u8) -> ChannelState {
+        let state = self.channels[channel as usize];
+        match self.mpe.channel_role(channel) {
+            ChannelRole::Member(master) => {
+                let master = self.channels[master as usize];
+                ChannelState {
+                    pitch_bend: state.pitch_bend + master.pitch_bend,
+                    pressure: state.pressure.max(master.pressure),
+                    slide: state.slide.max(master.slide),
                 }
-                self.voices[i].amplitude
             }
+            _ => state,
         }
     }
 
-    pub fn process(&mut self, buffer: &mut AudioBuffer<f32>) {
+    pub fn process(&mut self, buffer: &mut AudioBuffer<f32>, time_info: Option<TimeInfo>) {
         // First, get any new changes to parameter ranges.
-        while let Ok(message) = self.messages_from_params.try_recv() {
+        self.receive_updates();
+        self.transport.update(time_info);
+
+        let samples = buffer.samples();
+        let (_, mut outputs) = buffer.split();
+
+        for sample_idx in 0..samples {
+            let frame = self.render_frame();
+            for output_idx in 0..outputs.len() {
+                let buff = outputs.get_mut(output_idx);
+                buff[sample_idx] = frame[output_idx.min(1)];
+            }
+        }
+    }
+
+    /// Apply all parameter changes and state updates made by the `PluginState` since the last
+    /// call. Neither locks nor allocates.
+    fn receive_updates(&mut self) {
+        let parameter = &mut self.parameter;
+        self.parameters.take_changes(Reader::Dsp, |index, value| parameter[index] = value);
+
+        while let Some(message) = self.messages_from_params.pop() {
             match message {
-                StateUpdate::SetKnob(index, value) => {
-                    self.parameter[index as usize] = value;
+                StateUpdate::NoteOn(n) => self.note_on(0, n, 127),
+                StateUpdate::NoteOff(n) => self.note_off(0, n),
+                StateUpdate::SetWavetable(wavetable) => {
+                    let replaced = std::mem::replace(&mut self.wavetable, wavetable);
+                    self.retire(Retired::Wavetable(replaced));
+                }
+                StateUpdate::SetTuning(tuning) => {
+                    self.tuning = *tuning;
+                    self.retire(Retired::Tuning(tuning));
+                }
+                StateUpdate::SetModRoute(slot, route) => {
+                    self.mod_matrix.set_route(slot, route);
+                }
+                StateUpdate::SetMorph(morph) => {
+                    if let Some(replaced) = std::mem::replace(&mut self.morph, morph) {
+                        self.retire(Retired::Morph(replaced));
+                    }
+                }
+                StateUpdate::SetEffectOrder(order) => {
+                    self.effects.set_order(order);
+                }
+                StateUpdate::SetMpe(settings) => {
+                    self.release_all();
+                    self.mpe = settings;
+                }
+                StateUpdate::SetOversampling(settings) => {
+                    self.oversampling = settings;
+                    self.update_oversampling();
                 }
-                StateUpdate::NoteOn(n) => self.note_on(n),
-                StateUpdate::NoteOff(n) => self.note_off(n),
             }
         }
+    }
 
-        let samples = buffer.samples();
-        let (_, mut outputs) = buffer.split();
+    /// Hand shared data back to be freed on another thread. The queue has room for everything
+    /// retired in response to a full event queue, so it only overflows if the `PluginState` is
+    /// gone, in which case the data is freed here after all.
+    fn retire(&mut self, retired: Retired) {
+        if let Err(retired) = self.retired.push(retired) {
+            drop(retired);
+        }
+    }
 
+    /// Render a single stereo sample of all active voices through the master effects, and advance
+    /// the time by one sample. The voices run at the oversampled rate, while modulation is only
+    /// evaluated once per sample.
+    fn render_frame(&mut self) -> [f32; 2] {
         let time_per_sample = 1.0 / self.sample_rate;
+        let factor = self.downsamplers[0].oversampling().factor();
+        let oversampled_rate = self.sample_rate * factor as f32;
+        let time_per_step = time_per_sample / factor as f32;
 
-        for sample_idx in 0..samples {
-            // get modulation controls
-            let noise_amp: f32 = self.parameter[NOISE_AMP];
-            let noise_color = parameter_to_u8(self.parameter[NOISE_COLOR], 1);
-
-            let sine_amp: f32 = self.parameter[SINE_AMP];
-
-            let pulse_width = self.parameter[PULSE_WIDTH];
-            let pulse_width_mod: f32 = self.parameter[PULSE_WIDTH_MOD_AMP];
-            let pulse_width_mod_freq: f32 = self.parameter[PULSE_WIDTH_MOD_FREQ];
-            let pulse_amp: f32 = self.parameter[PULSE_AMP];
-
-            let sawtooth_amp: f32 = self.parameter[SAWTOOTH_AMP];
-            let sawtooth_shape: f32 = self.parameter[SAWTOOTH_SHAPE];
-
-            let phase_shift_mod_shape = parameter_to_u8(self.parameter[PHASE_SHIFT_MOD_SHAPE], 4);
-            let phase_shift_amount: f32 = self.parameter[PHASE_SHIFT_AMOUNT];
-            let phase_shift_freq: f32 = self.parameter[PHASE_SHIFT_MOD_FREQ];
-
-            let pitch_mod_shape = parameter_to_u8(self.parameter[PITCH_MOD_SHAPE], 4);
-            let pitch_mod_amp: f32 = self.parameter[PITCH_MOD_AMP];
-            let pitch_mod_freq: f32 = self.parameter[PITCH_MOD_FREQ];
-
-            let phase_modulator: f32 = if phase_shift_amount > 0.0 {
-                lfo(phase_shift_mod_shape, self.time, phase_shift_freq, phase_shift_amount)
-            } else {
-                0.0
-            };
-
-            let pulse_width_modulator: f32 =
-                (self.time * pulse_width_mod_freq).sin() * pulse_width_mod;
-
-            let pitch_modulator: f32 = if pitch_mod_amp > 0.0 {
-                lfo(pitch_mod_shape, self.time, pitch_mod_freq * 100.0, pitch_mod_amp)
-            } else {
-                0.0
-            };
-
-            // mutated between channels
-            let mut channel_phase_shift_amount: f32 = 0.0;
-
-            let noise = if noise_amp <= 0.0 {
-                0.0
-            } else {
-                match noise_color {
-                    1 => generate_pink_noise(noise_amp),
-                    _ => generate_white_noise(noise_amp),
-                }
-            };
+        // Decimation only keeps 1/factor of the power of noise generated at the oversampled rate,
+        // so it is louder by the square root of that to sound the same at every factor.
+        let mut noise = [0.0; MAX_OVERSAMPLING];
+        if self.parameter[NOISE_AMP] > 0.0 {
+            let pink = PARAMETERS[NOISE_COLOR].step(self.parameter[NOISE_COLOR]) == 1;
+            let amp = (factor as f32).sqrt();
+            for sample in noise.iter_mut().take(factor) {
+                *sample = if pink { generate_pink_noise(amp) } else { generate_white_noise(amp) };
+            }
+        }
 
-            for output_idx in 0..outputs.len() {
-                let mut signal = 0.0;
-                let mut max_signal = 1.0;
-                for i in 1..self.voices.len() {
-                    if self.voices[i].state != VoiceState::Off {
-                        signal += noise;
-
-                        let base_freq =
-                            midi_pitch_to_freq(self.voices[i].note) * 2.0 + pitch_modulator;
-                        let time = phase_shifted_time(
-                            self.time,
-                            base_freq,
-                            channel_phase_shift_amount * phase_modulator
-                        );
-
-                        signal += generate_sine_wave(time, base_freq, sine_amp);
-
-                        signal += generate_pulse_wave(
-                            time,
-                            base_freq,
-                            pulse_width + pulse_width_modulator,
-                            pulse_amp
-                        );
-
-                        signal += generate_sawtooth_wave(
-                            time,
-                            base_freq,
-                            sawtooth_shape,
-                            sawtooth_amp
-                        );
-
-                        signal *= self.adsr_for_voice(i);
-
-                        max_signal += 1.0; // each active voise adds range
+        let expressions: [ChannelState; NUM_CHANNELS] = std::array::from_fn(|channel| {
+            self.voice_expression(channel as u8)
+        });
+
+        let mut oversampled = [[0.0; MAX_OVERSAMPLING]; 2];
+        let mut max_signal = 1.0;
+        for voice in self.voices.iter_mut().skip(1) {
+            if !voice.is_active() {
+                continue;
+            }
+            let expression = expressions[voice.channel as usize];
+
+            // Envelopes and LFOs run on the modulated values of the previous sample, since they
+            // are modulation sources themselves.
+            let p = &voice.modulated;
+            let amp_envelope = voice.amp_envelope.next(
+                &envelope_settings(p, AMP_ATTACK),
+                self.sample_rate
+            );
+            let filter_envelope = voice.filter_envelope.next(
+                &envelope_settings(p, FILTER_ATTACK),
+                self.sample_rate
+            );
+
+            let mut sources = ModSourceValues::default();
+            let fade = fade_in(voice.age, p);
+            for (lfo, config) in voice.lfos.iter_mut().zip(VOICE_LFOS.iter()) {
+                let value = lfo.next(config, p, &self.transport, self.time, self.sample_rate);
+                sources.set(config.source, value * fade);
+            }
+            sources.set(ModSource::AmpEnvelope, amp_envelope);
+            sources.set(ModSource::FilterEnvelope, filter_envelope);
+            sources.set(ModSource::Velocity, voice.velocity);
+            sources.set(ModSource::Key, f32::from(voice.note) / 127.0);
+            sources.set(ModSource::ModWheel, self.mod_wheel);
+            sources.set(ModSource::Aftertouch, voice.pressure.max(expression.pressure));
+            sources.set(ModSource::Slide, expression.slide);
+
+            self.mod_matrix.apply(&sources, &self.parameter, &mut voice.modulated);
+            if let Some(morph) = &self.morph {
+                morph.modulate(self.parameter[MORPH], &mut voice.modulated);
+            }
+            let p = &voice.modulated;
+
+            // hard-wired modulation
+            let phase_shift_amount = p[PHASE_SHIFT_AMOUNT];
+            let phase_modulator = sources.get(ModSource::PhaseShiftLfo) * phase_shift_amount;
+            let pulse_width_modulator =
+                sources.get(ModSource::PulseWidthLfo) * p[PULSE_WIDTH_MOD_AMP];
+            let pitch_modulator = sources.get(ModSource::PitchLfo) * p[PITCH_MOD_AMP];
+            let cutoff_modulator = sources.get(ModSource::CutoffLfo) * p[FILTER_CUTOFF_MOD_AMP];
+
+            let cutoff = PARAMETERS[FILTER_CUTOFF].plain(p[FILTER_CUTOFF] + cutoff_modulator);
+            let resonance = p[FILTER_RESONANCE];
+            let poles = PARAMETERS[FILTER_POLES].plain(p[FILTER_POLES]) as usize;
+
+            let bend = (expression.pitch_bend / 12.0).exp2();
+            let base_freq = self.tuning.frequency(voice.note) * 2.0 * bend + pitch_modulator;
+            let sub_octaves = PARAMETERS[SUB_OCTAVE].step(p[SUB_OCTAVE]) + 1;
+            let sub_freq = base_freq / f32::from(sub_octaves).exp2();
+            let sub_shape = PARAMETERS[SUB_SHAPE].step(p[SUB_SHAPE]);
+
+            let mixer = Mixer::new(p);
+            let shaper_pre_filter = is_pre_filter(p[DISTORTION_POSITION]);
+            let crusher_pre_filter = is_pre_filter(p[CRUSH_POSITION]);
+
+            for step in 0..factor {
+                let step_time = self.time + (step as f32) * time_per_step;
+
+                // the right channel is time shifted against the left one
+                for (channel, output) in oversampled.iter_mut().enumerate() {
+                    let time = phase_shifted_time(
+                        step_time,
+                        base_freq,
+                        (channel as f32) * phase_shift_amount * phase_modulator
+                    );
+
+                    let mut oscillators = [0.0; NUM_MIXER_SOURCES];
+                    oscillators[MIX_NOISE] = noise[step];
+                    oscillators[MIX_SINE] = generate_sine_wave(time, base_freq, 1.0);
+                    oscillators[MIX_PULSE] = generate_pulse_wave(
+                        time,
+                        base_freq,
+                        p[PULSE_WIDTH] + pulse_width_modulator,
+                        1.0
+                    );
+                    oscillators[MIX_SAWTOOTH] = generate_sawtooth_wave(
+                        time,
+                        base_freq,
+                        p[SAWTOOTH_SHAPE],
+                        1.0
+                    );
+                    oscillators[MIX_SUB] = match sub_shape {
+                        1 => generate_sine_wave(time, sub_freq, 1.0),
+                        _ => generate_square_wave(time, sub_freq, 1.0),
+                    };
+                    oscillators[MIX_WAVETABLE] = self.wavetable.sample(
+                        time * base_freq,
+                        p[WAVETABLE_POSITION],
+                        base_freq,
+                        oversampled_rate
+                    );
+
+                    let mut signal = mixer.mix(&oscillators, channel);
+                    if shaper_pre_filter {
+                        signal = voice.shapers[channel].process(signal, p, oversampled_rate);
+                    }
+                    if crusher_pre_filter {
+                        signal = voice.crushers[channel].process(signal, p, oversampled_rate);
                     }
+                    signal = voice.filters[channel].process(
+                        signal,
+                        cutoff,
+                        resonance,
+                        poles,
+                        p[FILTER_DRIVE],
+                        oversampled_rate
+                    );
+                    if !shaper_pre_filter {
+                        signal = voice.shapers[channel].process(signal, p, oversampled_rate);
+                    }
+                    if !crusher_pre_filter {
+                        signal = voice.crushers[channel].process(signal, p, oversampled_rate);
+                    }
+                    output[step] += signal * amp_envelope;
+                }
+            }
+
+            voice.age += time_per_sample;
+            max_signal += 1.0; // each active voise adds range
+        }
+
+        let mut frame = [0.0; 2];
+        for (channel, output) in frame.iter_mut().enumerate() {
+            let signal = self.downsamplers[channel].process(&oversampled[channel][..factor]);
+            *output = scale_to_range(signal, 1.0, max_signal);
+        }
+        let frame = self.effects.process(frame, &self.parameter, &self.transport);
+
+        self.time += time_per_sample;
+        self.transport.advance(self.sample_rate);
+
+        frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::oversampling::Oversampling;
+    use vst::plugin::HostCallback;
+    use crate::plugin_state::{ PluginState, EVENT_QUEUE_CAPACITY, RETIRED_QUEUE_CAPACITY };
+    use crate::spsc;
+    use crate::preset::factory::FACTORY_PRESETS;
+
+    /// Every factory preset must make itself heard through the program bank, and never output NaN
+    /// or infinite samples, neither while the notes are held nor while they are released.
+    #[test]
+    fn factory_presets_render() {
+        for (index, preset) in FACTORY_PRESETS.iter().enumerate() {
+            let parameters = Arc::new(ParameterStore::new(&default_values()));
+            let (to_dsp, dsp_recv) = spsc::channel(EVENT_QUEUE_CAPACITY);
+            let (to_editor, _editor_recv) = spsc::channel(EVENT_QUEUE_CAPACITY);
+            let (retire, retired) = spsc::channel(RETIRED_QUEUE_CAPACITY);
+            let host = HostCallback::default();
+            let state =
+                PluginState::new(host, Arc::clone(&parameters), to_dsp, to_editor, retired);
+            let mut dsp = PluginDsp::new(parameters, dsp_recv, retire);
+            state.change_program(index);
+            dsp.receive_updates();
+
+            for note in [48, 55, 60] {
+                dsp.note_on(0, note, 100);
+            }
+            let mut peak: f32 = 0.0;
+            for frame in 0..44100 {
+                if frame == 22050 {
+                    dsp.release_all();
+                }
+                for sample in dsp.render_frame() {
+                    assert!(sample.is_finite(), "{} outputs {}", preset.name, sample);
+                    peak = peak.max(sample.abs());
                 }
-                let buff = outputs.get_mut(output_idx);
-                buff[sample_idx] = scale_to_range(signal, 1.0, max_signal);
-                channel_phase_shift_amount += phase_shift_amount; // introduce timeshift between channels
             }
-            self.time += time_per_sample;
+            assert!(peak > 0.01, "{} is silent", preset.name);
+        }
+    }
+
+    /// Replaced shared data goes back to be freed on another thread, rather than on this one.
+    #[test]
+    fn hands_replaced_data_back() {
+        let parameters = Arc::new(ParameterStore::new(&default_values()));
+        let (mut to_dsp, dsp_recv) = spsc::channel(EVENT_QUEUE_CAPACITY);
+        let (retire, mut retired) = spsc::channel(RETIRED_QUEUE_CAPACITY);
+        let mut dsp = PluginDsp::new(parameters, dsp_recv, retire);
+        let wavetable = Arc::clone(&dsp.wavetable);
+        let tuning = Arc::new(TuningTable::default());
+        let morph = Arc::new(Morph { a: default_values(), b: default_values() });
+
+        for state_update in [
+            StateUpdate::SetWavetable(Wavetable::default_table()),
+            StateUpdate::SetTuning(Arc::clone(&tuning)),
+            StateUpdate::SetMorph(Some(Arc::clone(&morph))),
+            StateUpdate::SetMorph(None),
+        ] {
+            assert!(to_dsp.push(state_update).is_ok());
+        }
+        dsp.receive_updates();
+
+        match retired.pop() {
+            Some(Retired::Wavetable(replaced)) => assert!(Arc::ptr_eq(&replaced, &wavetable)),
+            _ => panic!("expected the replaced wavetable"),
+        }
+        match retired.pop() {
+            Some(Retired::Tuning(replaced)) => assert!(Arc::ptr_eq(&replaced, &tuning)),
+            _ => panic!("expected the tuning"),
+        }
+        match retired.pop() {
+            Some(Retired::Morph(replaced)) => assert!(Arc::ptr_eq(&replaced, &morph)),
+            _ => panic!("expected the replaced morph"),
         }
+        assert!(retired.pop().is_none());
     }
-}
\ No newline at end of file
+
+    /// Noise must be equally loud at every oversampling factor.
+    #[test]
+    fn noise_level_ignores_oversampling() {
+        let mut values = default_values();
+        values[SAWTOOTH_AMP] = 0.0;
+        values[NOISE_AMP] = 1.0;
+        let rms = |oversampling: Oversampling| {
+            let parameters = Arc::new(ParameterStore::new(&values));
+            let (_, dsp_recv) = spsc::channel(EVENT_QUEUE_CAPACITY);
+            let (retire, _retired) = spsc::channel(RETIRED_QUEUE_CAPACITY);
+            let mut dsp = PluginDsp::new(parameters, dsp_recv, retire);
+            dsp.receive_updates();
+            dsp.oversampling.realtime = oversampling;
+            dsp.update_oversampling();
+            dsp.note_on(0, 60, 127);
+
+            let mut sum = 0.0;
+            for frame in 0..44100 {
+                let sample = dsp.render_frame()[0];
+                if frame >= 22050 {
+                    sum += sample * sample;
+                }
+            }
+            (sum / 22050.0).sqrt()
+        };
+
+        // realtime and offline defaults
+        let reference = rms(Oversampling::X2);
+        assert!(reference > 0.01);
+        for &oversampling in [Oversampling::X4, Oversampling::X8].iter() {
+            let level = rms(oversampling) / reference;
+            assert!((0.9..1.1).contains(&level), "{:?} at {}", oversampling, level);
+        }
+        // without oversampling, the noise also keeps the top of the band the decimators cut
+        let level = rms(Oversampling::Off) / reference;
+        assert!((1.0..1.3).contains(&level), "Off at {}", level);
+    }
+}
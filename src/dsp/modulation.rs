@@ -0,0 +1,158 @@
+//! The modulation matrix routes modulation sources onto parameters.
+//!
+//! Each of the `NUM_MOD_SLOTS` slots connects one source to one destination parameter with a
+//! bipolar amount. A slot can optionally be scaled by a second "via" source, e.g. an LFO routed to
+//! pitch via the mod wheel. Modulation is evaluated per voice on top of the parameter values set
+//! by the host or editor, so sources like velocity or the envelopes affect each note separately.
+
+use crate::*;
+
+pub const NUM_MOD_SLOTS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModSource {
+    None,
+    PulseWidthLfo,
+    PhaseShiftLfo,
+    PitchLfo,
+    CutoffLfo,
+    AmpEnvelope,
+    FilterEnvelope,
+    Velocity,
+    Key,
+    ModWheel,
+    Aftertouch,
+    /// Slide controller (CC 74), per note in MPE mode.
+    Slide,
+}
+
+pub const NUM_MOD_SOURCES: usize = 12;
+
+impl ModSource {
+    /// Inverse of `source as usize`.
+    pub fn from_index(index: usize) -> Option<Self> {
+        Some(match index {
+            0 => ModSource::None,
+            1 => ModSource::PulseWidthLfo,
+            2 => ModSource::PhaseShiftLfo,
+            3 => ModSource::PitchLfo,
+            4 => ModSource::CutoffLfo,
+            5 => ModSource::AmpEnvelope,
+            6 => ModSource::FilterEnvelope,
+            7 => ModSource::Velocity,
+            8 => ModSource::Key,
+            9 => ModSource::ModWheel,
+            10 => ModSource::Aftertouch,
+            11 => ModSource::Slide,
+            _ => {
+                return None;
+            }
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ModSource::None => "None",
+            ModSource::PulseWidthLfo => "Pulse width LFO",
+            ModSource::PhaseShiftLfo => "Phase shift LFO",
+            ModSource::PitchLfo => "Pitch LFO",
+            ModSource::CutoffLfo => "Cutoff LFO",
+            ModSource::AmpEnvelope => "Amp envelope",
+            ModSource::FilterEnvelope => "Filter envelope",
+            ModSource::Velocity => "Velocity",
+            ModSource::Key => "Key",
+            ModSource::ModWheel => "Mod wheel",
+            ModSource::Aftertouch => "Aftertouch",
+            ModSource::Slide => "Slide",
+        }
+    }
+}
+
+/// A single slot of the modulation matrix.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModRoute {
+    pub source: ModSource,
+    /// Parameter index of the modulated parameter.
+    pub destination: usize,
+    /// Modulation depth from -1 to 1, relative to the full parameter range.
+    pub amount: f32,
+    /// Optional second source scaling the modulation depth.
+    pub via: ModSource,
+}
+
+impl Default for ModRoute {
+    fn default() -> Self {
+        Self { source: ModSource::None, destination: 0, amount: 0.0, via: ModSource::None }
+    }
+}
+
+impl ModRoute {
+    pub fn is_active(&self) -> bool {
+        self.source != ModSource::None && self.amount != 0.0 && is_mod_destination(self.destination)
+    }
+}
+
+/// Only continuous parameters can be modulated. Stepped parameters like waveform selectors are
+/// left out, as modulating them would just flip between their settings. The master effects run
+/// after the voices are summed, so they can't follow per-voice modulation either.
+pub fn is_mod_destination(index: usize) -> bool {
+    match index {
+        CHORUS_BYPASS..=REVERB_MIX => false,
+        NOISE_COLOR | SINE_OCTAVE | PHASE_SHIFT_MOD_SHAPE | PITCH_MOD_SHAPE | FILTER_POLES |
+        FILTER_CUTOFF_MOD_SHAPE | SUB_OCTAVE | SUB_SHAPE | PULSE_WIDTH_MOD_SYNC |
+        PHASE_SHIFT_MOD_SYNC | PITCH_MOD_SYNC | FILTER_CUTOFF_MOD_SYNC | LFO_RETRIGGER |
+        DISTORTION_SHAPE | DISTORTION_POSITION | CRUSH_BITS | CRUSH_POSITION => false,
+        _ => index < (NUM_PARAMETERS as usize),
+    }
+}
+
+/// Current value of every modulation source for a single voice. LFOs are bipolar, all other
+/// sources range from 0 to 1.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ModSourceValues {
+    values: [f32; NUM_MOD_SOURCES],
+}
+
+impl ModSourceValues {
+    pub fn set(&mut self, source: ModSource, value: f32) {
+        self.values[source as usize] = value;
+    }
+
+    pub fn get(&self, source: ModSource) -> f32 {
+        match source {
+            ModSource::None => 0.0,
+            _ => self.values[source as usize],
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ModMatrix {
+    routes: [ModRoute; NUM_MOD_SLOTS],
+}
+
+impl ModMatrix {
+    pub fn set_route(&mut self, slot: usize, route: ModRoute) {
+        if slot < NUM_MOD_SLOTS {
+            self.routes[slot] = route;
+        }
+    }
+
+    /// Write the base parameter values plus all active modulation into `modulated`.
+    pub fn apply(&self, sources: &ModSourceValues, parameter: &[f32], modulated: &mut [f32]) {
+        modulated.copy_from_slice(parameter);
+
+        for route in self.routes.iter().filter(|route| route.is_active()) {
+            let via = match route.via {
+                ModSource::None => 1.0,
+                via => sources.get(via),
+            };
+            modulated[route.destination] += route.amount * sources.get(route.source) * via;
+        }
+
+        for route in self.routes.iter().filter(|route| route.is_active()) {
+            let value = &mut modulated[route.destination];
+            *value = value.clamp(0.0, 1.0);
+        }
+    }
+}
@@ -0,0 +1,65 @@
+//! Morphing between the parameters of two patches, loaded into the slots A and B.
+//!
+//! The `MORPH` parameter blends continuous parameters linearly from their value in slot A to their
+//! value in slot B, while stepped parameters like waveform selectors switch from A to B halfway.
+//! The plugin state writes the blended values into its parameter record whenever the morph
+//! parameter changes, so the host, the editor and the audio processing all see them like any other
+//! parameter change. Modulating the morph parameter moves the parameters of each voice further
+//! along the way between A and B. The master effects are shared by all voices, so they only follow
+//! the morph parameter itself.
+
+use crate::*;
+use crate::parameters::PARAMETERS;
+
+pub const NUM_MORPH_SLOTS: usize = 2;
+
+/// Morph position from which stepped parameters take their value from slot B.
+const SWITCH_THRESHOLD: f32 = 0.5;
+
+/// Normalized parameter values of the patches in slot A and B, by index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Morph {
+    pub a: Vec<f32>,
+    pub b: Vec<f32>,
+}
+
+/// Value of a parameter at a morph position from 0 for its value `a` in slot A to 1 for its value
+/// `b` in slot B.
+pub fn blend(index: usize, a: f32, b: f32, position: f32) -> f32 {
+    if PARAMETERS[index].steps > 0 {
+        if position < SWITCH_THRESHOLD { a } else { b }
+    } else {
+        a + (b - a) * position.clamp(0.0, 1.0)
+    }
+}
+
+impl Morph {
+    /// Value of a parameter at a morph position from 0 for A to 1 for B.
+    pub fn value(&self, index: usize, position: f32) -> f32 {
+        blend(index, self.a[index], self.b[index], position)
+    }
+
+    /// Move the parameters of a voice by the modulation of the morph parameter, i.e. from the
+    /// position `base` set by the host to the one in `modulated[MORPH]`. Continuous parameters
+    /// keep any change made since the morph parameter was set, while stepped ones only change
+    /// when the modulation crosses the switching threshold.
+    pub fn modulate(&self, base: f32, modulated: &mut [f32]) {
+        let position = modulated[MORPH];
+        if position == base {
+            return;
+        }
+        for (index, value) in modulated.iter_mut().enumerate() {
+            if index == MORPH {
+                continue;
+            }
+            let (from, to) = (self.value(index, base), self.value(index, position));
+            if PARAMETERS[index].steps > 0 {
+                if from != to {
+                    *value = to;
+                }
+            } else {
+                *value = (*value + to - from).clamp(0.0, 1.0);
+            }
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! MIDI Polyphonic Expression.
+//!
+//! In MPE mode, the MIDI channels are split into a lower zone, whose master channel is channel 1,
+//! and an upper zone, whose master channel is channel 16. The member channels of each zone count
+//! inwards from their master channel. Controllers play every note on a member channel of its own,
+//! so pitch bend, channel pressure and slide (CC 74) on a member channel only affect that note.
+//! Messages on a master channel apply to the whole zone.
+//!
+//! Outside of MPE mode, only channel 1 is used and pitch bend affects all notes.
+
+/// Number of MIDI channels.
+pub const NUM_CHANNELS: usize = 16;
+/// Highest number of member channels in both zones together, leaving room for both masters.
+pub const MAX_MEMBER_CHANNELS: u8 = 14;
+pub const SLIDE_CC: u8 = 74;
+
+const LOWER_MASTER_CHANNEL: u8 = 0;
+const UPPER_MASTER_CHANNEL: u8 = 15;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MpeSettings {
+    pub enabled: bool,
+    /// Number of member channels of the lower zone, 0 to disable it.
+    pub lower_zone: u8,
+    /// Number of member channels of the upper zone, 0 to disable it.
+    pub upper_zone: u8,
+    /// Pitch bend range of the member channels in semitones.
+    pub pitch_bend_range: f32,
+    /// Pitch bend range of the master channels in semitones. Outside of MPE mode this range
+    /// applies to channel 1.
+    pub master_pitch_bend_range: f32,
+}
+
+impl Default for MpeSettings {
+    /// A single lower zone spanning all channels, with the default bend ranges of the MPE
+    /// specification.
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            lower_zone: 15,
+            upper_zone: 0,
+            pitch_bend_range: 48.0,
+            master_pitch_bend_range: 2.0,
+        }
+    }
+}
+
+/// What a MIDI channel is used for under the current settings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChannelRole {
+    /// A master channel, or channel 1 outside of MPE mode.
+    Master,
+    /// A member channel, along with the master channel of its zone.
+    Member(u8),
+    Ignored,
+}
+
+impl MpeSettings {
+    /// Limit the zones so that they don't overlap. A zone may only use all 15 member channels while
+    /// the other one is disabled, as in the MPE specification.
+    pub fn normalized(mut self) -> Self {
+        self.lower_zone = self.lower_zone.min(15);
+        self.upper_zone = self.upper_zone.min(15);
+        if self.lower_zone > 0 && self.upper_zone > 0 {
+            // the lower zone takes precedence
+            self.lower_zone = self.lower_zone.min(MAX_MEMBER_CHANNELS - 1);
+            self.upper_zone = self.upper_zone.min(MAX_MEMBER_CHANNELS - self.lower_zone);
+        }
+        self.pitch_bend_range = self.pitch_bend_range.clamp(0.0, 96.0);
+        self.master_pitch_bend_range = self.master_pitch_bend_range.clamp(0.0, 96.0);
+        self
+    }
+
+    /// Role of a MIDI channel, counted from 0.
+    pub fn channel_role(&self, channel: u8) -> ChannelRole {
+        if !self.enabled {
+            return if channel == LOWER_MASTER_CHANNEL {
+                ChannelRole::Master
+            } else {
+                ChannelRole::Ignored
+            };
+        }
+
+        let lower_zone = self.lower_zone > 0;
+        let upper_zone = self.upper_zone > 0;
+        if
+            (lower_zone && channel == LOWER_MASTER_CHANNEL) ||
+            (upper_zone && channel == UPPER_MASTER_CHANNEL)
+        {
+            ChannelRole::Master
+        } else if lower_zone && channel > LOWER_MASTER_CHANNEL && channel <= self.lower_zone {
+            ChannelRole::Member(LOWER_MASTER_CHANNEL)
+        } else if upper_zone && channel < UPPER_MASTER_CHANNEL && channel >= 15 - self.upper_zone {
+            ChannelRole::Member(UPPER_MASTER_CHANNEL)
+        } else {
+            ChannelRole::Ignored
+        }
+    }
+}
+
+/// Channel-wide expression. On member channels, these belong to a single note.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChannelState {
+    /// Pitch bend in semitones.
+    pub pitch_bend: f32,
+    /// Channel pressure, from 0 to 1.
+    pub pressure: f32,
+    /// Slide controller, from 0 to 1.
+    pub slide: f32,
+}
+
+/// Convert a 14 bit pitch bend value into semitones.
+pub fn pitch_bend_semitones(value: u16, range: f32) -> f32 {
+    ((f32::from(value) - 8192.0) / 8192.0) * range
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zones(lower_zone: u8, upper_zone: u8) -> MpeSettings {
+        MpeSettings { enabled: true, lower_zone, upper_zone, ..MpeSettings::default() }
+    }
+
+    #[test]
+    fn normalized_zones_dont_overlap() {
+        let normalized = |lower, upper| {
+            let settings = zones(lower, upper).normalized();
+            (settings.lower_zone, settings.upper_zone)
+        };
+        assert_eq!(normalized(15, 0), (15, 0));
+        assert_eq!(normalized(0, 20), (0, 15));
+        assert_eq!(normalized(5, 3), (5, 3));
+        // the lower zone takes precedence, and both masters keep their channel
+        assert_eq!(normalized(5, 20), (5, 9));
+        assert_eq!(normalized(15, 15), (13, 1));
+        assert_eq!(normalized(8, 8), (8, 6));
+
+        let settings = MpeSettings {
+            pitch_bend_range: 200.0,
+            master_pitch_bend_range: -2.0,
+            ..MpeSettings::default()
+        }
+        .normalized();
+        assert_eq!(settings.pitch_bend_range, 96.0);
+        assert_eq!(settings.master_pitch_bend_range, 0.0);
+    }
+
+    #[test]
+    fn assigns_channels_to_zones() {
+        let roles = |settings: MpeSettings| {
+            let channels = 0..NUM_CHANNELS as u8;
+            channels.map(|channel| settings.channel_role(channel)).collect::<Vec<_>>()
+        };
+        let lower = ChannelRole::Member(LOWER_MASTER_CHANNEL);
+        let upper = ChannelRole::Member(UPPER_MASTER_CHANNEL);
+
+        let disabled = roles(MpeSettings { enabled: false, ..zones(5, 3) });
+        assert_eq!(disabled[0], ChannelRole::Master);
+        assert!(disabled[1..].iter().all(|&role| role == ChannelRole::Ignored));
+
+        let both = roles(zones(5, 3));
+        assert_eq!(both[0], ChannelRole::Master);
+        assert!(both[1..=5].iter().all(|&role| role == lower));
+        assert!(both[6..=11].iter().all(|&role| role == ChannelRole::Ignored));
+        assert!(both[12..=14].iter().all(|&role| role == upper));
+        assert_eq!(both[15], ChannelRole::Master);
+
+        let lower_only = roles(zones(15, 0));
+        assert_eq!(lower_only[0], ChannelRole::Master);
+        assert!(lower_only[1..].iter().all(|&role| role == lower));
+
+        let upper_only = roles(zones(0, 15));
+        assert!(upper_only[..15].iter().all(|&role| role == upper));
+        assert_eq!(upper_only[15], ChannelRole::Master);
+    }
+
+    #[test]
+    fn converts_pitch_bend_to_semitones() {
+        assert_eq!(pitch_bend_semitones(8192, 48.0), 0.0);
+        assert_eq!(pitch_bend_semitones(0, 48.0), -48.0);
+        assert_eq!(pitch_bend_semitones(12288, 48.0), 24.0);
+        assert_eq!(pitch_bend_semitones(4096, 2.0), -1.0);
+        assert!((pitch_bend_semitones(16383, 2.0) - 2.0).abs() < 1e-3);
+        assert_eq!(pitch_bend_semitones(16383, 0.0), 0.0);
+    }
+}
@@ -0,0 +1,167 @@
+//! Oversampling of the voice path.
+//!
+//! Hard-edged oscillators, filter drive and waveshaping all produce harmonics far above the
+//! audible range, which fold back as aliasing at 44.1 or 48 kHz. The voices can therefore be
+//! rendered at 2, 4 or 8 times the host sample rate. Since the voices generate their signal at the
+//! oversampled rate, no interpolation is needed on the way in. On the way out, the summed voices
+//! are brought back to the host rate by a cascade of polyphase halfband decimators, each halving
+//! the rate while removing everything above the new Nyquist frequency.
+//!
+//! The decimators delay the signal by a fraction of a sample that depends on the factor. To keep
+//! the latency reported to the host constant when switching between the realtime and offline
+//! settings, every factor is padded up to the latency of the highest one.
+
+use once_cell::sync::Lazy;
+use std::f32::consts::PI;
+
+const HALFBAND_TAPS: usize = 31;
+/// Group delay of a halfband decimator in samples at its input rate.
+const HALFBAND_DELAY: usize = HALFBAND_TAPS / 2;
+
+pub const MAX_OVERSAMPLING: usize = 8;
+const MAX_DECIMATOR_STAGES: usize = 3;
+
+/// Latency of the oversampled voice path in samples at the host rate, for every factor.
+pub const OVERSAMPLING_LATENCY: usize = Oversampling::X8.latency();
+
+/// Windowed sinc lowpass at a quarter of the input rate.
+static HALFBAND: Lazy<[f32; HALFBAND_TAPS]> = Lazy::new(|| {
+    let mut taps = [0.0; HALFBAND_TAPS];
+    let span = (HALFBAND_TAPS - 1) as f32;
+    for (n, tap) in taps.iter_mut().enumerate() {
+        let x = n as f32 - span / 2.0;
+        let sinc = if x == 0.0 { 0.5 } else { (0.5 * PI * x).sin() / (PI * x) };
+        // Blackman window
+        let window =
+            0.42 - 0.5 * ((2.0 * PI * n as f32) / span).cos() +
+            0.08 * ((4.0 * PI * n as f32) / span).cos();
+        *tap = sinc * window;
+    }
+    let sum: f32 = taps.iter().sum();
+    taps.iter_mut().for_each(|tap| *tap /= sum);
+    taps
+});
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Oversampling {
+    Off,
+    X2,
+    X4,
+    X8,
+}
+
+impl Oversampling {
+    /// Inverse of `oversampling as usize`.
+    pub fn from_index(index: usize) -> Option<Self> {
+        [Oversampling::Off, Oversampling::X2, Oversampling::X4, Oversampling::X8]
+            .get(index)
+            .copied()
+    }
+
+    pub fn factor(self) -> usize {
+        1 << self as usize
+    }
+
+    fn stages(self) -> usize {
+        self as usize
+    }
+
+    /// Delay of the decimator cascade in samples at the host rate, rounded up to a whole sample.
+    const fn latency(self) -> usize {
+        let factor = 1 << self as usize;
+        // each stage runs at half the rate of the previous one, doubling its delay in samples at
+        // the oversampled rate
+        (HALFBAND_DELAY * (factor - 1)).div_ceil(factor)
+    }
+}
+
+/// Oversampling factors used while playing live and while the host renders offline. Offline
+/// rendering isn't bound to realtime, so it can afford a higher quality.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OversamplingSettings {
+    pub realtime: Oversampling,
+    pub offline: Oversampling,
+}
+
+impl Default for OversamplingSettings {
+    fn default() -> Self {
+        Self { realtime: Oversampling::X2, offline: Oversampling::X8 }
+    }
+}
+
+/// Halves the sample rate. Only every second output of the lowpass is computed, which is the
+/// polyphase form of filtering followed by dropping samples.
+#[derive(Debug, Clone)]
+struct HalfbandDecimator {
+    /// Input history, newest first.
+    history: [f32; HALFBAND_TAPS],
+}
+
+impl Default for HalfbandDecimator {
+    fn default() -> Self {
+        Self { history: [0.0; HALFBAND_TAPS] }
+    }
+}
+
+impl HalfbandDecimator {
+    fn process(&mut self, input: [f32; 2]) -> f32 {
+        self.history.copy_within(0..HALFBAND_TAPS - 2, 2);
+        self.history[1] = input[0];
+        self.history[0] = input[1];
+
+        self.history
+            .iter()
+            .zip(HALFBAND.iter())
+            .map(|(sample, tap)| sample * tap)
+            .sum()
+    }
+}
+
+/// Brings one channel of the oversampled voice path back to the host rate.
+#[derive(Debug, Clone)]
+pub struct Downsampler {
+    oversampling: Oversampling,
+    stages: [HalfbandDecimator; MAX_DECIMATOR_STAGES],
+    /// Pads the delay of the cascade up to `OVERSAMPLING_LATENCY`, at the oversampled rate.
+    padding: [f32; OVERSAMPLING_LATENCY * MAX_OVERSAMPLING],
+    padding_length: usize,
+    padding_pos: usize,
+}
+
+impl Downsampler {
+    pub fn new(oversampling: Oversampling) -> Self {
+        let factor = oversampling.factor();
+        Self {
+            oversampling,
+            stages: Default::default(),
+            padding: [0.0; OVERSAMPLING_LATENCY * MAX_OVERSAMPLING],
+            padding_length: OVERSAMPLING_LATENCY * factor - HALFBAND_DELAY * (factor - 1),
+            padding_pos: 0,
+        }
+    }
+
+    pub fn oversampling(&self) -> Oversampling {
+        self.oversampling
+    }
+
+    /// Take `factor` consecutive samples at the oversampled rate, and return one sample at the
+    /// host rate.
+    pub fn process(&mut self, input: &[f32]) -> f32 {
+        let mut buffer = [0.0; MAX_OVERSAMPLING];
+        for (padded, &sample) in buffer.iter_mut().zip(input.iter()) {
+            *padded = self.padding[self.padding_pos];
+            self.padding[self.padding_pos] = sample;
+            self.padding_pos = (self.padding_pos + 1) % self.padding_length;
+        }
+
+        // every stage writes its output over the front of the buffer it is reading from
+        let mut length = input.len();
+        for stage in self.stages.iter_mut().take(self.oversampling.stages()) {
+            length /= 2;
+            for i in 0..length {
+                buffer[i] = stage.process([buffer[2 * i], buffer[2 * i + 1]]);
+            }
+        }
+        buffer[0]
+    }
+}
@@ -0,0 +1,143 @@
+//! Tracks the host transport so modulation can follow the song tempo.
+//!
+//! The host's tempo and musical position are read once per processing block through
+//! `HostCallback::get_time_info`, and advanced sample by sample in between. When the host doesn't
+//! report a position, or playback is stopped, the transport keeps running freely at the last known
+//! tempo so that synced LFOs don't freeze.
+
+use vst::api::{ TimeInfo, TimeInfoFlags };
+
+const DEFAULT_TEMPO: f64 = 120.0;
+
+/// Time info fields requested from the host.
+pub fn time_info_mask() -> i32 {
+    (TimeInfoFlags::TEMPO_VALID | TimeInfoFlags::PPQ_POS_VALID).bits()
+}
+
+/// Selectable note divisions for synced rates, with their length in quarter notes. Sorted from the
+/// longest to the shortest, so turning a synced rate knob up speeds it up like in free mode.
+pub const NOTE_DIVISIONS: [(&str, f64); 21] = [
+    ("1/1.", 6.0),
+    ("1/1", 4.0),
+    ("1/2.", 3.0),
+    ("1/1T", 8.0 / 3.0),
+    ("1/2", 2.0),
+    ("1/4.", 1.5),
+    ("1/2T", 4.0 / 3.0),
+    ("1/4", 1.0),
+    ("1/8.", 0.75),
+    ("1/4T", 2.0 / 3.0),
+    ("1/8", 0.5),
+    ("1/16.", 0.375),
+    ("1/8T", 1.0 / 3.0),
+    ("1/16", 0.25),
+    ("1/32.", 0.1875),
+    ("1/16T", 1.0 / 6.0),
+    ("1/32", 0.125),
+    ("1/64.", 0.09375),
+    ("1/32T", 1.0 / 12.0),
+    ("1/64", 0.0625),
+    ("1/64T", 1.0 / 24.0),
+];
+
+/// Select a note division from a normalized rate parameter.
+pub fn note_division(value: f32) -> usize {
+    let steps = (NOTE_DIVISIONS.len() - 1) as f32;
+    (value.clamp(0.0, 1.0) * steps).round() as usize
+}
+
+pub struct Transport {
+    /// Tempo in beats per minute.
+    tempo: f64,
+    /// Current position in quarter notes.
+    ppq_pos: f64,
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Self { tempo: DEFAULT_TEMPO, ppq_pos: 0.0 }
+    }
+}
+
+impl Transport {
+    /// Synchronize with the host at the start of a processing block.
+    pub fn update(&mut self, time_info: Option<TimeInfo>) {
+        let time_info = match time_info {
+            Some(time_info) => time_info,
+            None => {
+                return;
+            }
+        };
+        let flags = TimeInfoFlags::from_bits_truncate(time_info.flags);
+
+        if flags.contains(TimeInfoFlags::TEMPO_VALID) && time_info.tempo > 0.0 {
+            self.tempo = time_info.tempo;
+        }
+        if
+            flags.contains(TimeInfoFlags::TRANSPORT_PLAYING) &&
+            flags.contains(TimeInfoFlags::PPQ_POS_VALID)
+        {
+            self.ppq_pos = time_info.ppq_pos;
+        }
+    }
+
+    /// Move on by one sample.
+    pub fn advance(&mut self, sample_rate: f32) {
+        self.ppq_pos += self.tempo / 60.0 / f64::from(sample_rate);
+    }
+
+    /// Phase of an LFO with a synced rate parameter, as the fraction of a cycle since the last
+    /// cycle started. Cycles are counted from the start of the song rather than the current bar,
+    /// so divisions that don't fit into a bar, like dotted whole notes in 4/4, run on across bar
+    /// lines without jumping, and modulation stays locked to the song.
+    pub fn synced_cycles(&self, rate: f32) -> f32 {
+        let (_, length) = NOTE_DIVISIONS[note_division(rate)];
+        (self.ppq_pos / length).rem_euclid(1.0) as f32
+    }
+
+    /// Length of the note division selected by a synced time parameter, in seconds.
+    pub fn synced_seconds(&self, rate: f32) -> f32 {
+        let (_, length) = NOTE_DIVISIONS[note_division(rate)];
+        (length * 60.0 / self.tempo) as f32
+    }
+
+    /// LFO cycles per sample for a synced rate parameter at the current tempo.
+    pub fn synced_increment(&self, rate: f32, sample_rate: f32) -> f32 {
+        let (_, length) = NOTE_DIVISIONS[note_division(rate)];
+        (self.tempo / 60.0 / f64::from(sample_rate) / length) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rate parameter value selecting a note division.
+    fn rate(name: &str) -> f32 {
+        let index = NOTE_DIVISIONS.iter().position(|(division, _)| *division == name).unwrap();
+        index as f32 / (NOTE_DIVISIONS.len() - 1) as f32
+    }
+
+    /// A dotted whole note is a bar and a half long in 4/4, so its phase must run on through the
+    /// bar line rather than restart there.
+    #[test]
+    fn synced_phase_runs_across_bar_lines() {
+        let dotted_whole = rate("1/1.");
+        let mut transport = Transport { ppq_pos: 3.99, ..Transport::default() };
+        let mut previous = transport.synced_cycles(dotted_whole);
+        while transport.ppq_pos < 4.01 {
+            transport.advance(44100.0);
+            let cycles = transport.synced_cycles(dotted_whole);
+            assert!((cycles - previous).rem_euclid(1.0) < 1e-4, "jumped at {}", transport.ppq_pos);
+            previous = cycles;
+        }
+        assert!((previous - 4.01 / 6.0).abs() < 1e-3);
+
+        // cycles are counted from the start of the song
+        transport.ppq_pos = 12.0;
+        assert_eq!(transport.synced_cycles(dotted_whole), 0.0);
+        transport.ppq_pos = 13.5;
+        assert_eq!(transport.synced_cycles(dotted_whole), 0.25);
+        assert_eq!(transport.synced_cycles(rate("1/4T")), 0.25);
+    }
+}
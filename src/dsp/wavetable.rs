@@ -0,0 +1,284 @@
+//! Wavetable oscillator.
+//!
+//! A wavetable is a sequence of single-cycle frames of `FRAME_SIZE` samples. The oscillator reads
+//! the cycle at the current phase and morphs linearly between neighbouring frames according to the
+//! position parameter. Tables can be loaded from single-cycle WAV files, or from WAV files holding
+//! several concatenated 2048 sample frames as exported by Serum and similar synths.
+//!
+//! Every frame is stored as a set of mipmaps, each one octave lower in bandwidth than the one
+//! before. Playback picks the mipmap whose highest harmonic still fits below Nyquist, so high notes
+//! don't alias.
+
+use std::{ fmt, path::Path, sync::Arc };
+
+use rustfft::{ num_complex::Complex, FftPlanner };
+
+/// Number of samples in one wavetable frame.
+pub const FRAME_SIZE: usize = 2048;
+
+/// Upper bound for the number of frames read from a file.
+pub const MAX_FRAMES: usize = 256;
+
+/// One mipmap per octave, from `FRAME_SIZE / 2` harmonics down to a single one.
+const NUM_MIPMAPS: usize = 11;
+
+/// Number of frames in the built-in table.
+const DEFAULT_FRAMES: usize = 16;
+
+#[derive(Debug)]
+pub enum WavetableError {
+    Wav(hound::Error),
+    Empty,
+}
+
+impl fmt::Display for WavetableError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WavetableError::Wav(error) => write!(f, "Failed to read wavetable: {}", error),
+            WavetableError::Empty => write!(f, "Wavetable file contains no samples"),
+        }
+    }
+}
+
+impl From<hound::Error> for WavetableError {
+    fn from(error: hound::Error) -> Self {
+        WavetableError::Wav(error)
+    }
+}
+
+pub struct Wavetable {
+    /// `mipmaps[level][frame]` holds one band-limited cycle with at most
+    /// `FRAME_SIZE / 2 >> level` harmonics.
+    mipmaps: Vec<Vec<Vec<f32>>>,
+}
+
+impl Wavetable {
+    /// Build a table from frames of exactly `FRAME_SIZE` samples each.
+    pub fn from_frames(frames: Vec<Vec<f32>>) -> Result<Self, WavetableError> {
+        if frames.is_empty() {
+            return Err(WavetableError::Empty);
+        }
+
+        let peak = frames
+            .iter()
+            .flat_map(|frame| frame.iter())
+            .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        let gain = if peak > 0.0 { 1.0 / peak } else { 1.0 };
+
+        let mut planner = FftPlanner::new();
+        let forward = planner.plan_fft_forward(FRAME_SIZE);
+        let inverse = planner.plan_fft_inverse(FRAME_SIZE);
+
+        let mut mipmaps: Vec<Vec<Vec<f32>>> = (0..NUM_MIPMAPS)
+            .map(|_| Vec::with_capacity(frames.len()))
+            .collect();
+        for frame in frames.iter() {
+            let mut spectrum: Vec<Complex<f32>> = frame
+                .iter()
+                .map(|sample| Complex::new(sample * gain, 0.0))
+                .collect();
+            forward.process(&mut spectrum);
+
+            for (level, mipmap) in mipmaps.iter_mut().enumerate() {
+                let max_harmonic = (FRAME_SIZE / 2) >> level;
+                let mut band_limited = spectrum.clone();
+                for (bin, value) in band_limited.iter_mut().enumerate() {
+                    let harmonic = bin.min(FRAME_SIZE - bin);
+                    if harmonic > max_harmonic {
+                        *value = Complex::new(0.0, 0.0);
+                    }
+                }
+                inverse.process(&mut band_limited);
+                mipmap.push(
+                    band_limited
+                        .iter()
+                        .map(|value| value.re / (FRAME_SIZE as f32))
+                        .collect()
+                );
+            }
+        }
+
+        Ok(Self { mipmaps })
+    }
+
+    /// Split raw samples into frames. Sample counts that are a multiple of `FRAME_SIZE` are read as
+    /// consecutive frames, anything else is taken as a single cycle and resampled to `FRAME_SIZE`.
+    pub fn from_samples(samples: &[f32]) -> Result<Self, WavetableError> {
+        if samples.is_empty() {
+            return Err(WavetableError::Empty);
+        }
+
+        let frames = if samples.len().is_multiple_of(FRAME_SIZE) {
+            samples
+                .chunks(FRAME_SIZE)
+                .take(MAX_FRAMES)
+                .map(|frame| frame.to_vec())
+                .collect()
+        } else {
+            vec![resample_cycle(samples)]
+        };
+
+        Self::from_frames(frames)
+    }
+
+    /// Load a table from a WAV file. Only the first channel is used.
+    pub fn from_wav_file(path: &Path) -> Result<Self, WavetableError> {
+        let mut reader = hound::WavReader::open(path)?;
+        let spec = reader.spec();
+        let channels = spec.channels.max(1) as usize;
+
+        let samples: Vec<f32> = match spec.sample_format {
+            hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+            hound::SampleFormat::Int => {
+                let scale = 1.0 / ((1i64 << (spec.bits_per_sample - 1)) as f32);
+                reader
+                    .samples::<i32>()
+                    .map(|sample| sample.map(|value| (value as f32) * scale))
+                    .collect::<Result<_, _>>()?
+            }
+        };
+        let first_channel: Vec<f32> = samples.into_iter().step_by(channels).collect();
+
+        Self::from_samples(&first_channel)
+    }
+
+    /// The built-in table morphs from a sawtooth into a square wave.
+    pub fn default_table() -> Arc<Self> {
+        let frames = (0..DEFAULT_FRAMES)
+            .map(|frame| {
+                let morph = (frame as f32) / ((DEFAULT_FRAMES - 1) as f32);
+                (0..FRAME_SIZE)
+                    .map(|i| {
+                        let phase = (i as f32) / (FRAME_SIZE as f32);
+                        let saw = 2.0 * phase - 1.0;
+                        let square = if phase < 0.5 { 1.0 } else { -1.0 };
+                        saw * (1.0 - morph) + square * morph
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Arc::new(Self::from_frames(frames).expect("Built-in wavetable is not empty"))
+    }
+
+    /// Read the table at `phase` (0..1 of a cycle), morphing between frames by `position` (0..1).
+    pub fn sample(&self, phase: f32, position: f32, base_freq: f32, sample_rate: f32) -> f32 {
+        let mipmap = &self.mipmaps[mipmap_level(base_freq, sample_rate)];
+
+        let frame_pos = position.clamp(0.0, 1.0) * ((mipmap.len() - 1) as f32);
+        let frame_idx = frame_pos.floor() as usize;
+        let frame_fract = frame_pos - (frame_idx as f32);
+
+        let current = read_frame(&mipmap[frame_idx], phase);
+        if frame_fract > 0.0 && frame_idx + 1 < mipmap.len() {
+            let next = read_frame(&mipmap[frame_idx + 1], phase);
+            current + (next - current) * frame_fract
+        } else {
+            current
+        }
+    }
+}
+
+/// Select the widest mipmap whose harmonics all stay below Nyquist at the given frequency.
+fn mipmap_level(base_freq: f32, sample_rate: f32) -> usize {
+    let max_harmonics = sample_rate / 2.0 / base_freq.abs().max(1.0);
+    let level = ((FRAME_SIZE as f32) / 2.0 / max_harmonics).log2().ceil();
+    if level > 0.0 { (level as usize).min(NUM_MIPMAPS - 1) } else { 0 }
+}
+
+/// Linearly interpolated read of a single cycle.
+fn read_frame(frame: &[f32], phase: f32) -> f32 {
+    let pos = phase.rem_euclid(1.0) * (FRAME_SIZE as f32);
+    let idx = (pos as usize) % FRAME_SIZE;
+    let fract = pos - pos.floor();
+    let current = frame[idx];
+    let next = frame[(idx + 1) % FRAME_SIZE];
+    current + (next - current) * fract
+}
+
+/// Linearly resample a single cycle of arbitrary length to `FRAME_SIZE` samples.
+fn resample_cycle(cycle: &[f32]) -> Vec<f32> {
+    let step = (cycle.len() as f32) / (FRAME_SIZE as f32);
+    (0..FRAME_SIZE)
+        .map(|i| {
+            let pos = (i as f32) * step;
+            let idx = pos as usize;
+            let fract = pos - (idx as f32);
+            let current = cycle[idx % cycle.len()];
+            let next = cycle[(idx + 1) % cycle.len()];
+            current + (next - current) * fract
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wave_math::TAU;
+
+    /// Highest harmonic with noticeable energy in a single cycle.
+    fn highest_harmonic(frame: &[f32]) -> usize {
+        let mut spectrum: Vec<Complex<f32>> =
+            frame.iter().map(|&sample| Complex::new(sample, 0.0)).collect();
+        FftPlanner::new().plan_fft_forward(FRAME_SIZE).process(&mut spectrum);
+        (1..=FRAME_SIZE / 2)
+            .filter(|&harmonic| spectrum[harmonic].norm() / (FRAME_SIZE as f32) > 1e-5)
+            .max()
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn mipmaps_stay_below_nyquist() {
+        let table = Wavetable::default_table();
+        let sample_rate = 44100.0;
+        for &base_freq in &[20.0, 440.0, 3000.0, 11025.0, 20000.0] {
+            let level = mipmap_level(base_freq, sample_rate);
+            for frame in &table.mipmaps[level] {
+                let harmonic = highest_harmonic(frame);
+                assert!(harmonic <= (FRAME_SIZE / 2) >> level, "{} Hz", base_freq);
+                assert!(harmonic as f32 * base_freq <= sample_rate / 2.0, "{} Hz", base_freq);
+            }
+        }
+        // the top level only holds the fundamental of the sawtooth
+        assert_eq!(mipmap_level(20000.0, sample_rate), NUM_MIPMAPS - 1);
+        assert_eq!(highest_harmonic(&table.mipmaps[NUM_MIPMAPS - 1][0]), 1);
+    }
+
+    #[test]
+    fn splits_samples_into_frames() {
+        let frames = |samples: &[f32]| Wavetable::from_samples(samples).unwrap().mipmaps[0].len();
+        assert_eq!(frames(&vec![0.5; 3 * FRAME_SIZE]), 3);
+        assert_eq!(frames(&vec![0.5; (MAX_FRAMES + 1) * FRAME_SIZE]), MAX_FRAMES);
+        // other lengths are a single cycle
+        assert_eq!(frames(&vec![0.5; 600]), 1);
+        assert_eq!(frames(&vec![0.5; FRAME_SIZE + 1]), 1);
+        assert!(matches!(Wavetable::from_samples(&[]), Err(WavetableError::Empty)));
+    }
+
+    #[test]
+    fn reads_frames_from_the_first_wav_channel() {
+        let path = std::env::temp_dir().join("machine_elf_wavetable_test.wav");
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 44100,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for i in 0..2 * FRAME_SIZE {
+            // a sine cycle per frame on the left, a full scale square wave on the right
+            let phase = (i % FRAME_SIZE) as f32 / FRAME_SIZE as f32;
+            writer.write_sample(((phase * TAU).sin() * 16384.0) as i16).unwrap();
+            writer.write_sample(if phase < 0.5 { i16::MAX } else { i16::MIN }).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let table = Wavetable::from_wav_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(table.mipmaps[0].len(), 2);
+        assert_eq!(highest_harmonic(&table.mipmaps[0][1]), 1);
+        // normalized to full scale
+        let quarter = table.mipmaps[0][0][FRAME_SIZE / 4];
+        assert!((quarter - 1.0).abs() < 1e-3, "{}", quarter);
+    }
+}
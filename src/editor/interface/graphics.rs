@@ -11,6 +11,8 @@ use wgpu::util::DeviceExt;
 use wgpu_glyph::{GlyphBrush, GlyphBrushBuilder};
 use zerocopy::AsBytes;
 
+use crate::{parameters::PARAMETERS, NOISE_AMP};
+
 use super::{
     image_consts::{ORIG_BG_SIZE_X, ORIG_BG_SIZE_Y, ORIG_KNOB_RADIUS, ORIG_KNOB_X, ORIG_KNOB_Y},
     SCALE, SIZE_X, SIZE_Y,
@@ -362,15 +364,7 @@ impl<W: raw_window_handle::HasRawWindowHandle> Renderer<W> {
                     rpass.draw_indexed(0..6, 0, 0..1);
                 }
 
-                let display_val = state.amplitude_value * 2.;
-
-                let int_text = display_val.trunc() as u8;
-                let frac_text = (display_val.fract() * 100.).trunc() as u8;
-                let text = if frac_text < 10 {
-                    format!("{}.0{}", int_text, frac_text)
-                } else {
-                    format!("{}.{}", int_text, frac_text)
-                };
+                let text = PARAMETERS[NOISE_AMP].format(state.amplitude_value);
 
                 self.text_renderer.queue(wgpu_glyph::Section {
                     text: vec![wgpu_glyph::Text::default()
@@ -3,11 +3,13 @@
 //! Fundamentally, the UI is split into graphics rendering and state management in response to
 //! input events, both of which are managed within the `EditorInterface` type.
 
-use std::sync::{mpsc::Receiver, Arc};
+use std::sync::Arc;
 
 use vst_window::EditorWindow;
 
+use crate::parameter_store::{ParameterStore, Reader};
 use crate::plugin_state::StateUpdate;
+use crate::spsc::Consumer;
 
 mod graphics;
 mod state;
@@ -64,14 +66,19 @@ impl EditorInterface {
     }
 
     /// Run as much as possible of the editor interface without blocking. This means acting on any
-    /// pending state change events from remote state storage, responding to any new window input
-    /// events, and then rendering the new state of the UI.
+    /// parameter changes and pending state change events from remote state storage, responding to
+    /// any new window input events, and then rendering the new state of the UI.
     pub fn run_tasks<S: EditorRemoteState>(
         &mut self,
         remote_state: &S,
-        incoming: &mut Receiver<StateUpdate>,
+        parameters: &ParameterStore,
+        incoming: &mut Consumer<StateUpdate>,
     ) {
-        while let Ok(event) = incoming.try_recv() {
+        let state = &mut self.state;
+        parameters.take_changes(Reader::Editor, |index, value| {
+            state.react_to_parameter_change(index, value);
+        });
+        while let Some(event) = incoming.pop() {
             self.state.react_to_control_event(event);
         }
 
@@ -6,7 +6,7 @@
 use vst_window::WindowEvent;
 
 use super::{ image_consts::{ ORIG_KNOB_RADIUS, ORIG_KNOB_X, ORIG_KNOB_Y }, SCALE, SIZE_X, SIZE_Y };
-use crate::plugin_state::StateUpdate;
+use crate::{ parameters::PARAMETERS, plugin_state::StateUpdate, NOISE_AMP };
 
 /// All the possible ways a click+drag operation on the interface window might be interpreted.
 enum DragBehavior {
@@ -42,18 +42,29 @@ impl InterfaceState {
             note: None
         }
     }
+    /// Update the editor state in response to a parameter set elsewhere, e.g. by the host.
+    pub fn react_to_parameter_change(&mut self, index: usize, value: f32) {
+        if index == NOISE_AMP {
+            self.amplitude_value = value;
+        }
+    }
+
     /// Update the editor state in response to an external message.
     pub fn react_to_control_event(&mut self, event: StateUpdate) {
         match event {
-            StateUpdate::SetKnob(index, value) => {
-                self.amplitude_value = value;
-            }
             StateUpdate::NoteOn(n) => {
                 self.note = Some(n);
             }
             StateUpdate::NoteOff(n) => {
                 self.note = Some(n);
             }
+            StateUpdate::SetWavetable(_) |
+            StateUpdate::SetTuning(_) |
+            StateUpdate::SetModRoute(..) |
+            StateUpdate::SetMorph(_) |
+            StateUpdate::SetEffectOrder(_) |
+            StateUpdate::SetOversampling(_) |
+            StateUpdate::SetMpe(_) => (),
         }
     }
 
@@ -88,18 +99,21 @@ impl InterfaceState {
                     (KNOB_RADIUS.pow(2) as isize)
                 {
                     if button == vst_window::MouseButton::Left {
+                        remote_state.begin_gesture(NOISE_AMP);
                         self.drag_behavior = Some(DragBehavior::TurnAmplitudeKnob {
                             click_y: y,
                             original_value: self.amplitude_value,
                         });
                     } else if button == vst_window::MouseButton::Right {
-                        self.amplitude_value = 0.5;
+                        self.amplitude_value = PARAMETERS[NOISE_AMP].default_value();
                         remote_state.set_amplitude_control(self.amplitude_value);
                     }
                 }
             }
             WindowEvent::MouseRelease(vst_window::MouseButton::Left) => {
-                drop(self.drag_behavior.take());
+                if let Some(DragBehavior::TurnAmplitudeKnob { .. }) = self.drag_behavior.take() {
+                    remote_state.end_gesture(NOISE_AMP);
+                }
             }
             _ => (),
         }
@@ -7,30 +7,56 @@
 //! and handles notifications of state updates that occur on the processing thread.
 
 use std::{
-    sync::{mpsc::Receiver, Arc}
+    path::Path,
+    sync::Arc
 };
 
-use vst::editor::Editor;
+use vst::editor::{Editor, KeyCode};
 use vst::plugin::PluginParameters;
 use vst_window::setup;
 
+use crate::dsp::{
+    effects::{EffectKind, NUM_EFFECTS},
+    modulation::ModRoute,
+    morph::NUM_MORPH_SLOTS,
+    mpe::MpeSettings,
+    oversampling::OversamplingSettings,
+};
+use crate::parameter_store::ParameterStore;
 use crate::plugin_state::{PluginState, StateUpdate};
+use crate::preset::{
+    fx::{FxFormat, FxKind},
+    random::{LockMask, Randomization, Section},
+    Metadata,
+};
+use crate::spsc::Consumer;
 
 mod interface;
 use interface::{EditorInterface, InterfaceState, SIZE_X, SIZE_Y};
 
+/// Modifier flags of a `KeyCode`, as defined by the VST 2 SDK. The control flag stands for the
+/// command key on macOS.
+const MODIFIER_SHIFT: u8 = 1 << 0;
+const MODIFIER_CONTROL: u8 = 1 << 3;
+
 /// Persistent VST-compatible wrapper that opens and closes an `EditorInterface`.
 pub(super) struct PluginEditor {
     opened_interface: Option<EditorInterface>,
     remote_state: Arc<PluginState>,
-    incoming: Receiver<StateUpdate>,
+    parameters: Arc<ParameterStore>,
+    incoming: Consumer<StateUpdate>,
 }
 
 impl PluginEditor {
-    pub fn new(remote_state: Arc<PluginState>, incoming: Receiver<StateUpdate>) -> Self {
+    pub fn new(
+        remote_state: Arc<PluginState>,
+        parameters: Arc<ParameterStore>,
+        incoming: Consumer<StateUpdate>,
+    ) -> Self {
         Self {
             opened_interface: None,
             remote_state,
+            parameters,
             incoming,
         }
     }
@@ -75,9 +101,35 @@ impl Editor for PluginEditor {
     }
 
     fn idle(&mut self) {
+        self.remote_state.apply_pending_program();
         if let Some(opened_interface) = &mut self.opened_interface {
-            opened_interface.run_tasks(&*self.remote_state, &mut self.incoming);
+            opened_interface.run_tasks(&*self.remote_state, &self.parameters, &mut self.incoming);
+        }
+    }
+
+    /// Undo with Ctrl+Z, redo with Ctrl+Shift+Z or Ctrl+Y. These are taken even if there is
+    /// nothing to undo, so they don't reach the host's own undo. All other keys are left to the
+    /// host.
+    fn key_down(&mut self, keycode: KeyCode) -> bool {
+        if self.opened_interface.is_none() || keycode.modifier & MODIFIER_CONTROL == 0 {
+            return false;
         }
+        let remote_state = &*self.remote_state;
+        match keycode.character.to_ascii_lowercase() {
+            'z' if keycode.modifier & MODIFIER_SHIFT != 0 => {
+                EditorRemoteState::redo(remote_state);
+            }
+            'z' => {
+                EditorRemoteState::undo(remote_state);
+            }
+            'y' => {
+                EditorRemoteState::redo(remote_state);
+            }
+            _ => {
+                return false;
+            }
+        }
+        true
     }
 }
 
@@ -90,11 +142,89 @@ impl Editor for PluginEditor {
 ///   - Pass a message to the audio processing thread, instructing it to affect its algorithm
 ///     accordingly
 ///   - Notify the host DAW if any of its knobs need to be re-rendered.
+///
+/// The interface only has controls for a few of these so far. The rest, like file import and
+/// export, randomization and the morph slots, are kept ready for its controls to come, and so are
+/// exempt from dead code warnings along with everything only they reach.
+#[allow(dead_code)]
 pub(super) trait EditorRemoteState {
     /// While the event subscription is enabled, state update events will be sent over the
-    /// `control_send` channel.
+    /// editor's event queue. Parameter changes are always picked up from the parameter store.
     fn set_event_subscription(&self, enabled: bool);
     /// Sets the position of the amplitude control to a new fraction of its full range between 0
     /// and 1.
     fn set_amplitude_control(&self, value: f32);
+    /// Replaces the wavetable oscillator's table with one read from a WAV file. Returns `false` if
+    /// the file could not be loaded, in which case the previous table stays active.
+    fn load_wavetable(&self, path: &Path) -> bool;
+    /// Connects a modulation source to a destination parameter in one slot of the modulation
+    /// matrix.
+    fn set_mod_route(&self, slot: usize, route: ModRoute);
+    /// Reorders the master effects. Returns `false` if `order` doesn't list every effect exactly
+    /// once.
+    fn set_effect_order(&self, order: [EffectKind; NUM_EFFECTS]) -> bool;
+    /// Selects the oversampling factors used for realtime playback and offline rendering.
+    fn set_oversampling(&self, settings: OversamplingSettings);
+    /// Switches MPE mode on or off, and sets its zones and pitch bend ranges.
+    fn set_mpe(&self, settings: MpeSettings);
+    /// Loads a Scala scale file. Returns `false` if the file could not be loaded, in which case
+    /// the previous tuning stays active.
+    fn load_scale(&self, path: &Path) -> bool;
+    /// Loads a Scala keyboard mapping file. Returns `false` if the file could not be loaded.
+    fn load_keyboard_mapping(&self, path: &Path) -> bool;
+    /// Sets the frequency of the reference note in Hz.
+    fn set_reference_pitch(&self, freq: f64);
+    /// Returns to 12-tone equal temperament with A4 at 440 Hz.
+    fn reset_tuning(&self);
+    /// Resets the patch to a plain sawtooth sound, keeping the MPE and oversampling settings.
+    fn initialize_patch(&self);
+    /// Stores the current sound in its program and switches to another of the `NUM_PROGRAMS`
+    /// programs.
+    fn change_program(&self, index: usize);
+    /// Replaces the current sound with one of the factory presets, by its index in
+    /// `FACTORY_PRESETS`.
+    fn load_factory_preset(&self, index: usize);
+    /// Loads a `.fxp` program into the current program, or a `.fxb` bank into the program bank.
+    /// Returns `false` if the file could not be loaded, in which case nothing changes.
+    fn load_fx_file(&self, path: &Path) -> bool;
+    /// Saves the current program or the whole bank in a standard VST preset file. Returns `false`
+    /// if the file could not be written.
+    fn save_fx_file(&self, path: &Path, kind: FxKind, format: FxFormat) -> bool;
+    /// Sets the author, category, tags and notes saved with the current sound.
+    fn set_metadata(&self, metadata: Metadata);
+    /// Loads a human-readable patch file into the current program. Returns `false` if the file
+    /// could not be loaded, in which case nothing changes.
+    fn load_text_patch(&self, path: &Path) -> bool;
+    /// Saves the current sound as a human-readable patch file. Returns `false` if the file could
+    /// not be written.
+    fn save_text_patch(&self, path: &Path) -> bool;
+    /// Loads the current sound into morph slot A (0) or B (1). Once both slots are loaded, the
+    /// morph parameter blends the sound between them.
+    fn store_morph_slot(&self, slot: usize);
+    /// Loads one of the `NUM_PROGRAMS` programs into morph slot A (0) or B (1).
+    fn load_morph_slot(&self, slot: usize, program: usize);
+    /// Empties a morph slot, which stops the morph parameter from changing the sound.
+    fn clear_morph_slot(&self, slot: usize);
+    /// Names of the patches in the morph slots, `None` for empty slots.
+    fn morph_slot_names(&self) -> [Option<String>; NUM_MORPH_SLOTS];
+    /// Randomizes the unlocked parameters of the current sound, or varies them slightly. Returns
+    /// the seed used, which reproduces the result when passed in again for the same sound.
+    fn randomize(&self, mode: Randomization, seed: Option<u64>) -> u64;
+    /// Parameters that keep their value when randomizing.
+    fn locks(&self) -> LockMask;
+    /// Locks a parameter against randomizing, by its index.
+    fn set_parameter_locked(&self, index: usize, locked: bool);
+    /// Locks every parameter of a section against randomizing.
+    fn set_section_locked(&self, section: Section, locked: bool);
+    /// Starts dragging the control of a parameter. All changes until the gesture ends are undone
+    /// in a single step.
+    fn begin_gesture(&self, index: usize);
+    /// Ends dragging the control of a parameter.
+    fn end_gesture(&self, index: usize);
+    /// Reverts the last change made in the editor. Returns `false` if there is nothing to undo.
+    fn undo(&self) -> bool;
+    /// Reapplies the last change that was undone. Returns `false` if there is nothing to redo.
+    fn redo(&self) -> bool;
+    fn can_undo(&self) -> bool;
+    fn can_redo(&self) -> bool;
 }
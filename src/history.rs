@@ -0,0 +1,86 @@
+//! Undo and redo of changes to the sound.
+//!
+//! The history keeps a snapshot of the patch from before every change made in the editor. As the
+//! snapshots hold the complete patch, loading a preset or randomizing is undone like turning a
+//! knob. Continuous edits like dragging a knob are framed by a gesture and coalesced into a single
+//! step. Changes made by the host, like automation playback, aren't recorded, and switching to
+//! another program starts a new history.
+
+use std::collections::VecDeque;
+
+use crate::preset::Patch;
+
+/// Number of steps that can be undone, the oldest ones are forgotten first.
+pub const MAX_UNDO_STEPS: usize = 100;
+
+#[derive(Default)]
+pub struct History {
+    undo: VecDeque<Patch>,
+    redo: Vec<Patch>,
+    /// The patch from before the current gesture, if one is in progress.
+    gesture: Option<Patch>,
+}
+
+impl History {
+    pub fn in_gesture(&self) -> bool {
+        self.gesture.is_some()
+    }
+
+    /// Record a change, given the patch from before it. Redoing is no longer possible afterwards.
+    pub fn record(&mut self, before: Patch) {
+        self.push_undo(before);
+        self.redo.clear();
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Start coalescing changes, given the patch from before them.
+    pub fn begin_gesture(&mut self, before: Patch) {
+        if self.gesture.is_none() {
+            self.gesture = Some(before);
+        }
+    }
+
+    /// Record all changes since the start of the gesture as a single step, unless the gesture
+    /// didn't change anything.
+    pub fn end_gesture(&mut self, current: &Patch) {
+        if let Some(before) = self.gesture.take() {
+            if before != *current {
+                self.record(before);
+            }
+        }
+    }
+
+    /// Step back, returning the patch to restore. Gestures still in progress end first.
+    pub fn undo(&mut self, current: Patch) -> Option<Patch> {
+        self.end_gesture(&current);
+        let previous = self.undo.pop_back()?;
+        self.redo.push(current);
+        Some(previous)
+    }
+
+    /// Step forward again after undoing, returning the patch to restore.
+    pub fn redo(&mut self, current: Patch) -> Option<Patch> {
+        self.end_gesture(&current);
+        let next = self.redo.pop()?;
+        self.push_undo(current);
+        Some(next)
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    fn push_undo(&mut self, patch: Patch) {
+        if self.undo.len() == MAX_UNDO_STEPS {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(patch);
+    }
+}
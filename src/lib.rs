@@ -7,29 +7,51 @@
 //! ampli-Fe's code is well-documented - feel free to use it as a starting point for your next VST2
 //! plugin in Rust.
 
-use std::sync::{ mpsc::channel, Arc };
+use std::sync::Arc;
 
 use vst::{
     event::Event,
     api::{ Supported, Events },
     buffer::AudioBuffer,
     editor::Editor,
+    host::Host,
     plugin::{ CanDo, HostCallback, Info, Plugin, PluginParameters, Category },
 };
 
 mod dsp;
-use dsp::PluginDsp;
+use dsp::{ oversampling::OVERSAMPLING_LATENCY, PluginDsp };
 
 mod editor;
 use editor::PluginEditor;
 
+mod history;
+
+mod parameter_store;
+use parameter_store::ParameterStore;
+
+mod parameters;
+
 mod plugin_state;
-use plugin_state::PluginState;
+
+mod preset;
+use plugin_state::{ PluginState, EVENT_QUEUE_CAPACITY, RETIRED_QUEUE_CAPACITY };
+
+mod spsc;
+
+mod tuning;
 
 mod wave_math;
 
+/// Version reported to the host, and written into preset files.
+pub const PLUGIN_VERSION: i32 = 1;
+/// The unique ID reported to the host, which also identifies the plugin's preset files. Hosts
+/// store it with their projects, so it must never change. It was once a hash of the plugin name,
+/// and is fixed at that value since the standard hasher may change between Rust releases.
+pub const UNIQUE_ID: i32 = i32::from_be_bytes([0xe2, 0xbb, 0x54, 0x48]);
+
 pub const NUM_VOICES: i32 = 12;
-pub const NUM_PARAMETERS: i32 = 31;
+pub const NUM_PARAMETERS: i32 = 72;
+pub const NUM_PROGRAMS: i32 = 128;
 
 // parameter indexes
 
@@ -82,11 +104,91 @@ pub const FILTER_CUTOFF_MOD_SHAPE: usize = 28;
 pub const FILTER_CUTOFF_MOD_AMP: usize = 29;
 pub const FILTER_CUTOFF_MOD_FREQ: usize = 30;
 
+// sub oscillator
+pub const SUB_AMP: usize = 31;
+pub const SUB_OCTAVE: usize = 32;
+pub const SUB_SHAPE: usize = 33;
+
+// mixer panning
+pub const NOISE_PAN: usize = 34;
+pub const SINE_PAN: usize = 35;
+pub const PULSE_PAN: usize = 36;
+pub const SAWTOOTH_PAN: usize = 37;
+pub const SUB_PAN: usize = 38;
+
+// wavetable oscillator
+pub const WAVETABLE_AMP: usize = 39;
+pub const WAVETABLE_POSITION: usize = 40;
+pub const WAVETABLE_PAN: usize = 41;
+
+// tempo sync of the modulators, switches the rate from Hz to note divisions
+pub const PULSE_WIDTH_MOD_SYNC: usize = 42;
+pub const PHASE_SHIFT_MOD_SYNC: usize = 43;
+pub const PITCH_MOD_SYNC: usize = 44;
+pub const FILTER_CUTOFF_MOD_SYNC: usize = 45;
+
+// per voice behaviour of all modulators
+pub const LFO_RETRIGGER: usize = 46;
+pub const LFO_START_PHASE: usize = 47;
+pub const LFO_FADE_DELAY: usize = 48;
+pub const LFO_FADE_RISE: usize = 49;
+
+// master effects
+pub const CHORUS_BYPASS: usize = 50;
+pub const CHORUS_RATE: usize = 51;
+pub const CHORUS_DEPTH: usize = 52;
+pub const CHORUS_MIX: usize = 53;
+
+pub const DELAY_BYPASS: usize = 54;
+pub const DELAY_TIME: usize = 55;
+pub const DELAY_SYNC: usize = 56;
+pub const DELAY_FEEDBACK: usize = 57;
+pub const DELAY_MIX: usize = 58;
+
+pub const REVERB_BYPASS: usize = 59;
+pub const REVERB_SIZE: usize = 60;
+pub const REVERB_DAMPING: usize = 61;
+pub const REVERB_MIX: usize = 62;
+
+// per voice distortion, placed before or after the filter
+pub const DISTORTION_SHAPE: usize = 63;
+pub const DISTORTION_DRIVE: usize = 64;
+pub const DISTORTION_MIX: usize = 65;
+pub const DISTORTION_POSITION: usize = 66;
+
+pub const CRUSH_BITS: usize = 67;
+pub const CRUSH_RATE: usize = 68;
+pub const CRUSH_MIX: usize = 69;
+pub const CRUSH_POSITION: usize = 70;
+
+// position between the patches in the morph slots A and B
+pub const MORPH: usize = 71;
+
+// midi
+pub const MOD_WHEEL_CC: u8 = 1;
+
+// host opcodes the `vst` crate doesn't wrap
+const HOST_GET_CURRENT_PROCESS_LEVEL: i32 = 23;
+const PROCESS_LEVEL_OFFLINE: isize = 4;
+const HOST_BEGIN_EDIT: i32 = 43;
+const HOST_END_EDIT: i32 = 44;
+
 // values
 pub const MIN_ENV_ATTACK_TIME: f32 = 0.001; // prevent pop
 pub const MAX_ENV_ATTACK_TIME: f32 = 1.0;
 pub const MAX_ENV_DECAY_TIME: f32 = 2.0;
 pub const MAX_ENV_RELEASE_TIME: f32 = 1.0;
+pub const MIN_FILTER_CUTOFF: f32 = 20.0;
+pub const MAX_FILTER_CUTOFF: f32 = 20000.0;
+pub const MIN_LFO_FREQ: f32 = 0.01;
+pub const MAX_LFO_FREQ: f32 = 50.0;
+pub const MAX_LFO_FADE_DELAY: f32 = 2.0;
+pub const MAX_LFO_FADE_RISE: f32 = 2.0;
+pub const MIN_CHORUS_RATE: f32 = 0.05;
+pub const MAX_CHORUS_RATE: f32 = 5.0;
+pub const MAX_DELAY_TIME: f32 = 2.0;
+pub const MAX_DELAY_FEEDBACK: f32 = 0.95;
+pub const MAX_DISTORTION_DRIVE: f32 = 20.0;
 
 /// Top level wrapper that exposes a full `vst::Plugin` implementation.
 struct MachineElf {
@@ -102,6 +204,9 @@ struct MachineElf {
     /// updates as they occur to other parts of the plugin. It is shared on both the audio
     /// processing thread and the UI thread, and updated using thread-safe interior mutability.
     state_handle: Arc<PluginState>,
+
+    /// Used on the audio processing thread to query the host transport.
+    host: HostCallback,
 }
 
 impl MachineElf {
@@ -109,14 +214,20 @@ impl MachineElf {
     fn new_maybe_host(maybe_host: Option<HostCallback>) -> Self {
         let host = maybe_host.unwrap_or_default();
 
-        let (to_editor, editor_recv) = channel();
-        let (to_dsp, dsp_recv) = channel();
+        let parameters = Arc::new(ParameterStore::new(&parameters::default_values()));
+        let (to_editor, editor_recv) = spsc::channel(EVENT_QUEUE_CAPACITY);
+        let (to_dsp, dsp_recv) = spsc::channel(EVENT_QUEUE_CAPACITY);
+        let (retire, retired) = spsc::channel(RETIRED_QUEUE_CAPACITY);
 
-        let state_handle = Arc::new(PluginState::new(host, to_dsp, to_editor));
+        let state_handle = Arc::new(
+            PluginState::new(host, Arc::clone(&parameters), to_dsp, to_editor, retired)
+        );
 
-        let editor_placeholder = Some(PluginEditor::new(Arc::clone(&state_handle), editor_recv));
+        let editor_placeholder = Some(
+            PluginEditor::new(Arc::clone(&state_handle), Arc::clone(&parameters), editor_recv)
+        );
 
-        let dsp = PluginDsp::new(dsp_recv);
+        let dsp = PluginDsp::new(parameters, dsp_recv, retire);
 
         log::debug!("Initialized plugin");
 
@@ -124,6 +235,7 @@ impl MachineElf {
             dsp,
             state_handle,
             editor_placeholder,
+            host,
         }
     }
 
@@ -138,19 +250,46 @@ impl MachineElf {
     ///
     /// [source]: http://www.midimountain.com/midi/midi_status.htm
     fn process_midi_event(&mut self, data: [u8; 3]) {
-        match data[0] {
-            128 => self.note_off(data[1]),
-            144 => self.note_on(data[1]),
+        let channel = data[0] & 0x0f;
+        match data[0] & 0xf0 {
+            128 => self.note_off(channel, data[1]),
+            144 => self.note_on(channel, data[1], data[2]),
+            160 => self.dsp.set_poly_pressure(channel, data[1], data[2]),
+            176 => self.dsp.control_change(channel, data[1], data[2]),
+            192 if self.dsp.is_master_channel(channel) => {
+                self.state_handle.request_program(data[1] as usize);
+            }
+            208 => self.dsp.set_channel_pressure(channel, data[1]),
+            224 => {
+                let value = u16::from(data[1]) | (u16::from(data[2]) << 7);
+                self.dsp.set_pitch_bend(channel, value);
+            }
             _ => (),
         }
     }
 
-    fn note_on(&mut self, note: u8) {
-        self.dsp.note_on(note);
+    /// Ask the host whether it is rendering offline rather than playing in realtime.
+    fn is_offline(&self) -> bool {
+        match self.host.raw_callback() {
+            Some(callback) =>
+                callback(
+                    self.host.raw_effect(),
+                    HOST_GET_CURRENT_PROCESS_LEVEL,
+                    0,
+                    0,
+                    std::ptr::null_mut(),
+                    0.0
+                ) == PROCESS_LEVEL_OFFLINE,
+            None => false,
+        }
+    }
+
+    fn note_on(&mut self, channel: u8, note: u8, velocity: u8) {
+        self.dsp.note_on(channel, note, velocity);
     }
 
-    fn note_off(&mut self, note: u8) {
-        self.dsp.note_off(note);
+    fn note_off(&mut self, channel: u8, note: u8) {
+        self.dsp.note_off(channel, note);
     }
 }
 
@@ -186,28 +325,26 @@ impl Plugin for MachineElf {
         self.dsp.set_sample_rate(rate);
     }
 
-    fn get_info(&self) -> Info {
-        /// Use a hash of a string describing this plugin to avoid unique ID conflicts.
-        const UNIQUE_ID_SEED: &str = "machineElf VST2 Plugin";
-        static UNIQUE_ID: once_cell::sync::Lazy<i32> = once_cell::sync::Lazy::new(|| {
-            use std::collections::hash_map::DefaultHasher;
-            use std::hash::{ Hash, Hasher };
-
-            let mut s = DefaultHasher::new();
-            UNIQUE_ID_SEED.hash(&mut s);
-            s.finish() as i32
-        });
+    /// Program changes and updates made while audio processing was suspended may still be
+    /// pending.
+    fn resume(&mut self) {
+        self.state_handle.apply_pending_program();
+        self.state_handle.flush_updates();
+    }
 
+    fn get_info(&self) -> Info {
         Info {
             name: "MachineElf".to_string(),
             vendor: "JLFO".to_string(),
-            unique_id: *UNIQUE_ID,
+            unique_id: UNIQUE_ID,
+            version: PLUGIN_VERSION,
             category: Category::Synth,
             inputs: 0,
             outputs: 2,
             parameters: NUM_PARAMETERS,
-            initial_delay: 0,
-            preset_chunks: false,
+            presets: NUM_PROGRAMS,
+            initial_delay: OVERSAMPLING_LATENCY as i32,
+            preset_chunks: true,
             ..Info::default()
         }
     }
@@ -218,6 +355,7 @@ impl Plugin for MachineElf {
         for event in events.events() {
             match event {
                 Event::Midi(ev) => self.process_midi_event(ev.data),
+                Event::SysEx(ev) => self.dsp.process_sysex(ev.payload),
                 // More events can be handled here.
                 _ => (),
             }
@@ -225,7 +363,9 @@ impl Plugin for MachineElf {
     }
 
     fn process(&mut self, buffer: &mut AudioBuffer<f32>) {
-        self.dsp.process(buffer);
+        let time_info = self.host.get_time_info(dsp::time_info_mask());
+        self.dsp.set_offline(self.is_offline());
+        self.dsp.process(buffer, time_info);
     }
 
     fn can_do(&self, _can_do: CanDo) -> Supported {
@@ -243,4 +383,27 @@ impl Plugin for MachineElf {
     */
 }
 
-vst::plugin_main!(MachineElf);
\ No newline at end of file
+vst::plugin_main!(MachineElf);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A program change switches the sound during playback, without waiting for the host to ask
+    /// for the current program.
+    #[test]
+    fn midi_program_changes_switch_the_sound() {
+        let mut plugin = MachineElf::default();
+        let programs = plugin.state_handle.programs();
+        assert_ne!(programs[5].parameters, programs[0].parameters);
+
+        plugin.process_midi_event([0xc0, 5, 0]);
+        let state = &plugin.state_handle;
+        let values: Vec<_> = (0..NUM_PARAMETERS).map(|index| state.get_parameter(index)).collect();
+        assert_eq!(values, programs[5].parameters);
+
+        plugin.resume();
+        assert_eq!(plugin.state_handle.get_preset_num(), 5);
+        assert_eq!(plugin.state_handle.patch(), programs[5]);
+    }
+}
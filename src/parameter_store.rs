@@ -0,0 +1,196 @@
+//! Lock-free storage of the normalized parameter values, shared by the host, the editor and the
+//! audio processing.
+//!
+//! Each value is kept as the bits of an `f32` in an `AtomicU32`, so any thread can read or write
+//! it at any time without locking. Every reader has its own set of dirty flags, one bit per
+//! parameter, which writers raise after storing a value. Readers poll for the parameters that
+//! changed since they last looked, which neither locks nor allocates, so the audio processing
+//! thread can do it at the start of every block. Several changes to a parameter between two polls
+//! are reported once, with the latest value.
+
+use std::sync::atomic::{ AtomicU32, AtomicU64, Ordering };
+
+/// Threads that poll the store for changed parameters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reader {
+    Dsp,
+    Editor,
+}
+
+const NUM_READERS: usize = 2;
+
+/// Number of dirty flags in each word.
+const FLAGS_PER_WORD: usize = 64;
+
+pub struct ParameterStore {
+    values: Vec<AtomicU32>,
+    /// Dirty flags of each reader, indexed by `Reader`.
+    dirty: [Vec<AtomicU64>; NUM_READERS],
+}
+
+impl ParameterStore {
+    /// Create a store holding `values`, all of which are reported as changed to every reader.
+    pub fn new(values: &[f32]) -> Self {
+        let words = values.len().div_ceil(FLAGS_PER_WORD);
+        let store = Self {
+            values: values.iter().map(|value| AtomicU32::new(value.to_bits())).collect(),
+            dirty: [(); NUM_READERS].map(|_| (0..words).map(|_| AtomicU64::new(0)).collect()),
+        };
+        for index in 0..values.len() {
+            store.mark_dirty(index);
+        }
+        store
+    }
+
+    pub fn get(&self, index: usize) -> f32 {
+        f32::from_bits(self.values[index].load(Ordering::Relaxed))
+    }
+
+    /// Store a value and report it to every reader.
+    pub fn set(&self, index: usize, value: f32) {
+        self.values[index].store(value.to_bits(), Ordering::Relaxed);
+        self.mark_dirty(index);
+    }
+
+    /// Current value of every parameter.
+    pub fn values(&self) -> Vec<f32> {
+        (0..self.values.len()).map(|index| self.get(index)).collect()
+    }
+
+    /// Call `changed` with the index and current value of every parameter set since the last call
+    /// for the same reader.
+    pub fn take_changes(&self, reader: Reader, mut changed: impl FnMut(usize, f32)) {
+        for (word, flags) in self.dirty[reader as usize].iter().enumerate() {
+            // pairs with the release in `mark_dirty`, so the values read below are at least as
+            // recent as the writes that raised the flags
+            let mut bits = flags.swap(0, Ordering::Acquire);
+            while bits != 0 {
+                let index = word * FLAGS_PER_WORD + bits.trailing_zeros() as usize;
+                changed(index, self.get(index));
+                bits &= bits - 1;
+            }
+        }
+    }
+
+    fn mark_dirty(&self, index: usize) {
+        let (word, bit) = (index / FLAGS_PER_WORD, index % FLAGS_PER_WORD);
+        for flags in self.dirty.iter() {
+            flags[word].fetch_or(1 << bit, Ordering::Release);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{ sync::{ atomic::AtomicBool, Arc }, thread };
+
+    fn changes(store: &ParameterStore, reader: Reader) -> Vec<(usize, f32)> {
+        let mut changes = Vec::new();
+        store.take_changes(reader, |index, value| changes.push((index, value)));
+        changes
+    }
+
+    #[test]
+    fn reports_each_change_once_per_reader() {
+        let store = ParameterStore::new(&[0.0; 70]);
+        assert_eq!(changes(&store, Reader::Dsp).len(), 70);
+        assert!(changes(&store, Reader::Dsp).is_empty());
+
+        store.set(3, 0.25);
+        store.set(3, 0.5);
+        store.set(65, 1.0);
+        assert_eq!(changes(&store, Reader::Dsp), vec![(3, 0.5), (65, 1.0)]);
+        assert!(changes(&store, Reader::Dsp).is_empty());
+        assert_eq!(changes(&store, Reader::Editor).len(), 70);
+        assert_eq!(store.get(65), 1.0);
+    }
+
+    /// Writers hammer their own parameters with rising values while a reader polls. The reader
+    /// must never see a value go back or one that was never written, and must end up with the
+    /// last value written to every parameter.
+    #[test]
+    fn readers_follow_concurrent_writers() {
+        const WRITERS: usize = 4;
+        const PARAMETERS: usize = 72;
+        const WRITES: usize = 20_000;
+        let store = Arc::new(ParameterStore::new(&[0.0; PARAMETERS]));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let reader = {
+            let (store, done) = (Arc::clone(&store), Arc::clone(&done));
+            thread::spawn(move || {
+                let mut seen = vec![0.0; PARAMETERS];
+                let poll = |seen: &mut Vec<f32>| {
+                    store.take_changes(Reader::Dsp, |index, value| {
+                        assert!(value >= seen[index], "parameter {} went back", index);
+                        assert!(value.fract() == 0.0 && value <= WRITES as f32);
+                        seen[index] = value;
+                    });
+                };
+                while !done.load(Ordering::Acquire) {
+                    poll(&mut seen);
+                }
+                poll(&mut seen);
+                seen
+            })
+        };
+
+        let writers: Vec<_> = (0..WRITERS)
+            .map(|writer| {
+                let store = Arc::clone(&store);
+                thread::spawn(move || {
+                    for count in 1..=WRITES {
+                        for index in (writer..PARAMETERS).step_by(WRITERS) {
+                            store.set(index, count as f32);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        done.store(true, Ordering::Release);
+
+        let seen = reader.join().unwrap();
+        assert_eq!(seen, vec![WRITES as f32; PARAMETERS]);
+        assert_eq!(store.values(), seen);
+    }
+
+    /// Writers racing on the same parameters only ever leave values that one of them wrote, and
+    /// the reader is told about the final value.
+    #[test]
+    fn racing_writers_leave_a_written_value() {
+        const WRITERS: usize = 4;
+        let store = Arc::new(ParameterStore::new(&[0.0; 8]));
+        let writers: Vec<_> = (0..WRITERS)
+            .map(|writer| {
+                let store = Arc::clone(&store);
+                thread::spawn(move || {
+                    for count in 0..10_000 {
+                        for index in 0..8 {
+                            store.set(index, (writer * 100_000 + count) as f32);
+                        }
+                        if writer == 0 {
+                            store.take_changes(Reader::Editor, |_, value| {
+                                assert!((value as usize) % 100_000 < 10_000);
+                            });
+                        }
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let last = store.values();
+        for value in last.iter() {
+            assert_eq!((*value as usize) % 100_000, 9_999);
+        }
+        let mut reported = vec![0.0; 8];
+        store.take_changes(Reader::Dsp, |index, value| reported[index] = value);
+        assert_eq!(reported, last);
+    }
+}
@@ -0,0 +1,456 @@
+//! Descriptions of all plugin parameters.
+//!
+//! The host only ever sees normalized parameter values from 0 to 1. Everything else about a
+//! parameter, i.e. its name, unit, plain value range, taper, steps and how it is displayed, is
+//! described by a single `ParameterInfo` in the `PARAMETERS` table. The plugin state uses it to
+//! answer the host's name, label and text queries, the audio processing maps normalized values to
+//! plain ones through it, and the editor displays values with it, so all of them always agree.
+
+use crate::*;
+use crate::dsp::distortion::{ MAX_CRUSH_BITS, MAX_CRUSH_RATE, MIN_CRUSH_RATE };
+
+/// Power of the taper of all times. The middle of the knob sits at an eighth of the longest time,
+/// leaving most of the travel to the short times that need a finer resolution.
+const TIME_SKEW: f32 = 3.0;
+
+/// How the normalized value is spread across the plain value range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Taper {
+    Linear,
+    /// Equal ratios of the plain value take equal knob travel, as for frequencies. Requires a
+    /// positive minimum.
+    Log,
+    /// The plain value follows the normalized value raised to the given power, which gives more
+    /// resolution to the low end for powers above 1.
+    Exp(f32),
+}
+
+/// How the plain value is displayed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// A number with the given count of decimals.
+    Number(usize),
+    /// A stereo position from L100 over C to R100.
+    Pan,
+    /// One label per step.
+    Labels(&'static [&'static str]),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParameterInfo {
+    /// Identifier under which the value is saved, see `stable_id`.
+    pub id: &'static str,
+    pub name: &'static str,
+    pub unit: &'static str,
+    pub min: f32,
+    pub max: f32,
+    /// Plain value of a new patch.
+    pub default: f32,
+    pub taper: Taper,
+    /// Number of steps between the lowest and highest setting, 0 for continuous parameters.
+    pub steps: u8,
+    pub format: Format,
+    /// Index of the switch that syncs this rate or time to the host tempo, if any. Synced values
+    /// select one of the `NOTE_DIVISIONS` instead.
+    pub sync: Option<usize>,
+}
+
+impl ParameterInfo {
+    const fn new(
+        name: &'static str,
+        unit: &'static str,
+        min: f32,
+        max: f32,
+        decimals: usize
+    ) -> Self {
+        Self {
+            id: "",
+            name,
+            unit,
+            min,
+            max,
+            default: min,
+            taper: Taper::Linear,
+            steps: 0,
+            format: Format::Number(decimals),
+            sync: None,
+        }
+    }
+
+    const fn percent(name: &'static str) -> Self {
+        Self::new(name, "%", 0.0, 100.0, 1)
+    }
+
+    const fn seconds(name: &'static str, max: f32) -> Self {
+        let mut info = Self::new(name, "s", 0.0, max, 3);
+        info.taper = Taper::Exp(TIME_SKEW);
+        info
+    }
+
+    const fn hertz(name: &'static str, min: f32, max: f32) -> Self {
+        Self::new(name, "Hz", min, max, 2).log()
+    }
+
+    const fn pan(name: &'static str) -> Self {
+        let mut info = Self::new(name, "", -100.0, 100.0, 0);
+        info.default = 0.0;
+        info.format = Format::Pan;
+        info
+    }
+
+    /// A selector whose plain value is the index of the selected label.
+    const fn choice(name: &'static str, labels: &'static [&'static str]) -> Self {
+        let mut info = Self::new(name, "", 0.0, (labels.len() - 1) as f32, 0);
+        info.steps = (labels.len() - 1) as u8;
+        info.format = Format::Labels(labels);
+        info
+    }
+
+    const fn with_default(mut self, default: f32) -> Self {
+        self.default = default;
+        self
+    }
+
+    const fn log(mut self) -> Self {
+        self.taper = Taper::Log;
+        self
+    }
+
+    const fn stepped(mut self, steps: u8) -> Self {
+        self.steps = steps;
+        self
+    }
+
+    const fn synced_by(mut self, sync: usize) -> Self {
+        self.sync = Some(sync);
+        self
+    }
+
+    /// Round a normalized value to the nearest step.
+    pub fn quantize(&self, value: f32) -> f32 {
+        let value = value.clamp(0.0, 1.0);
+        if self.steps == 0 {
+            value
+        } else {
+            let steps = f32::from(self.steps);
+            (value * steps).round() / steps
+        }
+    }
+
+    /// Index of the step selected by a normalized value, e.g. the index of a selector's label.
+    pub fn step(&self, value: f32) -> u8 {
+        (value.clamp(0.0, 1.0) * f32::from(self.steps)).round() as u8
+    }
+
+    /// Map a normalized value onto the plain value range. Values outside of 0 to 1, e.g. after
+    /// modulation, are limited to the range.
+    pub fn plain(&self, value: f32) -> f32 {
+        let value = self.quantize(value);
+        match self.taper {
+            Taper::Linear => self.min + (self.max - self.min) * value,
+            Taper::Log => self.min * (self.max / self.min).powf(value),
+            Taper::Exp(power) => self.min + (self.max - self.min) * value.powf(power),
+        }
+    }
+
+    /// Inverse of `plain`. Plain values outside of the range map to values outside of 0 to 1.
+    pub fn normalize(&self, plain: f32) -> f32 {
+        match self.taper {
+            Taper::Linear => (plain - self.min) / (self.max - self.min),
+            Taper::Log => (plain / self.min).ln() / (self.max / self.min).ln(),
+            Taper::Exp(power) => ((plain - self.min) / (self.max - self.min)).powf(power.recip()),
+        }
+    }
+
+    /// Normalized value of a new patch.
+    pub fn default_value(&self) -> f32 {
+        self.normalize(self.default)
+    }
+
+    /// Display text of a normalized value, without the unit.
+    pub fn format(&self, value: f32) -> String {
+        match self.format {
+            Format::Number(decimals) => format!("{:.*}", decimals, self.plain(value)),
+            Format::Pan => {
+                let pan = self.plain(value);
+                if pan.abs() < 0.5 {
+                    "C".to_string()
+                } else if pan < 0.0 {
+                    format!("L{:.0}", -pan)
+                } else {
+                    format!("R{:.0}", pan)
+                }
+            }
+            Format::Labels(labels) => labels[self.step(value) as usize].to_string(),
+        }
+    }
+
+    /// Inverse of `format`. Accepts the display text, optionally followed by the unit, and returns
+    /// the normalized value, which lies outside of 0 to 1 if the text is out of range.
+    pub fn parse(&self, text: &str) -> Option<f32> {
+        let text = text.trim();
+        let text = text.strip_suffix(self.unit).unwrap_or(text).trim_end();
+        match self.format {
+            Format::Number(_) => {
+                let plain = text.parse::<f32>().ok()?;
+                Some(self.normalize(plain)).filter(|value| value.is_finite())
+            }
+            Format::Pan => {
+                let pan = if text.eq_ignore_ascii_case("C") {
+                    0.0
+                } else if let Some(amount) = text.strip_prefix(['L', 'l']) {
+                    -amount.parse::<f32>().ok()?
+                } else {
+                    text.strip_prefix(['R', 'r'])?.parse::<f32>().ok()?
+                };
+                Some(self.normalize(pan))
+            }
+            Format::Labels(labels) => parse_label(text, labels),
+        }
+    }
+}
+
+/// Position of `text` in a list of step labels, as a normalized parameter value.
+pub fn parse_label(text: &str, labels: &[&str]) -> Option<f32> {
+    labels
+        .iter()
+        .position(|label| label.eq_ignore_ascii_case(text))
+        .map(|step| (step as f32) / ((labels.len() - 1) as f32))
+}
+
+const LFO_SHAPES: &[&str] = &["sine", "square", "triangle", "saw", "sample and hold"];
+const SYNC_MODES: &[&str] = &["free", "sync"];
+const BYPASS_MODES: &[&str] = &["on", "bypassed"];
+const POSITIONS: &[&str] = &["pre filter", "post filter"];
+
+const fn describe(index: usize) -> ParameterInfo {
+    match index {
+        NOISE_AMP => ParameterInfo::percent("Noise"),
+        NOISE_COLOR => ParameterInfo::choice("Noise Color", &["white", "pink"]),
+
+        SINE_AMP => ParameterInfo::percent("Sine"),
+        SINE_OCTAVE => ParameterInfo::percent("SineOctave"),
+
+        PULSE_AMP => ParameterInfo::percent("Pulse"),
+        PULSE_WIDTH => ParameterInfo::percent("Pulse width").with_default(50.0),
+        PULSE_WIDTH_MOD_AMP => ParameterInfo::percent("Pulse width modulation amplitude"),
+        PULSE_WIDTH_MOD_FREQ =>
+            ParameterInfo::hertz("Pulse width modulation frequency", MIN_LFO_FREQ, MAX_LFO_FREQ)
+                .synced_by(PULSE_WIDTH_MOD_SYNC)
+                .with_default(0.1),
+
+        SAWTOOTH_AMP => ParameterInfo::percent("Sawtooth").with_default(80.0),
+        SAWTOOTH_SHAPE => ParameterInfo::percent("Sawtooth width"),
+
+        PHASE_SHIFT_AMOUNT => ParameterInfo::percent("Channel phase shift amount"),
+        PHASE_SHIFT_MOD_FREQ =>
+            ParameterInfo::hertz("Phase shift modulation frequency", MIN_LFO_FREQ, MAX_LFO_FREQ)
+                .synced_by(PHASE_SHIFT_MOD_SYNC)
+                .with_default(0.5),
+        PHASE_SHIFT_MOD_SHAPE =>
+            ParameterInfo::choice("Phase shift modulation wave form", LFO_SHAPES),
+
+        PITCH_MOD_SHAPE => ParameterInfo::choice("Pitch modulation waveform", LFO_SHAPES),
+        PITCH_MOD_AMP => ParameterInfo::percent("Pitch modulation amplitude"),
+        PITCH_MOD_FREQ =>
+            ParameterInfo::hertz("Pitch modulation frequency", MIN_LFO_FREQ, MAX_LFO_FREQ)
+                .synced_by(PITCH_MOD_SYNC)
+                .with_default(5.0),
+
+        AMP_ATTACK => ParameterInfo::seconds("Attack", MAX_ENV_ATTACK_TIME).with_default(0.01),
+        AMP_DECAY => ParameterInfo::seconds("Decay", MAX_ENV_DECAY_TIME).with_default(0.3),
+        AMP_SUSTAIN_LEVEL => ParameterInfo::percent("Sustain").with_default(80.0),
+        AMP_RELEASE => ParameterInfo::seconds("Release", MAX_ENV_RELEASE_TIME).with_default(0.2),
+
+        FILTER_ATTACK =>
+            ParameterInfo::seconds("Filter Attack", MAX_ENV_ATTACK_TIME).with_default(0.01),
+        FILTER_DECAY =>
+            ParameterInfo::seconds("Filter Decay", MAX_ENV_DECAY_TIME).with_default(0.5),
+        FILTER_SUSTAIN_LEVEL => ParameterInfo::percent("Filter Sustain").with_default(50.0),
+        FILTER_RELEASE =>
+            ParameterInfo::seconds("Filter Release", MAX_ENV_RELEASE_TIME).with_default(0.3),
+
+        FILTER_CUTOFF =>
+            ParameterInfo::new("Cutoff", "Hz", MIN_FILTER_CUTOFF, MAX_FILTER_CUTOFF, 0)
+                .log()
+                .with_default(MAX_FILTER_CUTOFF),
+        FILTER_RESONANCE => ParameterInfo::percent("Resonance"),
+        FILTER_POLES => ParameterInfo::new("Poles", "", 1.0, 4.0, 0).stepped(3).with_default(4.0),
+        FILTER_DRIVE => ParameterInfo::percent("Drive"),
+
+        FILTER_CUTOFF_MOD_SHAPE => ParameterInfo::choice("Cutoff modulation waveform", LFO_SHAPES),
+        FILTER_CUTOFF_MOD_AMP => ParameterInfo::percent("Cutoff modulation amplitude"),
+        FILTER_CUTOFF_MOD_FREQ =>
+            ParameterInfo::hertz("Cutoff modulation frequency", MIN_LFO_FREQ, MAX_LFO_FREQ)
+                .synced_by(FILTER_CUTOFF_MOD_SYNC)
+                .with_default(2.0),
+
+        SUB_AMP => ParameterInfo::percent("Sub"),
+        SUB_OCTAVE => ParameterInfo::choice("Sub octave", &["-1 octave", "-2 octaves"]),
+        SUB_SHAPE => ParameterInfo::choice("Sub waveform", &["square", "sine"]),
+
+        NOISE_PAN => ParameterInfo::pan("Noise pan"),
+        SINE_PAN => ParameterInfo::pan("Sine pan"),
+        PULSE_PAN => ParameterInfo::pan("Pulse pan"),
+        SAWTOOTH_PAN => ParameterInfo::pan("Sawtooth pan"),
+        SUB_PAN => ParameterInfo::pan("Sub pan"),
+
+        WAVETABLE_AMP => ParameterInfo::percent("Wavetable"),
+        WAVETABLE_POSITION => ParameterInfo::percent("Wavetable position"),
+        WAVETABLE_PAN => ParameterInfo::pan("Wavetable pan"),
+
+        PULSE_WIDTH_MOD_SYNC => ParameterInfo::choice("Pulse width modulation sync", SYNC_MODES),
+        PHASE_SHIFT_MOD_SYNC => ParameterInfo::choice("Phase shift modulation sync", SYNC_MODES),
+        PITCH_MOD_SYNC => ParameterInfo::choice("Pitch modulation sync", SYNC_MODES),
+        FILTER_CUTOFF_MOD_SYNC => ParameterInfo::choice("Cutoff modulation sync", SYNC_MODES),
+
+        LFO_RETRIGGER => ParameterInfo::choice("Modulation retrigger", &["free", "retrigger"]),
+        LFO_START_PHASE => ParameterInfo::new("Modulation start phase", "deg", 0.0, 360.0, 0),
+        LFO_FADE_DELAY => ParameterInfo::seconds("Modulation fade delay", MAX_LFO_FADE_DELAY),
+        LFO_FADE_RISE => ParameterInfo::seconds("Modulation fade rise", MAX_LFO_FADE_RISE),
+
+        CHORUS_BYPASS => ParameterInfo::choice("Chorus bypass", BYPASS_MODES).with_default(1.0),
+        CHORUS_RATE =>
+            ParameterInfo::hertz("Chorus rate", MIN_CHORUS_RATE, MAX_CHORUS_RATE).with_default(0.8),
+        CHORUS_DEPTH => ParameterInfo::percent("Chorus depth").with_default(50.0),
+        CHORUS_MIX => ParameterInfo::percent("Chorus mix").with_default(50.0),
+
+        DELAY_BYPASS => ParameterInfo::choice("Delay bypass", BYPASS_MODES).with_default(1.0),
+        DELAY_TIME =>
+            ParameterInfo::seconds("Delay time", MAX_DELAY_TIME)
+                .synced_by(DELAY_SYNC)
+                .with_default(0.375),
+        DELAY_SYNC => ParameterInfo::choice("Delay sync", SYNC_MODES),
+        DELAY_FEEDBACK =>
+            ParameterInfo::new("Delay feedback", "%", 0.0, MAX_DELAY_FEEDBACK * 100.0, 1)
+                .with_default(40.0),
+        DELAY_MIX => ParameterInfo::percent("Delay mix").with_default(30.0),
+
+        REVERB_BYPASS => ParameterInfo::choice("Reverb bypass", BYPASS_MODES).with_default(1.0),
+        REVERB_SIZE => ParameterInfo::percent("Reverb size").with_default(50.0),
+        REVERB_DAMPING => ParameterInfo::percent("Reverb damping").with_default(50.0),
+        REVERB_MIX => ParameterInfo::percent("Reverb mix").with_default(25.0),
+
+        DISTORTION_SHAPE =>
+            ParameterInfo::choice(
+                "Distortion curve",
+                &["tanh", "hard clip", "foldback", "tube"]
+            ),
+        DISTORTION_DRIVE => ParameterInfo::percent("Distortion drive"),
+        DISTORTION_MIX => ParameterInfo::percent("Distortion mix"),
+        DISTORTION_POSITION => ParameterInfo::choice("Distortion position", POSITIONS),
+
+        CRUSH_BITS =>
+            ParameterInfo::new("Bitcrusher depth", "bit", 1.0, MAX_CRUSH_BITS as f32, 0)
+                .stepped(MAX_CRUSH_BITS - 1)
+                .with_default(MAX_CRUSH_BITS as f32),
+        CRUSH_RATE =>
+            ParameterInfo::new("Bitcrusher rate", "Hz", MIN_CRUSH_RATE, MAX_CRUSH_RATE, 0)
+                .log()
+                .with_default(MAX_CRUSH_RATE),
+        CRUSH_MIX => ParameterInfo::percent("Bitcrusher mix"),
+        CRUSH_POSITION => ParameterInfo::choice("Bitcrusher position", POSITIONS),
+
+        MORPH => ParameterInfo::percent("Morph"),
+
+        _ => panic!("undescribed parameter index"),
+    }
+}
+
+/// Stable identifier of every parameter, under which its value is saved. Unlike the index and the
+/// name, the identifier of a parameter must never change once released.
+const fn stable_id(index: usize) -> &'static str {
+    match index {
+        NOISE_AMP => "noise_amp",
+        NOISE_COLOR => "noise_color",
+        SINE_AMP => "sine_amp",
+        SINE_OCTAVE => "sine_octave",
+        PULSE_AMP => "pulse_amp",
+        PULSE_WIDTH => "pulse_width",
+        PULSE_WIDTH_MOD_AMP => "pulse_width_mod_amp",
+        PULSE_WIDTH_MOD_FREQ => "pulse_width_mod_freq",
+        SAWTOOTH_AMP => "sawtooth_amp",
+        SAWTOOTH_SHAPE => "sawtooth_shape",
+        PHASE_SHIFT_MOD_SHAPE => "phase_shift_mod_shape",
+        PHASE_SHIFT_AMOUNT => "phase_shift_amount",
+        PHASE_SHIFT_MOD_FREQ => "phase_shift_mod_freq",
+        PITCH_MOD_SHAPE => "pitch_mod_shape",
+        PITCH_MOD_AMP => "pitch_mod_amp",
+        PITCH_MOD_FREQ => "pitch_mod_freq",
+        AMP_ATTACK => "amp_attack",
+        AMP_DECAY => "amp_decay",
+        AMP_SUSTAIN_LEVEL => "amp_sustain_level",
+        AMP_RELEASE => "amp_release",
+        FILTER_ATTACK => "filter_attack",
+        FILTER_DECAY => "filter_decay",
+        FILTER_SUSTAIN_LEVEL => "filter_sustain_level",
+        FILTER_RELEASE => "filter_release",
+        FILTER_CUTOFF => "filter_cutoff",
+        FILTER_RESONANCE => "filter_resonance",
+        FILTER_POLES => "filter_poles",
+        FILTER_DRIVE => "filter_drive",
+        FILTER_CUTOFF_MOD_SHAPE => "filter_cutoff_mod_shape",
+        FILTER_CUTOFF_MOD_AMP => "filter_cutoff_mod_amp",
+        FILTER_CUTOFF_MOD_FREQ => "filter_cutoff_mod_freq",
+        SUB_AMP => "sub_amp",
+        SUB_OCTAVE => "sub_octave",
+        SUB_SHAPE => "sub_shape",
+        NOISE_PAN => "noise_pan",
+        SINE_PAN => "sine_pan",
+        PULSE_PAN => "pulse_pan",
+        SAWTOOTH_PAN => "sawtooth_pan",
+        SUB_PAN => "sub_pan",
+        WAVETABLE_AMP => "wavetable_amp",
+        WAVETABLE_POSITION => "wavetable_position",
+        WAVETABLE_PAN => "wavetable_pan",
+        PULSE_WIDTH_MOD_SYNC => "pulse_width_mod_sync",
+        PHASE_SHIFT_MOD_SYNC => "phase_shift_mod_sync",
+        PITCH_MOD_SYNC => "pitch_mod_sync",
+        FILTER_CUTOFF_MOD_SYNC => "filter_cutoff_mod_sync",
+        LFO_RETRIGGER => "lfo_retrigger",
+        LFO_START_PHASE => "lfo_start_phase",
+        LFO_FADE_DELAY => "lfo_fade_delay",
+        LFO_FADE_RISE => "lfo_fade_rise",
+        CHORUS_BYPASS => "chorus_bypass",
+        CHORUS_RATE => "chorus_rate",
+        CHORUS_DEPTH => "chorus_depth",
+        CHORUS_MIX => "chorus_mix",
+        DELAY_BYPASS => "delay_bypass",
+        DELAY_TIME => "delay_time",
+        DELAY_SYNC => "delay_sync",
+        DELAY_FEEDBACK => "delay_feedback",
+        DELAY_MIX => "delay_mix",
+        REVERB_BYPASS => "reverb_bypass",
+        REVERB_SIZE => "reverb_size",
+        REVERB_DAMPING => "reverb_damping",
+        REVERB_MIX => "reverb_mix",
+        DISTORTION_SHAPE => "distortion_shape",
+        DISTORTION_DRIVE => "distortion_drive",
+        DISTORTION_MIX => "distortion_mix",
+        DISTORTION_POSITION => "distortion_position",
+        CRUSH_BITS => "crush_bits",
+        CRUSH_RATE => "crush_rate",
+        CRUSH_MIX => "crush_mix",
+        CRUSH_POSITION => "crush_position",
+        MORPH => "morph",
+        _ => panic!("parameter index without identifier"),
+    }
+}
+
+/// Normalized value of every parameter in a new patch, a plain sawtooth through the open filter.
+pub fn default_values() -> Vec<f32> {
+    PARAMETERS.iter().map(ParameterInfo::default_value).collect()
+}
+
+/// Every parameter, by index. Building the table at compile time ensures that no parameter is left
+/// undescribed or without identifier.
+pub static PARAMETERS: [ParameterInfo; NUM_PARAMETERS as usize] = {
+    let mut table = [describe(0); NUM_PARAMETERS as usize];
+    let mut index = 0;
+    while index < table.len() {
+        table[index] = describe(index);
+        table[index].id = stable_id(index);
+        index += 1;
+    }
+    table
+};
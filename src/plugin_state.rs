@@ -1,34 +1,176 @@
 //! Parameters are kept as the single "source of truth" for the long-term state of the plugin. As
 //! used by the VST API, the parameter bank is accessible by both the audio processing thread and
-//! the UI thread, and updated using thread-safe interior mutability. However, to avoid costly
-//! synchronization overhead, and to reduce recalculation of derived parameters, the audio
-//! processing and UI threads subscribe to parameter updates through cross-thread message passing.
+//! the UI thread, and updated using thread-safe interior mutability. Parameter values live in a
+//! lock-free `ParameterStore`, which the audio processing and UI threads poll for changes, so the
+//! host can automate parameters without ever waiting on a lock. Everything else, like wavetables
+//! and modulation routes, is handed over as `StateUpdate` events through bounded queues. Sending an
+//! event takes a lock, which only the UI thread and the host's non-realtime calls like loading a
+//! preset do. The audio processing thread never locks, and hands the wavetables, tunings and morphs
+//! it replaces back through another queue to be freed elsewhere.
 //!
-//! This plugin's long-term state only consists of a single floating-point value (the value of the
-//! amplitude knob), but it should be simple to extend this scheme to work with multiple knobs,
-//! toggles, node locations, waveforms, user-defined labels, and so on.
+//! The long-term state of the current sound is its parameter values, name and metadata, wavetable,
+//! modulation routes, effect order and tuning. Around it sit the bank of `NUM_PROGRAMS` programs
+//! and the setup of MPE, oversampling and the morph slots, which stays the same when switching
+//! programs. The host saves and restores all of it as chunks, see `preset::chunk`. Parameter
+//! locks for randomization and the undo history only last as long as the plugin instance.
 
-use std::sync::{ atomic::{ AtomicBool, Ordering }, mpsc::Sender, Mutex };
+use std::{
+    path::{ Path, PathBuf },
+    mem::discriminant,
+    sync::{ atomic::{ AtomicBool, AtomicUsize, Ordering }, Arc, Mutex },
+};
 
 use vst::{ host::Host, plugin::{ HostCallback, PluginParameters } };
 
 use crate::*;
+use crate::history::History;
+use crate::parameter_store::ParameterStore;
+use crate::parameters::{ parse_label, PARAMETERS };
+use crate::preset::{
+    chunk,
+    factory,
+    fx::{ self, FxFormat, FxKind },
+    random::{ self, LockMask, Randomization, Section },
+    text,
+    Metadata,
+    Patch,
+    PresetError,
+    Setup,
+    INIT_PATCH_NAME,
+};
+use crate::spsc::{ Consumer, Producer };
+use crate::tuning::{ scala::{ KeyboardMapping, Scale }, Tuning, TuningError, TuningTable };
+use crate::dsp::{
+    note_division,
+    NOTE_DIVISIONS,
+    effects::{ is_valid_effect_order, EffectKind, DEFAULT_EFFECT_ORDER, NUM_EFFECTS },
+    modulation::{ ModRoute, NUM_MOD_SLOTS },
+    morph::{ self, Morph, NUM_MORPH_SLOTS },
+    mpe::MpeSettings,
+    oversampling::OversamplingSettings,
+    wavetable::{ Wavetable, WavetableError },
+};
 
-/// Describes a discrete operation that can update this plugin's long-term state.
+/// Number of events that fit into the queues to the audio processing and UI threads.
+pub const EVENT_QUEUE_CAPACITY: usize = 256;
+/// Number of items that fit into the queue back from the audio processing thread. It has room for
+/// a full event queue along with the backlog held back from it.
+pub const RETIRED_QUEUE_CAPACITY: usize = 2 * EVENT_QUEUE_CAPACITY;
+
+/// Describes a discrete operation that can update this plugin's long-term state, other than
+/// setting a parameter.
 #[derive(Clone)]
 pub enum StateUpdate {
-    SetKnob(i32, f32),
     NoteOn(u8),
     NoteOff(u8),
+    SetWavetable(Arc<Wavetable>),
+    SetTuning(Arc<TuningTable>),
+    SetModRoute(usize, ModRoute),
+    SetMorph(Option<Arc<Morph>>),
+    SetEffectOrder([EffectKind; NUM_EFFECTS]),
+    SetOversampling(OversamplingSettings),
+    SetMpe(MpeSettings),
+}
+
+/// Shared data replaced on the audio processing thread, which is handed back to be freed on
+/// another thread. The data is only held until then, and never read.
+#[allow(dead_code)]
+pub enum Retired {
+    Wavetable(Arc<Wavetable>),
+    Tuning(Arc<TuningTable>),
+    Morph(Arc<Morph>),
+}
+
+impl StateUpdate {
+    /// Whether this update makes an earlier `other` one redundant, because it sets the same
+    /// piece of state.
+    fn replaces(&self, other: &StateUpdate) -> bool {
+        match (self, other) {
+            (StateUpdate::NoteOn(_), _) | (StateUpdate::NoteOff(_), _) => false,
+            (StateUpdate::SetModRoute(slot, _), StateUpdate::SetModRoute(other_slot, _)) =>
+                slot == other_slot,
+            _ => discriminant(self) == discriminant(other),
+        }
+    }
+}
+
+/// Sending end of an event queue. Events that don't fit while the receiving thread isn't keeping
+/// up, e.g. while the host has suspended audio processing, are held back and sent along with
+/// later ones. Only the latest of them is kept for each piece of state, so nothing is lost and the
+/// backlog can't grow without bounds.
+struct Outbox {
+    queue: Producer<StateUpdate>,
+    backlog: Vec<StateUpdate>,
+}
+
+impl Outbox {
+    fn new(queue: Producer<StateUpdate>) -> Self {
+        Self { queue, backlog: Vec::new() }
+    }
+
+    fn send(&mut self, state_update: StateUpdate) {
+        self.backlog.retain(|pending| !state_update.replaces(pending));
+        self.backlog.push(state_update);
+        self.flush();
+    }
+
+    /// Move as many held back events into the queue as fit.
+    fn flush(&mut self) {
+        let mut sent = 0;
+        for state_update in self.backlog.iter() {
+            if self.queue.push(state_update.clone()).is_err() {
+                break;
+            }
+            sent += 1;
+        }
+        self.backlog.drain(..sent);
+    }
 }
 
 pub struct PluginState {
     host: HostCallback,
-    to_dsp: Mutex<Sender<StateUpdate>>,
-    to_editor: Mutex<Sender<StateUpdate>>,
+    to_dsp: Mutex<Outbox>,
+    to_editor: Mutex<Outbox>,
+    /// Data the audio processing thread is done with, freed whenever an update is sent.
+    retired: Mutex<Consumer<Retired>>,
     editor_is_open: AtomicBool,
 
-    state_record: Mutex<Vec<f32>>,
+    /// Name of the current program.
+    name: Mutex<String>,
+    metadata: Mutex<Metadata>,
+    /// Values of all parameters, also read by the audio processing and UI threads.
+    parameters: Arc<ParameterStore>,
+    /// File the current wavetable was loaded from, `None` for the built-in table.
+    wavetable_path: Mutex<Option<PathBuf>>,
+    mod_routes: Mutex<[ModRoute; NUM_MOD_SLOTS]>,
+    effect_order: Mutex<[EffectKind; NUM_EFFECTS]>,
+    oversampling: Mutex<OversamplingSettings>,
+    tuning: Mutex<Tuning>,
+    mpe: Mutex<MpeSettings>,
+
+    /// Stored programs. The slot of the current program is only brought up to date when switching
+    /// away from it or saving the bank, the live sound is kept in the fields above.
+    programs: Mutex<Vec<Patch>>,
+    /// Parameter values of every stored program, which a MIDI program change switches to on the
+    /// audio thread without locking.
+    program_values: Vec<ParameterStore>,
+    current_program: AtomicUsize,
+    /// Program whose name, modulation routes, tuning and other state beyond the parameters is
+    /// loaded. It lags behind `current_program` after a MIDI program change, until the next call
+    /// of `apply_pending_program`.
+    loaded_program: AtomicUsize,
+
+    /// Patches loaded into the morph slots A and B, which are saved with the setup.
+    morph_slots: Mutex<[Option<Patch>; NUM_MORPH_SLOTS]>,
+    /// Parameters of the morph slots, which the host's automation thread blends without locking.
+    morph_slot_values: [ParameterStore; NUM_MORPH_SLOTS],
+    /// Whether both morph slots are loaded.
+    morphing: AtomicBool,
+
+    /// Parameters that keep their value when randomizing the patch.
+    locks: Mutex<LockMask>,
+    /// Changes made in the editor, which can be undone.
+    history: Mutex<History>,
 }
 
 /// VST-accessible long-term plugin state storage. This is accessed through the audio processing
@@ -37,161 +179,676 @@ pub struct PluginState {
 impl PluginState {
     pub fn new(
         host: HostCallback,
-        to_dsp: Sender<StateUpdate>,
-        to_editor: Sender<StateUpdate>
+        parameters: Arc<ParameterStore>,
+        to_dsp: Producer<StateUpdate>,
+        to_editor: Producer<StateUpdate>,
+        retired: Consumer<Retired>
     ) -> Self {
-        Self {
+        let state = Self {
             host,
-            to_dsp: Mutex::new(to_dsp),
-            to_editor: Mutex::new(to_editor),
+            to_dsp: Mutex::new(Outbox::new(to_dsp)),
+            to_editor: Mutex::new(Outbox::new(to_editor)),
+            retired: Mutex::new(retired),
             editor_is_open: AtomicBool::new(false),
-            state_record: Mutex::new(vec![0.1; NUM_PARAMETERS as usize]),
+            name: Mutex::new(INIT_PATCH_NAME.to_string()),
+            metadata: Mutex::new(Metadata::default()),
+            parameters,
+            wavetable_path: Mutex::new(None),
+            mod_routes: Mutex::new([ModRoute::default(); NUM_MOD_SLOTS]),
+            effect_order: Mutex::new(DEFAULT_EFFECT_ORDER),
+            oversampling: Mutex::new(OversamplingSettings::default()),
+            tuning: Mutex::new(Tuning::default()),
+            mpe: Mutex::new(MpeSettings::default()),
+            programs: Mutex::new(Vec::new()),
+            program_values: (0..NUM_PROGRAMS)
+                .map(|_| ParameterStore::new(&[0.0; NUM_PARAMETERS as usize]))
+                .collect(),
+            current_program: AtomicUsize::new(0),
+            loaded_program: AtomicUsize::new(0),
+            morph_slots: Mutex::new([None, None]),
+            morph_slot_values: [(); NUM_MORPH_SLOTS]
+                .map(|_| ParameterStore::new(&[0.0; NUM_PARAMETERS as usize])),
+            morphing: AtomicBool::new(false),
+            locks: Mutex::new(LockMask::default()),
+            history: Mutex::new(History::default()),
+        };
+
+        // a new instance starts out on the first factory preset
+        let mut programs = factory::factory_bank();
+        programs.resize(NUM_PROGRAMS as usize, Patch::default());
+        state.apply_patch(&programs[0]);
+        state.store_programs(programs);
+        state
+    }
+
+    /// Replace a slot of the modulation matrix. Routes are part of the patch, but not exposed to
+    /// the host as parameters.
+    pub fn set_mod_route(&self, slot: usize, route: ModRoute) {
+        if slot >= NUM_MOD_SLOTS {
+            return;
         }
+        self.mod_routes.lock().unwrap()[slot] = route;
+
+        self.send(StateUpdate::SetModRoute(slot, route));
     }
-}
 
-/// The DAW directly accesses the plugin state through the VST API to get reports on knob states.
-impl PluginParameters for PluginState {
-    fn set_parameter(&self, index: i32, value: f32) {
-        let state_update = StateUpdate::SetKnob(index, value);
-        if self.editor_is_open.load(Ordering::Relaxed) {
-            self.to_editor.lock().unwrap().send(state_update.clone()).unwrap();
+    /// Change the order of the master effects. Orders that don't contain every effect exactly
+    /// once are rejected.
+    pub fn set_effect_order(&self, order: [EffectKind; NUM_EFFECTS]) -> bool {
+        if !is_valid_effect_order(&order) {
+            return false;
         }
-        self.to_dsp.lock().unwrap().send(state_update).unwrap();
-        self.state_record.lock().unwrap()[index as usize] = value;
+        *self.effect_order.lock().unwrap() = order;
+
+        self.send(StateUpdate::SetEffectOrder(order));
+        true
     }
 
-    fn get_parameter(&self, index: i32) -> f32 {
-        self.state_record.lock().unwrap()[index as usize]
+    /// Change the oversampling quality used for realtime playback and offline rendering.
+    pub fn set_oversampling(&self, settings: OversamplingSettings) {
+        *self.oversampling.lock().unwrap() = settings;
+
+        self.send(StateUpdate::SetOversampling(settings));
     }
 
-    fn get_parameter_label(&self, index: i32) -> String {
-        match index as usize {
-            PULSE_WIDTH_MOD_FREQ | PHASE_SHIFT_MOD_FREQ | PITCH_MOD_FREQ | FILTER_CUTOFF =>
-                "Hz".to_string(),
+    /// Switch MPE mode on or off and configure its zones. Sounding notes are released, since the
+    /// channels they were played on may change their role.
+    pub fn set_mpe(&self, settings: MpeSettings) {
+        let settings = settings.normalized();
+        *self.mpe.lock().unwrap() = settings;
+
+        self.send(StateUpdate::SetMpe(settings));
+    }
+
+    /// Whether `index` is a modulator rate or delay time that is currently synced to the host
+    /// tempo, and therefore displayed as a note division rather than in Hz or seconds.
+    fn is_tempo_synced(&self, index: usize) -> bool {
+        match PARAMETERS.get(index).and_then(|info| info.sync) {
+            Some(sync) => self.parameters.get(sync) >= 0.5,
+            None => false,
+        }
+    }
+
+    /// Replace the active tuning and hand its note frequencies over to the audio processing thread.
+    pub fn set_tuning(&self, tuning: Tuning) {
+        let table = Arc::new(tuning.table());
+        *self.tuning.lock().unwrap() = tuning;
+        self.send_to_dsp(StateUpdate::SetTuning(table));
+    }
+
+    /// Load a Scala scale file, keeping the current keyboard mapping.
+    pub fn load_scale(&self, path: &Path) -> Result<(), TuningError> {
+        let scale = Scale::from_file(path)?;
+        let keyboard = self.tuning.lock().unwrap().keyboard.clone();
+        self.set_tuning(Tuning::new(scale, keyboard)?);
+        Ok(())
+    }
+
+    /// Load a Scala keyboard mapping file, which also sets the reference pitch.
+    pub fn load_keyboard_mapping(&self, path: &Path) -> Result<(), TuningError> {
+        let keyboard = KeyboardMapping::from_file(path)?;
+        let scale = self.tuning.lock().unwrap().scale.clone();
+        self.set_tuning(Tuning::new(scale, keyboard)?);
+        Ok(())
+    }
 
-            AMP_SUSTAIN_LEVEL | FILTER_SUSTAIN_LEVEL | FILTER_DRIVE => "%".to_string(),
+    /// Set the frequency of the keyboard mapping's reference note in Hz.
+    pub fn set_reference_pitch(&self, freq: f64) {
+        let mut tuning = self.tuning.lock().unwrap().clone();
+        tuning.set_reference_pitch(freq);
+        self.set_tuning(tuning);
+    }
+
+    /// Inverse of `get_parameter_text`. Accepts the displayed text, optionally followed by the
+    /// parameter's label. Returns the normalized value, which may lie outside of 0 to 1 if the text
+    /// is out of range.
+    fn parse_parameter_text(&self, index: usize, text: &str) -> Option<f32> {
+        if self.is_tempo_synced(index) {
+            let names: Vec<&str> = NOTE_DIVISIONS.iter().map(|(name, _)| *name).collect();
+            return parse_label(text.trim(), &names);
+        }
+        PARAMETERS[index].parse(text)
+    }
 
-            AMP_ATTACK | AMP_DECAY | AMP_RELEASE | FILTER_ATTACK | FILTER_DECAY | FILTER_RELEASE =>
-                "s".to_string(),
+    /// Reset the patch to a plain sawtooth through the open filter: every parameter returns to its
+    /// default, and the modulation matrix, effect order, wavetable and tuning are reset as well.
+    /// MPE and oversampling belong to the setup rather than the sound, and are kept.
+    pub fn initialize_patch(&self) {
+        self.load_patch(&Patch::default());
+    }
 
-            _ => "".to_string(),
+    /// Snapshot of the current sound.
+    pub fn patch(&self) -> Patch {
+        Patch {
+            name: self.name.lock().unwrap().clone(),
+            metadata: self.metadata.lock().unwrap().clone(),
+            parameters: self.parameters.values(),
+            mod_routes: *self.mod_routes.lock().unwrap(),
+            effect_order: *self.effect_order.lock().unwrap(),
+            wavetable_path: self.wavetable_path.lock().unwrap().clone(),
+            tuning: self.tuning.lock().unwrap().clone(),
         }
     }
 
-    fn get_parameter_text(&self, index: i32) -> String {
-        match index as usize {
-            NOISE_COLOR => {
-                let value = self.state_record.lock().unwrap()[index as usize].round() as u8;
-                match value {
-                    0 => "white".to_string(),
-                    1 => "pink".to_string(),
-                    _ => "Invalid value".to_string(),
+    /// Replace the current sound, and let the host know that all parameters changed. A wavetable
+    /// that can't be loaded anymore is replaced by the built-in one.
+    pub fn load_patch(&self, patch: &Patch) {
+        self.apply_patch(patch);
+        if self.has_host() {
+            self.host.update_display();
+        }
+    }
+
+    fn apply_patch(&self, patch: &Patch) {
+        *self.name.lock().unwrap() = patch.name.clone();
+        *self.metadata.lock().unwrap() = patch.metadata.clone();
+        for (index, value) in patch.parameters.iter().enumerate() {
+            self.set_knob(index, *value);
+        }
+        for (slot, route) in patch.mod_routes.iter().enumerate() {
+            self.set_mod_route(slot, *route);
+        }
+        if !self.set_effect_order(patch.effect_order) {
+            self.set_effect_order(DEFAULT_EFFECT_ORDER);
+        }
+
+        let current_path = self.wavetable_path.lock().unwrap().clone();
+        if patch.wavetable_path != current_path {
+            let loaded = match &patch.wavetable_path {
+                Some(path) =>
+                    self.load_wavetable(path).map_err(|error| log::error!("{}", error)).is_ok(),
+                None => false,
+            };
+            if !loaded {
+                let wavetable = Wavetable::default_table();
+                self.send_to_dsp(StateUpdate::SetWavetable(wavetable));
+                *self.wavetable_path.lock().unwrap() = None;
+            }
+        }
+
+        // resending an unchanged tuning would undo any retuning received over MIDI
+        let tuning_changed = *self.tuning.lock().unwrap() != patch.tuning;
+        if tuning_changed {
+            self.set_tuning(patch.tuning.clone());
+        }
+    }
+
+    /// Store the current sound in its program slot and switch to another program. Out of range
+    /// program numbers are ignored.
+    pub fn change_program(&self, index: usize) {
+        self.request_program(index);
+        self.apply_pending_program();
+    }
+
+    /// Switch to another program from the audio thread. Loading a whole program locks, allocates
+    /// and may read a wavetable file, so only the parameter values are switched right away, which
+    /// neither locks nor allocates. The rest of the program follows on the next call of
+    /// `apply_pending_program` from another thread. Out of range program numbers are ignored.
+    pub fn request_program(&self, index: usize) {
+        let current = self.current_program.load(Ordering::Relaxed);
+        if index == current || index >= NUM_PROGRAMS as usize {
+            return;
+        }
+        let (stored, requested) = (&self.program_values[current], &self.program_values[index]);
+        for parameter in 0..NUM_PARAMETERS as usize {
+            stored.set(parameter, self.parameters.get(parameter));
+            self.parameters.set(parameter, requested.get(parameter));
+        }
+        self.current_program.store(index, Ordering::Release);
+    }
+
+    /// Load the rest of the program last switched to by `request_program`, storing the rest of
+    /// the one switched away from. Called from the editor's idle loop, when the host resumes
+    /// processing, and before the host shows or saves programs.
+    pub fn apply_pending_program(&self) {
+        let current = self.current_program.load(Ordering::Acquire);
+        let loaded = self.loaded_program.load(Ordering::Relaxed);
+        if current == loaded {
+            return;
+        }
+        let mut live = self.patch();
+        live.parameters = self.program_values[loaded].values();
+        let mut patch = {
+            let mut programs = self.programs.lock().unwrap();
+            programs[loaded] = live;
+            programs[current].clone()
+        };
+        // the parameters are already switched, and may have been automated since
+        patch.parameters = self.parameters.values();
+        self.loaded_program.store(current, Ordering::Relaxed);
+        self.history.lock().unwrap().clear();
+        self.load_patch(&patch);
+    }
+
+    /// Replace all stored programs, which must fill every program slot.
+    fn store_programs(&self, programs: Vec<Patch>) {
+        for (patch, values) in programs.iter().zip(self.program_values.iter()) {
+            for (index, value) in patch.parameters.iter().enumerate() {
+                values.set(index, *value);
+            }
+        }
+        *self.programs.lock().unwrap() = programs;
+    }
+
+    /// Replace the current sound with one of the `FACTORY_PRESETS`.
+    pub fn load_factory_preset(&self, index: usize) {
+        if let Some(preset) = factory::FACTORY_PRESETS.get(index) {
+            self.load_patch(&preset.patch());
+        }
+    }
+
+    /// All programs, with the live sound in the slot of the current one.
+    pub fn programs(&self) -> Vec<Patch> {
+        let mut programs = self.programs.lock().unwrap().clone();
+        programs[self.current_program.load(Ordering::Relaxed)] = self.patch();
+        programs
+    }
+
+    /// Replace the stored programs, filling any slots missing from `programs` with the init patch,
+    /// and load program `current`.
+    pub fn load_programs(&self, programs: Vec<Patch>, current: usize) {
+        let mut programs = programs;
+        programs.resize(NUM_PROGRAMS as usize, Patch::default());
+        let current = current.min(NUM_PROGRAMS as usize - 1);
+        let patch = programs[current].clone();
+        self.store_programs(programs);
+        self.current_program.store(current, Ordering::Relaxed);
+        self.loaded_program.store(current, Ordering::Relaxed);
+        self.history.lock().unwrap().clear();
+        self.load_patch(&patch);
+    }
+
+    /// Load a patch into one of the morph slots, or empty the slot. Once both slots are loaded,
+    /// the sound is blended between them at the position of the `MORPH` parameter.
+    pub fn set_morph_slot(&self, slot: usize, patch: Option<Patch>) {
+        if slot >= NUM_MORPH_SLOTS {
+            return;
+        }
+        let morph = {
+            let mut slots = self.morph_slots.lock().unwrap();
+            if let Some(patch) = &patch {
+                for (index, value) in patch.parameters.iter().enumerate() {
+                    self.morph_slot_values[slot].set(index, *value);
                 }
             }
+            slots[slot] = patch;
+            match &*slots {
+                [Some(a), Some(b)] => Some(Arc::new(Morph {
+                    a: a.parameters.clone(),
+                    b: b.parameters.clone(),
+                })),
+                _ => None,
+            }
+        };
+        self.morphing.store(morph.is_some(), Ordering::Release);
+
+        self.send(StateUpdate::SetMorph(morph));
+        self.apply_morph();
+    }
+
+    /// Load the current sound into one of the morph slots.
+    pub fn store_morph_slot(&self, slot: usize) {
+        self.set_morph_slot(slot, Some(self.patch()));
+    }
 
-            PHASE_SHIFT_MOD_SHAPE | PITCH_MOD_SHAPE | FILTER_CUTOFF_MOD_SHAPE => {
-                let value = (self.state_record.lock().unwrap()[index as usize] * 4.0).round() as u8;
-                match value {
-                    0 => "sine".to_string(),
-                    1 => "square".to_string(),
-                    2 => "triangle".to_string(),
-                    3 => "saw".to_string(),
-                    4 => "sample and hold".to_string(),
-                    _ => "Invalid value".to_string(),
+    /// Load one of the stored programs into one of the morph slots.
+    pub fn load_morph_slot(&self, slot: usize, program: usize) {
+        if let Some(patch) = self.programs().get(program) {
+            self.set_morph_slot(slot, Some(patch.clone()));
+        }
+    }
+
+    /// Names of the patches in the morph slots.
+    pub fn morph_slot_names(&self) -> [Option<String>; NUM_MORPH_SLOTS] {
+        let slots = self.morph_slots.lock().unwrap();
+        std::array::from_fn(|slot| slots[slot].as_ref().map(|patch| patch.name.clone()))
+    }
+
+    /// Blend every other parameter between the morph slots, at the position of the `MORPH`
+    /// parameter. Nothing changes unless both slots are loaded. Neither locks nor allocates, as
+    /// the host calls this when automating the morph parameter.
+    fn apply_morph(&self) {
+        if !self.morphing.load(Ordering::Acquire) {
+            return;
+        }
+        let position = self.parameters.get(MORPH);
+        let [a, b] = &self.morph_slot_values;
+        for index in (0..NUM_PARAMETERS as usize).filter(|&index| index != MORPH) {
+            let value = morph::blend(index, a.get(index), b.get(index), position);
+            if self.parameters.get(index) != value {
+                self.set_knob(index, value);
+            }
+        }
+    }
+
+    /// Set a parameter for the audio processing and the editor to pick up, without any of the
+    /// side effects of the host setting it.
+    fn set_knob(&self, index: usize, value: f32) {
+        self.parameters.set(index, value);
+    }
+
+    /// Pass an update on to the audio processing, and to the editor while it is open.
+    fn send(&self, state_update: StateUpdate) {
+        if self.editor_is_open.load(Ordering::Relaxed) {
+            self.to_editor.lock().unwrap().send(state_update.clone());
+        }
+        self.send_to_dsp(state_update);
+    }
+
+    fn send_to_dsp(&self, state_update: StateUpdate) {
+        self.free_retired();
+        self.to_dsp.lock().unwrap().send(state_update);
+    }
+
+    fn free_retired(&self) {
+        let mut retired = self.retired.lock().unwrap();
+        while retired.pop().is_some() {}
+    }
+
+    /// Retry sending the updates that were held back because a queue was full. The updates
+    /// otherwise wait for the next one to be sent.
+    pub fn flush_updates(&self) {
+        self.free_retired();
+        self.to_dsp.lock().unwrap().flush();
+        self.to_editor.lock().unwrap().flush();
+    }
+
+    /// Replace the unlocked parameters with random values, or nudge them for a variation. Without
+    /// a seed, a new one is picked. Returns the seed, with which the same result can be produced
+    /// again from the same patch.
+    pub fn randomize(&self, mode: Randomization, seed: Option<u64>) -> u64 {
+        let seed = seed.unwrap_or_else(rand::random);
+        let locks = self.locks.lock().unwrap().clone();
+        self.load_patch(&random::randomize(&self.patch(), mode, &locks, seed));
+        seed
+    }
+
+    pub fn locks(&self) -> LockMask {
+        self.locks.lock().unwrap().clone()
+    }
+
+    pub fn set_parameter_locked(&self, index: usize, locked: bool) {
+        self.locks.lock().unwrap().set_locked(index, locked);
+    }
+
+    pub fn set_section_locked(&self, section: Section, locked: bool) {
+        self.locks.lock().unwrap().set_section_locked(section, locked);
+    }
+
+    /// Apply a change made in the editor, and record the sound from before it so the change can be
+    /// undone. Changes that leave the sound as it was aren't recorded, and those made during a
+    /// gesture are recorded as one when it ends.
+    fn edit<T>(&self, change: impl FnOnce() -> T) -> T {
+        if self.history.lock().unwrap().in_gesture() {
+            return change();
+        }
+        let before = self.patch();
+        let result = change();
+        if self.patch() != before {
+            self.history.lock().unwrap().record(before);
+        }
+        result
+    }
+
+    /// Start a continuous edit of a parameter in the editor, like dragging a knob. All changes
+    /// until `end_gesture` are undone in a single step, and the host records them as automation.
+    pub fn begin_gesture(&self, index: usize) {
+        let before = self.patch();
+        self.history.lock().unwrap().begin_gesture(before);
+        self.host_opcode(HOST_BEGIN_EDIT, index as i32);
+    }
+
+    pub fn end_gesture(&self, index: usize) {
+        let current = self.patch();
+        self.history.lock().unwrap().end_gesture(&current);
+        self.host_opcode(HOST_END_EDIT, index as i32);
+    }
+
+    /// Whether the plugin runs inside a host. Calling into the host without one panics or
+    /// crashes, as it happens in tests.
+    fn has_host(&self) -> bool {
+        self.host.raw_callback().is_some()
+    }
+
+    /// Send an opcode that the `vst` crate doesn't wrap to the host, if there is one.
+    fn host_opcode(&self, opcode: i32, index: i32) {
+        if let Some(callback) = self.host.raw_callback() {
+            callback(self.host.raw_effect(), opcode, index, 0, std::ptr::null_mut(), 0.0);
+        }
+    }
+
+    /// Restore the sound from before the last change made in the editor. Returns `false` if there
+    /// is nothing left to undo.
+    pub fn undo(&self) -> bool {
+        let current = self.patch();
+        let previous = self.history.lock().unwrap().undo(current);
+        match previous {
+            Some(patch) => {
+                self.load_patch(&patch);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Restore the sound as it was before the last undo. Returns `false` if there is nothing to
+    /// redo.
+    pub fn redo(&self) -> bool {
+        let current = self.patch();
+        let next = self.history.lock().unwrap().redo(current);
+        match next {
+            Some(patch) => {
+                self.load_patch(&patch);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.history.lock().unwrap().can_undo()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        self.history.lock().unwrap().can_redo()
+    }
+
+    pub fn setup(&self) -> Setup {
+        Setup {
+            mpe: *self.mpe.lock().unwrap(),
+            oversampling: *self.oversampling.lock().unwrap(),
+            morph_slots: self.morph_slots.lock().unwrap().clone(),
+        }
+    }
+
+    /// Apply a stored setup. MPE settings are only resent when they change, as that releases
+    /// every sounding note.
+    pub fn load_setup(&self, setup: &Setup) {
+        let mpe_changed = *self.mpe.lock().unwrap() != setup.mpe.normalized();
+        if mpe_changed {
+            self.set_mpe(setup.mpe);
+        }
+        self.set_oversampling(setup.oversampling);
+        for (slot, patch) in setup.morph_slots.iter().enumerate() {
+            let slot_changed = self.morph_slots.lock().unwrap()[slot] != *patch;
+            if slot_changed {
+                self.set_morph_slot(slot, patch.clone());
+            }
+        }
+    }
+
+    /// Load a `.fxp` program file into the current program, or a `.fxb` bank file into the
+    /// program bank.
+    pub fn load_fx_file(&self, path: &Path) -> Result<(), PresetError> {
+        let data = std::fs::read(path)?;
+        match fx::kind(&data)? {
+            FxKind::Program => {
+                let (patch, setup) = fx::read_fxp(&data, &self.patch())?;
+                if let Some(setup) = setup {
+                    self.load_setup(&setup);
+                }
+                self.load_patch(&patch);
+            }
+            FxKind::Bank => {
+                let (programs, current, setup) = fx::read_fxb(&data, &self.programs())?;
+                if let Some(setup) = setup {
+                    self.load_setup(&setup);
                 }
+                self.load_programs(programs, current);
+            }
+        }
+        Ok(())
+    }
+
+    /// Save the current program as a `.fxp` file, or the whole bank as a `.fxb` file.
+    pub fn save_fx_file(
+        &self,
+        path: &Path,
+        kind: FxKind,
+        format: FxFormat
+    ) -> Result<(), PresetError> {
+        let data = match kind {
+            FxKind::Program => fx::write_fxp(&self.patch(), &self.setup(), format),
+            FxKind::Bank => {
+                let current = self.current_program.load(Ordering::Relaxed);
+                fx::write_fxb(&self.programs(), current, &self.setup(), format)
             }
+        };
+        std::fs::write(path, data)?;
+        Ok(())
+    }
 
-            FILTER_ATTACK | AMP_ATTACK =>
-                format!(
-                    "{:.2}",
-                    f32::abs(
-                        self.state_record.lock().unwrap()[index as usize] * MAX_ENV_ATTACK_TIME
-                    )
-                ),
+    /// Describe the current sound. The metadata is saved with the patch, but has no effect on the
+    /// sound.
+    pub fn set_metadata(&self, metadata: Metadata) {
+        *self.metadata.lock().unwrap() = metadata;
+    }
 
-            FILTER_DECAY | AMP_DECAY =>
-                format!(
-                    "{:.2}",
-                    f32::abs(self.state_record.lock().unwrap()[index as usize] * MAX_ENV_DECAY_TIME)
-                ),
+    /// Load a human-readable patch file into the current program.
+    pub fn load_text_patch(&self, path: &Path) -> Result<(), PresetError> {
+        let patch = text::read_patch(&std::fs::read_to_string(path)?)?;
+        self.load_patch(&patch);
+        Ok(())
+    }
 
-            FILTER_SUSTAIN_LEVEL | AMP_SUSTAIN_LEVEL =>
-                format!("{:.2}", f32::abs(self.state_record.lock().unwrap()[index as usize])),
+    /// Save the current sound as a human-readable patch file.
+    pub fn save_text_patch(&self, path: &Path) -> Result<(), PresetError> {
+        std::fs::write(path, text::write_patch(&self.patch()))?;
+        Ok(())
+    }
 
-            FILTER_RELEASE | AMP_RELEASE =>
-                format!(
-                    "{:.2}",
-                    f32::abs(
-                        self.state_record.lock().unwrap()[index as usize] * MAX_ENV_RELEASE_TIME
-                    )
-                ),
+    /// Load a wavetable from a WAV file and hand it over to the audio processing thread. The table
+    /// is decoded and band-limited here, so this should never be called from the audio thread.
+    pub fn load_wavetable(&self, path: &Path) -> Result<(), WavetableError> {
+        let wavetable = Arc::new(Wavetable::from_wav_file(path)?);
+        self.send_to_dsp(StateUpdate::SetWavetable(wavetable));
+        *self.wavetable_path.lock().unwrap() = Some(path.to_path_buf());
+        Ok(())
+    }
+}
 
-            _ => format!("{:.1}", self.state_record.lock().unwrap()[index as usize] * 100.0),
+/// The DAW directly accesses the plugin state through the VST API to get reports on knob states.
+impl PluginParameters for PluginState {
+    /// Setting the morph parameter blends all others between the morph slots.
+    fn set_parameter(&self, index: i32, value: f32) {
+        self.set_knob(index as usize, value);
+        if index as usize == MORPH {
+            self.apply_morph();
         }
     }
 
-    fn get_parameter_name(&self, index: i32) -> String {
-        (
-            match index as usize {
-                NOISE_AMP => "Noise",
-                NOISE_COLOR => "Noise Color",
+    fn get_parameter(&self, index: i32) -> f32 {
+        self.parameters.get(index as usize)
+    }
 
-                SINE_AMP => "Sine",
-                SINE_OCTAVE => "SineOctave",
+    fn get_parameter_label(&self, index: i32) -> String {
+        if self.is_tempo_synced(index as usize) {
+            return "".to_string();
+        }
+        PARAMETERS.get(index as usize).map_or("", |info| info.unit).to_string()
+    }
 
-                PULSE_AMP => "Pulse",
-                PULSE_WIDTH => "Pulse width",
-                PULSE_WIDTH_MOD_AMP => "Pulse width modulation amplitude",
-                PULSE_WIDTH_MOD_FREQ => "Pulse width modulation frequency",
+    fn get_parameter_text(&self, index: i32) -> String {
+        let value = self.parameters.get(index as usize);
+        if self.is_tempo_synced(index as usize) {
+            return NOTE_DIVISIONS[note_division(value)].0.to_string();
+        }
+        PARAMETERS[index as usize].format(value)
+    }
 
-                SAWTOOTH_AMP => "Sawtooth",
-                SAWTOOTH_SHAPE => "Sawtooth width",
+    fn get_parameter_name(&self, index: i32) -> String {
+        PARAMETERS.get(index as usize).map_or("Unknown", |info| info.name).to_string()
+    }
 
-                PHASE_SHIFT_AMOUNT => "Channel phase shift amount",
-                PHASE_SHIFT_MOD_FREQ => "Phase shift modulation frequency",
-                PHASE_SHIFT_MOD_SHAPE => "Phase shift modulation wave form",
+    fn change_preset(&self, preset: i32) {
+        if preset >= 0 {
+            self.change_program(preset as usize);
+        }
+    }
 
-                PITCH_MOD_SHAPE => "Pitch modulation waveform",
-                PITCH_MOD_AMP => "Pitch modulation amplitude",
-                PITCH_MOD_FREQ => "Pitch modulation frequency",
+    fn get_preset_num(&self) -> i32 {
+        self.apply_pending_program();
+        self.current_program.load(Ordering::Relaxed) as i32
+    }
 
-                AMP_ATTACK => "Attack",
-                AMP_DECAY => "Decay",
-                AMP_SUSTAIN_LEVEL => "Sustain",
-                AMP_RELEASE => "Release",
+    fn set_preset_name(&self, name: String) {
+        *self.name.lock().unwrap() = name;
+    }
 
-                FILTER_ATTACK => "Filter Attack",
-                FILTER_DECAY => "Filter Decay",
-                FILTER_SUSTAIN_LEVEL => "Fiter Sustain",
-                FILTER_RELEASE => "Filter Release",
+    fn get_preset_name(&self, preset: i32) -> String {
+        self.apply_pending_program();
+        if preset as usize == self.current_program.load(Ordering::Relaxed) {
+            return self.name.lock().unwrap().clone();
+        }
+        match self.programs.lock().unwrap().get(preset as usize) {
+            Some(patch) => patch.name.clone(),
+            None => "".to_string(),
+        }
+    }
 
-                FILTER_CUTOFF => "Cutoff",
-                FILTER_RESONANCE => "Resonance",
-                FILTER_POLES => "Poles",
-                FILTER_DRIVE => "Drive",
+    fn get_preset_data(&self) -> Vec<u8> {
+        self.apply_pending_program();
+        chunk::write_preset(&self.patch(), &self.setup())
+    }
 
-                FILTER_CUTOFF_MOD_SHAPE => "Cutoff modulation waveform",
-                FILTER_CUTOFF_MOD_AMP => "Cutoff modulation amplitude",
-                FILTER_CUTOFF_MOD_FREQ => "Cutoff modulation frequency",
+    fn get_bank_data(&self) -> Vec<u8> {
+        self.apply_pending_program();
+        let current = self.current_program.load(Ordering::Relaxed);
+        chunk::write_bank(&self.programs(), current, &self.setup())
+    }
 
-                _ => "Unknown",
+    /// Corrupt chunks, and chunks written by newer versions of the plugin, leave the current state
+    /// untouched.
+    fn load_preset_data(&self, data: &[u8]) {
+        match chunk::read_preset(data) {
+            Ok((patch, setup)) => {
+                self.load_setup(&setup);
+                self.load_patch(&patch);
             }
-        ).to_string()
+            Err(error) => log::error!("Failed to load preset: {}", error),
+        }
+    }
+
+    fn load_bank_data(&self, data: &[u8]) {
+        match chunk::read_bank(data) {
+            Ok((programs, current, setup)) => {
+                self.load_setup(&setup);
+                self.load_programs(programs, current);
+            }
+            Err(error) => log::error!("Failed to load bank: {}", error),
+        }
     }
 
     fn string_to_parameter(&self, index: i32, text: String) -> bool {
-        dbg!("Set string to parameter for {}, {}", index, &text);
-        match index {
-            0 =>
-                match text.parse::<f32>() {
-                    Ok(value) if value <= 1.0 && value >= 0.0 => {
-                        self.set_parameter(index, value);
-                        true
-                    }
-                    _ => false,
-                }
-            _ => unreachable!(),
+        if !(0..NUM_PARAMETERS).contains(&index) {
+            return false;
+        }
+        match self.parse_parameter_text(index as usize, &text) {
+            Some(value) if (0.0..=1.0).contains(&value) => {
+                self.set_parameter(index, value);
+                true
+            }
+            _ => false,
         }
     }
 }
@@ -199,14 +856,569 @@ impl PluginParameters for PluginState {
 /// The editor interface also directly accesses the plugin state through its own API.
 impl crate::editor::EditorRemoteState for PluginState {
     fn set_amplitude_control(&self, value: f32) {
-        self.state_record.lock().unwrap()[0] = value;
-
-        self.to_dsp.lock().unwrap().send(StateUpdate::SetKnob(0, value)).unwrap();
-
-        self.host.automate(0, value);
+        self.edit(|| {
+            self.set_knob(NOISE_AMP, value);
+            if self.has_host() {
+                self.host.automate(NOISE_AMP as i32, value);
+            }
+        });
     }
 
     fn set_event_subscription(&self, enabled: bool) {
         self.editor_is_open.store(enabled, Ordering::Relaxed);
     }
-}
\ No newline at end of file
+
+    fn set_mod_route(&self, slot: usize, route: ModRoute) {
+        self.edit(|| PluginState::set_mod_route(self, slot, route));
+    }
+
+    fn set_effect_order(&self, order: [EffectKind; NUM_EFFECTS]) -> bool {
+        self.edit(|| PluginState::set_effect_order(self, order))
+    }
+
+    fn set_oversampling(&self, settings: OversamplingSettings) {
+        PluginState::set_oversampling(self, settings);
+    }
+
+    fn set_mpe(&self, settings: MpeSettings) {
+        PluginState::set_mpe(self, settings);
+    }
+
+    fn load_wavetable(&self, path: &Path) -> bool {
+        self.edit(|| match PluginState::load_wavetable(self, path) {
+            Ok(()) => true,
+            Err(error) => {
+                log::error!("{}", error);
+                false
+            }
+        })
+    }
+
+    fn load_scale(&self, path: &Path) -> bool {
+        self.edit(|| match PluginState::load_scale(self, path) {
+            Ok(()) => true,
+            Err(error) => {
+                log::error!("{}", error);
+                false
+            }
+        })
+    }
+
+    fn load_keyboard_mapping(&self, path: &Path) -> bool {
+        self.edit(|| match PluginState::load_keyboard_mapping(self, path) {
+            Ok(()) => true,
+            Err(error) => {
+                log::error!("{}", error);
+                false
+            }
+        })
+    }
+
+    fn set_reference_pitch(&self, freq: f64) {
+        self.edit(|| PluginState::set_reference_pitch(self, freq));
+    }
+
+    fn reset_tuning(&self) {
+        self.edit(|| self.set_tuning(Tuning::default()));
+    }
+
+    fn initialize_patch(&self) {
+        self.edit(|| PluginState::initialize_patch(self));
+    }
+
+    fn change_program(&self, index: usize) {
+        PluginState::change_program(self, index);
+    }
+
+    fn load_factory_preset(&self, index: usize) {
+        self.edit(|| PluginState::load_factory_preset(self, index));
+    }
+
+    fn load_fx_file(&self, path: &Path) -> bool {
+        self.edit(|| match PluginState::load_fx_file(self, path) {
+            Ok(()) => true,
+            Err(error) => {
+                log::error!("{}", error);
+                false
+            }
+        })
+    }
+
+    fn save_fx_file(&self, path: &Path, kind: FxKind, format: FxFormat) -> bool {
+        match PluginState::save_fx_file(self, path, kind, format) {
+            Ok(()) => true,
+            Err(error) => {
+                log::error!("{}", error);
+                false
+            }
+        }
+    }
+
+    fn set_metadata(&self, metadata: Metadata) {
+        self.edit(|| PluginState::set_metadata(self, metadata));
+    }
+
+    fn load_text_patch(&self, path: &Path) -> bool {
+        self.edit(|| match PluginState::load_text_patch(self, path) {
+            Ok(()) => true,
+            Err(error) => {
+                log::error!("{}", error);
+                false
+            }
+        })
+    }
+
+    fn save_text_patch(&self, path: &Path) -> bool {
+        match PluginState::save_text_patch(self, path) {
+            Ok(()) => true,
+            Err(error) => {
+                log::error!("{}", error);
+                false
+            }
+        }
+    }
+
+    fn store_morph_slot(&self, slot: usize) {
+        self.edit(|| PluginState::store_morph_slot(self, slot));
+    }
+
+    fn load_morph_slot(&self, slot: usize, program: usize) {
+        self.edit(|| PluginState::load_morph_slot(self, slot, program));
+    }
+
+    fn clear_morph_slot(&self, slot: usize) {
+        self.edit(|| self.set_morph_slot(slot, None));
+    }
+
+    fn morph_slot_names(&self) -> [Option<String>; NUM_MORPH_SLOTS] {
+        PluginState::morph_slot_names(self)
+    }
+
+    fn randomize(&self, mode: Randomization, seed: Option<u64>) -> u64 {
+        self.edit(|| PluginState::randomize(self, mode, seed))
+    }
+
+    fn locks(&self) -> LockMask {
+        PluginState::locks(self)
+    }
+
+    fn set_parameter_locked(&self, index: usize, locked: bool) {
+        PluginState::set_parameter_locked(self, index, locked);
+    }
+
+    fn set_section_locked(&self, section: Section, locked: bool) {
+        PluginState::set_section_locked(self, section, locked);
+    }
+
+    fn begin_gesture(&self, index: usize) {
+        PluginState::begin_gesture(self, index);
+    }
+
+    fn end_gesture(&self, index: usize) {
+        PluginState::end_gesture(self, index);
+    }
+
+    fn undo(&self) -> bool {
+        PluginState::undo(self)
+    }
+
+    fn redo(&self) -> bool {
+        PluginState::redo(self)
+    }
+
+    fn can_undo(&self) -> bool {
+        PluginState::can_undo(self)
+    }
+
+    fn can_redo(&self) -> bool {
+        PluginState::can_redo(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use crate::parameter_store::Reader;
+    use crate::parameters::default_values;
+    use crate::spsc;
+
+    fn test_state() -> (PluginState, Consumer<StateUpdate>, Consumer<StateUpdate>) {
+        let parameters = Arc::new(ParameterStore::new(&default_values()));
+        let (to_dsp, dsp_recv) = spsc::channel(EVENT_QUEUE_CAPACITY);
+        let (to_editor, editor_recv) = spsc::channel(EVENT_QUEUE_CAPACITY);
+        let (_, retired) = spsc::channel(RETIRED_QUEUE_CAPACITY);
+        let host = HostCallback::default();
+        let state = PluginState::new(host, parameters, to_dsp, to_editor, retired);
+        (state, dsp_recv, editor_recv)
+    }
+
+    /// Every displayed value must parse back to a value that is displayed the same way.
+    fn assert_round_trips(state: &PluginState, index: i32) {
+        for step in 0..=40 {
+            state.set_parameter(index, (step as f32) / 40.0);
+            let text = state.get_parameter_text(index);
+            let label = state.get_parameter_label(index);
+
+            for input in [text.clone(), format!("{} {}", text, label), format!("{}{}", text, label)]
+            {
+                assert!(
+                    state.string_to_parameter(index, input.clone()),
+                    "parameter {} rejected {:?}",
+                    index,
+                    input
+                );
+                assert_eq!(state.get_parameter_text(index), text, "parameter {}", index);
+            }
+        }
+    }
+
+    #[test]
+    fn every_parameter_round_trips() {
+        let (state, _dsp_recv, _editor_recv) = test_state();
+        for index in 0..NUM_PARAMETERS {
+            assert_round_trips(&state, index);
+        }
+    }
+
+    #[test]
+    fn synced_rates_round_trip() {
+        let (state, _dsp_recv, _editor_recv) = test_state();
+        for sync in [PULSE_WIDTH_MOD_SYNC, PHASE_SHIFT_MOD_SYNC, PITCH_MOD_SYNC, DELAY_SYNC] {
+            state.set_parameter(sync as i32, 1.0);
+        }
+        state.set_parameter(FILTER_CUTOFF_MOD_SYNC as i32, 1.0);
+
+        for rate in [PULSE_WIDTH_MOD_FREQ, PHASE_SHIFT_MOD_FREQ, PITCH_MOD_FREQ, DELAY_TIME] {
+            assert_round_trips(&state, rate as i32);
+        }
+        assert!(state.string_to_parameter(PITCH_MOD_FREQ as i32, "1/8T".to_string()));
+        assert_eq!(state.get_parameter_text(PITCH_MOD_FREQ as i32), "1/8T");
+    }
+
+    #[test]
+    fn parses_displayed_units() {
+        let (state, _dsp_recv, _editor_recv) = test_state();
+
+        assert!(state.string_to_parameter(AMP_DECAY as i32, "1.5 s".to_string()));
+        assert_eq!(state.get_parameter_text(AMP_DECAY as i32), "1.500");
+
+        assert!(state.string_to_parameter(FILTER_CUTOFF as i32, "1000 Hz".to_string()));
+        assert_eq!(state.get_parameter_text(FILTER_CUTOFF as i32), "1000");
+
+        assert!(state.string_to_parameter(PITCH_MOD_FREQ as i32, "0.01".to_string()));
+        assert_eq!(state.get_parameter(PITCH_MOD_FREQ as i32), 0.0);
+
+        assert!(state.string_to_parameter(NOISE_COLOR as i32, "Pink".to_string()));
+        assert_eq!(state.get_parameter(NOISE_COLOR as i32), 1.0);
+
+        assert!(state.string_to_parameter(PITCH_MOD_SHAPE as i32, "triangle".to_string()));
+        assert_eq!(state.get_parameter_text(PITCH_MOD_SHAPE as i32), "triangle");
+
+        assert!(state.string_to_parameter(SINE_PAN as i32, "L50".to_string()));
+        assert_eq!(state.get_parameter(SINE_PAN as i32), 0.25);
+
+        assert!(state.string_to_parameter(NOISE_AMP as i32, "75%".to_string()));
+        assert_eq!(state.get_parameter(NOISE_AMP as i32), 0.75);
+    }
+
+    #[test]
+    fn rejects_invalid_text() {
+        let (state, _dsp_recv, _editor_recv) = test_state();
+        state.set_parameter(AMP_DECAY as i32, 0.5);
+
+        for (index, text) in [
+            (AMP_DECAY, "3 s"),
+            (AMP_DECAY, "-0.1"),
+            (NOISE_AMP, "101"),
+            (NOISE_AMP, "NaN"),
+            (NOISE_AMP, "loud"),
+            (NOISE_COLOR, "brown"),
+            (SINE_PAN, "L101"),
+            (CRUSH_BITS, "17"),
+            (CRUSH_BITS, "0"),
+        ] {
+            assert!(!state.string_to_parameter(index as i32, text.to_string()), "{:?}", text);
+        }
+        assert!(!state.string_to_parameter(NUM_PARAMETERS, "0".to_string()));
+        assert!(!state.string_to_parameter(-1, "0".to_string()));
+        assert_eq!(state.get_parameter(AMP_DECAY as i32), 0.5);
+    }
+
+    /// Program changes from the audio thread switch the parameters at once, while the rest of the
+    /// program waits for another thread.
+    #[test]
+    fn switches_requested_programs_in_two_steps() {
+        let (state, _dsp_recv, _editor_recv) = test_state();
+        state.set_parameter(FILTER_CUTOFF as i32, 0.125);
+        let programs = state.programs();
+        state.request_program(3);
+        state.request_program(2);
+        assert_eq!(state.current_program.load(Ordering::Relaxed), 2);
+        assert_eq!(state.parameters.values(), programs[2].parameters);
+        assert_eq!(*state.name.lock().unwrap(), programs[0].name);
+
+        assert_eq!(state.get_preset_num(), 2);
+        assert_eq!(state.patch(), programs[2]);
+        assert_eq!(state.programs(), programs);
+        state.apply_pending_program();
+        assert_eq!(state.get_preset_num(), 2);
+
+        state.request_program(NUM_PROGRAMS as usize);
+        assert_eq!(state.get_preset_num(), 2);
+    }
+
+    #[test]
+    fn morphs_between_slots() {
+        let (state, _dsp_recv, _editor_recv) = test_state();
+        let (a, b) = (state.programs()[0].clone(), state.programs()[1].clone());
+        state.load_morph_slot(0, 0);
+        state.set_parameter(MORPH as i32, 0.25);
+        // a single slot doesn't morph
+        assert_eq!(state.patch().parameters[..MORPH], a.parameters[..MORPH]);
+
+        state.load_morph_slot(1, 1);
+        assert_ne!(a.parameters[SAWTOOTH_AMP], b.parameters[SAWTOOTH_AMP]);
+        assert_ne!(a.parameters[SUB_SHAPE], b.parameters[SUB_SHAPE]);
+        assert_eq!(state.morph_slot_names(), [Some(a.name.clone()), Some(b.name.clone())]);
+
+        let blend = |index: usize, position: f32| {
+            a.parameters[index] + (b.parameters[index] - a.parameters[index]) * position
+        };
+        for &position in [0.25, 0.75, 1.0].iter() {
+            state.set_parameter(MORPH as i32, position);
+            let value = state.get_parameter(SAWTOOTH_AMP as i32);
+            assert!((value - blend(SAWTOOTH_AMP, position)).abs() < 1e-6);
+            let stepped = if position < 0.5 { &a } else { &b };
+            assert_eq!(
+                state.get_parameter(SUB_SHAPE as i32),
+                stepped.parameters[SUB_SHAPE]
+            );
+            assert_eq!(state.get_parameter(MORPH as i32), position);
+        }
+
+        // loading a patch replaces the sound without morphing it
+        state.load_patch(&Patch::default());
+        assert_eq!(state.patch().parameters, Patch::default().parameters);
+    }
+
+    /// Reloading a patch keeps the tuning the audio thread may have received over MIDI, unless the
+    /// patch brings a different one.
+    #[test]
+    fn only_resends_changed_tunings() {
+        let (state, mut dsp_recv, _editor_recv) = test_state();
+        let mut sent_tuning = || {
+            state.flush_updates();
+            let mut sent = false;
+            while let Some(update) = dsp_recv.pop() {
+                sent |= matches!(update, StateUpdate::SetTuning(_));
+            }
+            sent
+        };
+        sent_tuning();
+
+        let mut patch = state.patch();
+        state.load_patch(&patch);
+        assert!(!sent_tuning());
+
+        patch.tuning.set_reference_pitch(432.0);
+        state.load_patch(&patch);
+        assert!(sent_tuning());
+        assert_eq!(state.patch().tuning, patch.tuning);
+    }
+
+    /// Loading presets with the setup already in place leaves sounding notes alone.
+    #[test]
+    fn only_resends_changed_mpe_settings() {
+        let (state, mut dsp_recv, _editor_recv) = test_state();
+        let mut sent_mpe = || {
+            state.flush_updates();
+            let mut sent = false;
+            while let Some(update) = dsp_recv.pop() {
+                sent |= matches!(update, StateUpdate::SetMpe(_));
+            }
+            sent
+        };
+        sent_mpe();
+
+        let data = state.get_preset_data();
+        state.load_preset_data(&data);
+        assert!(!sent_mpe());
+
+        let mut setup = state.setup();
+        setup.mpe.enabled = !setup.mpe.enabled;
+        state.load_setup(&setup);
+        assert!(sent_mpe());
+        assert_eq!(state.setup().mpe, setup.mpe.normalized());
+    }
+
+    /// The morph slots come back with the project, so the morph parameter keeps working.
+    #[test]
+    fn restores_morph_slots_from_chunks() {
+        let (state, _dsp_recv, _editor_recv) = test_state();
+        let (a, b) = (state.programs()[0].clone(), state.programs()[1].clone());
+        state.load_morph_slot(0, 0);
+        state.load_morph_slot(1, 1);
+
+        for &bank in [false, true].iter() {
+            let (restored, mut dsp_recv, _editor_recv) = test_state();
+            if bank {
+                restored.load_bank_data(&state.get_bank_data());
+            } else {
+                restored.load_preset_data(&state.get_preset_data());
+            }
+            assert_eq!(restored.morph_slot_names(), [Some(a.name.clone()), Some(b.name.clone())]);
+
+            restored.set_parameter(MORPH as i32, 1.0);
+            let value = restored.get_parameter(SAWTOOTH_AMP as i32);
+            assert!((value - b.parameters[SAWTOOTH_AMP]).abs() < 1e-6);
+            restored.flush_updates();
+            let mut morph = None;
+            while let Some(update) = dsp_recv.pop() {
+                if let StateUpdate::SetMorph(update) = update {
+                    morph = update;
+                }
+            }
+            assert_eq!(morph.map(|morph| morph.b.clone()), Some(b.parameters.clone()));
+        }
+    }
+
+    #[test]
+    fn undoes_editor_changes() {
+        use crate::editor::EditorRemoteState;
+
+        let (state, _dsp_recv, _editor_recv) = test_state();
+        // host automation isn't recorded
+        state.set_parameter(SINE_AMP as i32, 0.9);
+        assert!(!state.can_undo());
+        let automated = state.patch();
+
+        // a drag is undone in a single step, a click without change not at all
+        state.begin_gesture(NOISE_AMP);
+        state.end_gesture(NOISE_AMP);
+        assert!(!state.can_undo());
+        state.begin_gesture(NOISE_AMP);
+        for step in 1..=10 {
+            state.set_amplitude_control(step as f32 / 10.0);
+        }
+        state.end_gesture(NOISE_AMP);
+        let dragged = state.patch();
+
+        EditorRemoteState::load_factory_preset(&state, 5);
+        let seed = EditorRemoteState::randomize(&state, Randomization::Full, None);
+        let randomized = state.patch();
+        assert_eq!(randomized.name, format!("Random {}", seed));
+
+        assert!(state.undo());
+        assert_eq!(state.patch(), factory::FACTORY_PRESETS[5].patch());
+        assert!(state.undo());
+        assert_eq!(state.patch(), dragged);
+        assert!(state.undo());
+        assert_eq!(state.patch(), automated);
+        assert!(!state.undo());
+
+        assert!(state.redo());
+        assert!(state.redo());
+        assert!(state.redo());
+        assert_eq!(state.patch(), randomized);
+        assert!(!state.redo());
+
+        // a new change drops what could be redone
+        assert!(state.undo());
+        EditorRemoteState::initialize_patch(&state);
+        assert!(!state.can_redo());
+
+        // another program starts a new history
+        state.change_program(1);
+        assert!(!state.can_undo());
+    }
+
+    /// Updates that don't fit into a full queue are held back until there is room again, keeping
+    /// only the latest one for each piece of state.
+    #[test]
+    fn holds_back_updates_while_the_queue_is_full() {
+        let (state, mut dsp_recv, _editor_recv) = test_state();
+        while dsp_recv.pop().is_some() {}
+        for round in 0..EVENT_QUEUE_CAPACITY {
+            state.set_mod_route(round % NUM_MOD_SLOTS, ModRoute::default());
+        }
+        for _ in 0..3 {
+            state.load_patch(&factory::FACTORY_PRESETS[2].patch());
+        }
+
+        let mut routes = [ModRoute::default(); NUM_MOD_SLOTS];
+        let mut receive = || {
+            let mut received = 0;
+            while let Some(state_update) = dsp_recv.pop() {
+                if let StateUpdate::SetModRoute(slot, route) = state_update {
+                    routes[slot] = route;
+                }
+                received += 1;
+            }
+            received
+        };
+        assert_eq!(receive(), EVENT_QUEUE_CAPACITY);
+        state.flush_updates();
+        assert!(receive() <= NUM_MOD_SLOTS + 2);
+        assert_eq!(routes, state.patch().mod_routes);
+    }
+
+    /// The host automates parameters from several threads while the editor loads presets and an
+    /// audio thread polls for changes. Nothing may panic, even though the editor's end of its queue
+    /// is gone, and the audio thread must end up with the final values.
+    #[test]
+    fn survives_concurrent_automation() {
+        use crate::editor::EditorRemoteState;
+
+        let (state, mut dsp_recv, editor_recv) = test_state();
+        drop(editor_recv);
+        state.set_event_subscription(true);
+        let state = Arc::new(state);
+        let done = Arc::new(AtomicBool::new(false));
+
+        let audio = {
+            let (state, done) = (Arc::clone(&state), Arc::clone(&done));
+            thread::spawn(move || {
+                let mut parameter = vec![0.0; NUM_PARAMETERS as usize];
+                let mut poll = |parameter: &mut Vec<f32>| {
+                    state.parameters.take_changes(Reader::Dsp, |index, value| {
+                        parameter[index] = value;
+                    });
+                    while dsp_recv.pop().is_some() {}
+                };
+                while !done.load(Ordering::Acquire) {
+                    poll(&mut parameter);
+                }
+                poll(&mut parameter);
+                parameter
+            })
+        };
+
+        let mut writers: Vec<_> = (0..3)
+            .map(|host| {
+                let state = Arc::clone(&state);
+                thread::spawn(move || {
+                    for step in 0..5000 {
+                        let index = (host + step * 3) % NUM_PARAMETERS as usize;
+                        state.set_parameter(index as i32, (step % 100) as f32 / 100.0);
+                    }
+                })
+            })
+            .collect();
+        writers.push({
+            let state = Arc::clone(&state);
+            thread::spawn(move || {
+                for preset in 0..100 {
+                    let preset = preset % factory::FACTORY_PRESETS.len();
+                    EditorRemoteState::load_factory_preset(&*state, preset);
+                }
+            })
+        });
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        done.store(true, Ordering::Release);
+
+        assert_eq!(audio.join().unwrap(), state.patch().parameters);
+    }
+}
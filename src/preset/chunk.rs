@@ -0,0 +1,581 @@
+//! Binary chunk format in which hosts store the plugin state with their projects.
+//!
+//! A chunk starts with a four byte magic number and a format version, followed by a sequence of
+//! tagged sections. Every section starts with a four byte tag and the length of its payload, so
+//! readers skip sections they don't know, and sections missing from older chunks fall back to
+//! their defaults. All numbers are little-endian.
+//!
+//! Parameter values are stored under their stable identifier along with the layout version, and
+//! modulation routes name their destination by identifier, too. Version 1 chunks stored both by
+//! index, in the layout of version 2.
+//!
+//! A preset chunk holds a single patch along with the setup. A bank chunk holds the setup and the
+//! number of the current program, followed by one `PROG` section per program, each containing a
+//! complete preset chunk. Loaded morph slots are stored with the setup in a `MRPH` section, as a
+//! preset chunk without setup per slot, but not repeated in the programs of a bank.
+
+use std::{ convert::TryInto, path::PathBuf };
+
+use super::{ migration::{ self, LAYOUT_VERSION }, Metadata, Patch, PresetError, Setup };
+use crate::dsp::{
+    effects::EffectKind,
+    modulation::{ ModRoute, ModSource },
+    mpe::MpeSettings,
+    oversampling::{ Oversampling, OversamplingSettings },
+};
+use crate::parameters::PARAMETERS;
+use crate::tuning::{ scala::{ KeyboardMapping, Scale }, Tuning, TuningError };
+
+const PRESET_MAGIC: &[u8; 4] = b"MElP";
+const BANK_MAGIC: &[u8; 4] = b"MElB";
+pub const CHUNK_VERSION: u16 = 2;
+
+const NAME_TAG: &[u8; 4] = b"NAME";
+const METADATA_TAG: &[u8; 4] = b"META";
+const PARAMETERS_TAG: &[u8; 4] = b"PARM";
+const MOD_ROUTES_TAG: &[u8; 4] = b"MODR";
+const EFFECT_ORDER_TAG: &[u8; 4] = b"FXOR";
+const WAVETABLE_TAG: &[u8; 4] = b"WAVE";
+const SCALE_TAG: &[u8; 4] = b"SCAL";
+const KEYBOARD_TAG: &[u8; 4] = b"KBMP";
+const MPE_TAG: &[u8; 4] = b"MPE ";
+const OVERSAMPLING_TAG: &[u8; 4] = b"OVSM";
+const MORPH_TAG: &[u8; 4] = b"MRPH";
+const PROGRAM_TAG: &[u8; 4] = b"PROG";
+const CURRENT_PROGRAM_TAG: &[u8; 4] = b"CURR";
+
+pub fn write_preset(patch: &Patch, setup: &Setup) -> Vec<u8> {
+    let mut writer = Writer::new(PRESET_MAGIC);
+    write_patch(&mut writer, patch);
+    write_setup(&mut writer, setup);
+    writer.data
+}
+
+pub fn read_preset(data: &[u8]) -> Result<(Patch, Setup), PresetError> {
+    let mut patch = Patch::default();
+    let mut setup = Setup::default();
+    let (version, sections) = sections(data, PRESET_MAGIC)?;
+    for (tag, mut reader) in sections {
+        if !read_patch_section(version, &tag, &mut reader, &mut patch)? {
+            read_setup_section(&tag, &mut reader, &mut setup)?;
+        }
+    }
+    let Tuning { scale, keyboard } = patch.tuning;
+    patch.tuning = Tuning::new(scale, keyboard).map_err(|error| match error {
+        TuningError::Invalid(message) => PresetError::Invalid(message),
+        _ => PresetError::Invalid("the reference note of the tuning is unmapped"),
+    })?;
+    Ok((patch, setup))
+}
+
+pub fn write_bank(programs: &[Patch], current: usize, setup: &Setup) -> Vec<u8> {
+    let mut writer = Writer::new(BANK_MAGIC);
+    write_setup(&mut writer, setup);
+    writer.section(CURRENT_PROGRAM_TAG, |writer| writer.u32(current as u32));
+    let program_setup = Setup { morph_slots: Default::default(), ..setup.clone() };
+    for patch in programs {
+        let program = write_preset(patch, &program_setup);
+        writer.section(PROGRAM_TAG, |writer| writer.data.extend_from_slice(&program));
+    }
+    writer.data
+}
+
+/// Read the programs of a bank along with the number of the current one, which is limited to the
+/// programs present.
+pub fn read_bank(data: &[u8]) -> Result<(Vec<Patch>, usize, Setup), PresetError> {
+    let mut programs = Vec::new();
+    let mut current = 0;
+    let mut setup = Setup::default();
+    for (tag, mut reader) in sections(data, BANK_MAGIC)?.1 {
+        match &tag {
+            PROGRAM_TAG => {
+                let (patch, _) = read_preset(reader.data)?;
+                programs.push(patch);
+            }
+            CURRENT_PROGRAM_TAG => {
+                current = reader.u32()? as usize;
+            }
+            _ => read_setup_section(&tag, &mut reader, &mut setup)?,
+        }
+    }
+    let current = current.min(programs.len().saturating_sub(1));
+    Ok((programs, current, setup))
+}
+
+fn write_patch(writer: &mut Writer, patch: &Patch) {
+    writer.section(NAME_TAG, |writer| writer.string(&patch.name));
+
+    let metadata = &patch.metadata;
+    writer.section(METADATA_TAG, |writer| {
+        writer.string(&metadata.author);
+        writer.string(&metadata.category);
+        writer.u32(metadata.tags.len() as u32);
+        metadata.tags.iter().for_each(|tag| writer.string(tag));
+        writer.string(&metadata.notes);
+    });
+
+    writer.section(PARAMETERS_TAG, |writer| {
+        writer.u16(LAYOUT_VERSION);
+        writer.u32(patch.parameters.len() as u32);
+        for (info, value) in PARAMETERS.iter().zip(patch.parameters.iter()) {
+            writer.string(info.id);
+            writer.f32(*value);
+        }
+    });
+
+    writer.section(MOD_ROUTES_TAG, |writer| {
+        writer.u32(patch.mod_routes.len() as u32);
+        for route in patch.mod_routes.iter() {
+            writer.u8(route.source as u8);
+            writer.u8(route.via as u8);
+            writer.string(PARAMETERS[route.destination].id);
+            writer.f32(route.amount);
+        }
+    });
+
+    writer.section(EFFECT_ORDER_TAG, |writer| {
+        patch.effect_order.iter().for_each(|kind| writer.u8(*kind as u8));
+    });
+
+    if let Some(path) = &patch.wavetable_path {
+        writer.section(WAVETABLE_TAG, |writer| writer.string(&path.to_string_lossy()));
+    }
+
+    let scale = &patch.tuning.scale;
+    writer.section(SCALE_TAG, |writer| {
+        writer.string(&scale.description);
+        writer.u32(scale.cents.len() as u32);
+        scale.cents.iter().for_each(|cents| writer.f64(*cents));
+    });
+
+    let keyboard = &patch.tuning.keyboard;
+    writer.section(KEYBOARD_TAG, |writer| {
+        writer.u8(keyboard.first_note);
+        writer.u8(keyboard.last_note);
+        writer.u8(keyboard.middle_note);
+        writer.u8(keyboard.reference_note);
+        writer.f64(keyboard.reference_freq);
+        writer.i32(keyboard.octave_degree);
+        writer.u32(keyboard.mapping.len() as u32);
+        for degree in keyboard.mapping.iter() {
+            writer.u8(degree.is_some() as u8);
+            writer.i32(degree.unwrap_or(0));
+        }
+    });
+}
+
+/// Read a section belonging to the patch from a chunk of the given version. Returns `false` for
+/// sections that don't.
+fn read_patch_section(
+    version: u16,
+    tag: &[u8; 4],
+    reader: &mut Reader,
+    patch: &mut Patch
+) -> Result<bool, PresetError> {
+    match tag {
+        NAME_TAG => {
+            patch.name = reader.string()?;
+        }
+
+        METADATA_TAG => {
+            let author = reader.string()?;
+            let category = reader.string()?;
+            let count = reader.u32()? as usize;
+            let tags = (0..count).map(|_| reader.string()).collect::<Result<_, _>>()?;
+            let notes = reader.string()?;
+            patch.metadata = Metadata { author, category, tags, notes };
+        }
+
+        PARAMETERS_TAG => {
+            let mut saved = Vec::new();
+            let layout = if version < 2 {
+                let count = reader.u32()? as usize;
+                let (layout, ids) = migration::ids_by_index(count);
+                for id in ids.into_iter().take(count) {
+                    saved.push((id.to_string(), reader.f32()?));
+                }
+                layout
+            } else {
+                let layout = reader.u16()?;
+                let count = reader.u32()? as usize;
+                for _ in 0..count {
+                    saved.push((reader.string()?, reader.f32()?));
+                }
+                layout
+            };
+            let saved = saved.iter().map(|(id, value)| (id.as_str(), *value));
+            patch.parameters = migration::migrate(layout, saved)?;
+        }
+
+        MOD_ROUTES_TAG => {
+            let count = reader.u32()? as usize;
+            for slot in 0..count {
+                let source = mod_source(reader.u8()?)?;
+                let via = mod_source(reader.u8()?)?;
+                let destination = if version < 2 {
+                    Some(reader.u32()? as usize).filter(|&index| index < PARAMETERS.len())
+                } else {
+                    migration::index_of(&reader.string()?)
+                };
+                let amount = reader.f32()?;
+                if !amount.is_finite() {
+                    return Err(PresetError::Invalid("invalid modulation route"));
+                }
+                // routes to parameters that no longer exist are dropped
+                let route = match destination {
+                    Some(destination) => {
+                        ModRoute { source, destination, amount: amount.clamp(-1.0, 1.0), via }
+                    }
+                    None => ModRoute::default(),
+                };
+                if let Some(slot) = patch.mod_routes.get_mut(slot) {
+                    *slot = route;
+                }
+            }
+        }
+
+        EFFECT_ORDER_TAG => {
+            for kind in patch.effect_order.iter_mut() {
+                *kind = EffectKind::from_index(reader.u8()? as usize).ok_or(
+                    PresetError::Invalid("unknown effect")
+                )?;
+            }
+        }
+
+        WAVETABLE_TAG => {
+            patch.wavetable_path = Some(PathBuf::from(reader.string()?));
+        }
+
+        SCALE_TAG => {
+            let description = reader.string()?;
+            let count = reader.u32()? as usize;
+            let cents = (0..count).map(|_| reader.f64()).collect::<Result<_, _>>()?;
+            patch.tuning.scale = Scale { description, cents };
+        }
+
+        KEYBOARD_TAG => {
+            let mut keyboard = KeyboardMapping {
+                first_note: reader.u8()?,
+                last_note: reader.u8()?,
+                middle_note: reader.u8()?,
+                reference_note: reader.u8()?,
+                reference_freq: reader.f64()?,
+                octave_degree: reader.i32()?,
+                mapping: Vec::new(),
+            };
+            let count = reader.u32()? as usize;
+            for _ in 0..count {
+                let mapped = reader.u8()? != 0;
+                let degree = reader.i32()?;
+                keyboard.mapping.push(if mapped { Some(degree) } else { None });
+            }
+            patch.tuning.keyboard = keyboard;
+        }
+
+        _ => {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn write_setup(writer: &mut Writer, setup: &Setup) {
+    let mpe = &setup.mpe;
+    writer.section(MPE_TAG, |writer| {
+        writer.u8(mpe.enabled as u8);
+        writer.u8(mpe.lower_zone);
+        writer.u8(mpe.upper_zone);
+        writer.f32(mpe.pitch_bend_range);
+        writer.f32(mpe.master_pitch_bend_range);
+    });
+
+    writer.section(OVERSAMPLING_TAG, |writer| {
+        writer.u8(setup.oversampling.realtime as u8);
+        writer.u8(setup.oversampling.offline as u8);
+    });
+
+    if setup.morph_slots.iter().any(Option::is_some) {
+        writer.section(MORPH_TAG, |writer| {
+            for slot in setup.morph_slots.iter() {
+                // empty slots are left empty
+                let preset = slot.as_ref().map_or_else(Vec::new, |patch| {
+                    let mut preset = Writer::new(PRESET_MAGIC);
+                    write_patch(&mut preset, patch);
+                    preset.data
+                });
+                writer.u32(preset.len() as u32);
+                writer.data.extend_from_slice(&preset);
+            }
+        });
+    }
+}
+
+/// Read a section belonging to the setup, skipping all others.
+fn read_setup_section(
+    tag: &[u8; 4],
+    reader: &mut Reader,
+    setup: &mut Setup
+) -> Result<(), PresetError> {
+    match tag {
+        MPE_TAG => {
+            setup.mpe = MpeSettings {
+                enabled: reader.u8()? != 0,
+                lower_zone: reader.u8()?,
+                upper_zone: reader.u8()?,
+                pitch_bend_range: reader.f32()?,
+                master_pitch_bend_range: reader.f32()?,
+            }.normalized();
+        }
+
+        OVERSAMPLING_TAG => {
+            setup.oversampling = OversamplingSettings {
+                realtime: oversampling(reader.u8()?)?,
+                offline: oversampling(reader.u8()?)?,
+            };
+        }
+
+        MORPH_TAG => {
+            for slot in setup.morph_slots.iter_mut() {
+                let length = reader.u32()? as usize;
+                *slot = match reader.bytes(length)? {
+                    [] => None,
+                    preset => Some(read_preset(preset)?.0),
+                };
+            }
+        }
+
+        _ => (),
+    }
+    Ok(())
+}
+
+fn mod_source(index: u8) -> Result<ModSource, PresetError> {
+    ModSource::from_index(index as usize).ok_or(PresetError::Invalid("unknown modulation source"))
+}
+
+fn oversampling(index: u8) -> Result<Oversampling, PresetError> {
+    Oversampling::from_index(index as usize).ok_or(
+        PresetError::Invalid("unknown oversampling factor")
+    )
+}
+
+/// The tag and payload of every section of a chunk.
+type Sections<'a> = Vec<([u8; 4], Reader<'a>)>;
+
+/// Check the header of a chunk and split the rest into its sections, returning the format version
+/// along with them.
+fn sections<'a>(data: &'a [u8], magic: &[u8; 4]) -> Result<(u16, Sections<'a>), PresetError> {
+    let mut reader = Reader { data };
+    if reader.bytes(4).map_err(|_| PresetError::WrongFormat)? != magic {
+        return Err(PresetError::WrongFormat);
+    }
+    let version = reader.u16()?;
+    if version > CHUNK_VERSION {
+        return Err(PresetError::UnsupportedVersion(version));
+    }
+
+    let mut sections = Vec::new();
+    while !reader.data.is_empty() {
+        let tag = reader.bytes(4)?.try_into().unwrap();
+        let length = reader.u32()? as usize;
+        sections.push((tag, Reader { data: reader.bytes(length)? }));
+    }
+    Ok((version, sections))
+}
+
+struct Writer {
+    data: Vec<u8>,
+}
+
+impl Writer {
+    fn new(magic: &[u8; 4]) -> Self {
+        let mut writer = Self { data: magic.to_vec() };
+        writer.data.extend_from_slice(&CHUNK_VERSION.to_le_bytes());
+        writer
+    }
+
+    /// Write a section, filling in the length once its payload is written.
+    fn section(&mut self, tag: &[u8; 4], write: impl FnOnce(&mut Self)) {
+        self.data.extend_from_slice(tag);
+        let length_pos = self.data.len();
+        self.u32(0);
+        write(self);
+        let length = (self.data.len() - length_pos - 4) as u32;
+        self.data[length_pos..length_pos + 4].copy_from_slice(&length.to_le_bytes());
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn i32(&mut self, value: i32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn f32(&mut self, value: f32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn f64(&mut self, value: f64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// UTF-8 text, preceded by its length in bytes.
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.data.extend_from_slice(value.as_bytes());
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], PresetError> {
+        if self.data.len() < count {
+            return Err(PresetError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(count);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, PresetError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, PresetError> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, PresetError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, PresetError> {
+        Ok(i32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn f32(&mut self) -> Result<f32, PresetError> {
+        Ok(f32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> Result<f64, PresetError> {
+        Ok(f64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, PresetError> {
+        let length = self.u32()? as usize;
+        String::from_utf8(self.bytes(length)?.to_vec()).map_err(|_| {
+            PresetError::Invalid("text is not valid UTF-8")
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preset::factory;
+    use crate::tuning::scala::{ MAX_MAP_SIZE, MAX_SCALE_SIZE };
+
+    /// A chunk holding just the given section.
+    fn chunk(tag: &[u8; 4], write: impl FnOnce(&mut Writer)) -> Vec<u8> {
+        let mut writer = Writer::new(PRESET_MAGIC);
+        writer.section(tag, write);
+        writer.data
+    }
+
+    #[test]
+    fn round_trips_presets_and_banks() {
+        let mut patch = factory::factory_bank().swap_remove(1);
+        patch.tuning.scale.cents = vec![190.0, 400.0, 500.0, 1200.0];
+        patch.tuning.keyboard.mapping = vec![Some(0), None, Some(1), Some(2), Some(3)];
+        let setup = Setup { morph_slots: [None, Some(patch.clone())], ..Setup::default() };
+        let data = write_preset(&patch, &setup);
+        assert_eq!(read_preset(&data).unwrap(), (patch.clone(), setup.clone()));
+
+        let programs = vec![Patch::default(), patch];
+        let (read, current, read_setup) = read_bank(&write_bank(&programs, 1, &setup)).unwrap();
+        assert_eq!((read, current, read_setup), (programs, 1, setup));
+    }
+
+    /// Chunks cut off between two sections merely lack the later ones, but a cut anywhere else
+    /// must be noticed.
+    #[test]
+    fn rejects_truncated_chunks() {
+        let data = write_bank(&factory::factory_bank(), 0, &Setup::default());
+        let mut boundaries = vec![6];
+        while let Some(&end) = boundaries.last().filter(|&&end| end < data.len()) {
+            let length = u32::from_le_bytes(data[end + 4..end + 8].try_into().unwrap());
+            boundaries.push(end + 8 + length as usize);
+        }
+        for length in 0..data.len() {
+            let result = read_bank(&data[..length]);
+            if boundaries.contains(&length) {
+                assert!(result.is_ok(), "length {}", length);
+            } else {
+                assert!(result.is_err(), "length {}", length);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_corrupt_tunings() {
+        let scale = |count: u32, cents: f64| {
+            chunk(SCALE_TAG, |writer| {
+                writer.string("Scale");
+                writer.u32(count);
+                (0..count).for_each(|_| writer.f64(cents));
+            })
+        };
+        assert!(read_preset(&scale(4, 100.0)).is_ok());
+        let too_large = MAX_SCALE_SIZE as u32 + 1;
+        for data in [scale(0, 100.0), scale(too_large, 100.0), scale(4, f64::NAN)].iter() {
+            assert!(matches!(read_preset(data), Err(PresetError::Invalid(_))));
+        }
+        assert!(matches!(read_preset(&scale(4, f64::INFINITY)), Err(PresetError::Invalid(_))));
+
+        let keyboard = |notes: [u8; 4], octave: i32, mapping: &[i32]| {
+            chunk(KEYBOARD_TAG, |writer| {
+                notes.iter().for_each(|&note| writer.u8(note));
+                writer.f64(440.0);
+                writer.i32(octave);
+                writer.u32(mapping.len() as u32);
+                for &degree in mapping {
+                    writer.u8(1);
+                    writer.i32(degree);
+                }
+            })
+        };
+        assert!(read_preset(&keyboard([0, 127, 60, 69], 12, &[0, -1, 11])).is_ok());
+        for data in [
+            keyboard([0, 128, 60, 69], 12, &[0]),
+            keyboard([0, 127, 60, 255], 12, &[0]),
+            keyboard([0, 127, 60, 69], i32::MAX, &[0]),
+            keyboard([0, 127, 60, 69], 12, &[0, i32::MIN]),
+            keyboard([0, 127, 60, 69], 12, &[0; MAX_MAP_SIZE + 1]),
+        ]
+        .iter()
+        {
+            assert!(matches!(read_preset(data), Err(PresetError::Invalid(_))));
+        }
+    }
+
+    #[test]
+    fn rejects_foreign_and_newer_chunks() {
+        let data = write_preset(&Patch::default(), &Setup::default());
+        assert!(matches!(read_bank(&data), Err(PresetError::WrongFormat)));
+        let mut newer = data;
+        newer[4..6].copy_from_slice(&(CHUNK_VERSION + 1).to_le_bytes());
+        assert!(matches!(read_preset(&newer), Err(PresetError::UnsupportedVersion(_))));
+    }
+}
@@ -0,0 +1,450 @@
+//! Factory presets compiled into the plugin.
+//!
+//! Every preset is written down as the changes it makes to the init patch, with parameters given
+//! in plain values like on a patch sheet, i.e. seconds, Hz and percent. That keeps the presets
+//! readable, and parameters added later simply keep their defaults.
+
+use crate::*;
+use crate::dsp::modulation::{ ModRoute, ModSource };
+use crate::parameters::PARAMETERS;
+use crate::preset::{ Metadata, Patch };
+
+const FACTORY_AUTHOR: &str = "JLFO";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Category {
+    Bass,
+    Lead,
+    Pad,
+    Pluck,
+    Fx,
+}
+
+impl Category {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Category::Bass => "Bass",
+            Category::Lead => "Lead",
+            Category::Pad => "Pad",
+            Category::Pluck => "Pluck",
+            Category::Fx => "FX",
+        }
+    }
+}
+
+/// Category order of the preset browser in the editor.
+#[allow(dead_code)]
+pub const CATEGORIES: [Category; 5] = [
+    Category::Bass,
+    Category::Lead,
+    Category::Pad,
+    Category::Pluck,
+    Category::Fx,
+];
+
+pub struct FactoryPreset {
+    pub name: &'static str,
+    pub category: Category,
+    /// Plain values of the parameters that differ from the init patch.
+    settings: &'static [(usize, f32)],
+    /// Modulation routes as source, destination and amount, filling the slots in order.
+    routes: &'static [(ModSource, usize, f32)],
+}
+
+impl FactoryPreset {
+    pub fn patch(&self) -> Patch {
+        let mut patch = Patch {
+            name: self.name.to_string(),
+            metadata: Metadata {
+                author: FACTORY_AUTHOR.to_string(),
+                category: self.category.name().to_string(),
+                ..Metadata::default()
+            },
+            ..Patch::default()
+        };
+        for &(index, plain) in self.settings {
+            patch.parameters[index] = PARAMETERS[index].normalize(plain);
+        }
+        for (slot, &(source, destination, amount)) in self.routes.iter().enumerate() {
+            patch.mod_routes[slot] = ModRoute { source, destination, amount, via: ModSource::None };
+        }
+        patch
+    }
+}
+
+/// All factory presets, grouped by category in the order of `CATEGORIES`.
+pub static FACTORY_PRESETS: &[FactoryPreset] = &[
+    // basses
+    FactoryPreset {
+        name: "Round Sub",
+        category: Category::Bass,
+        settings: &[
+            (SAWTOOTH_AMP, 0.0),
+            (SINE_AMP, 70.0),
+            (SUB_AMP, 60.0),
+            (SUB_SHAPE, 1.0),
+            (SUB_OCTAVE, 0.0),
+            (FILTER_CUTOFF, 800.0),
+            (AMP_ATTACK, 0.005),
+            (AMP_SUSTAIN_LEVEL, 100.0),
+            (AMP_RELEASE, 0.08),
+        ],
+        routes: &[],
+    },
+    FactoryPreset {
+        name: "Acid Squelch",
+        category: Category::Bass,
+        settings: &[
+            (SAWTOOTH_AMP, 90.0),
+            (FILTER_CUTOFF, 180.0),
+            (FILTER_RESONANCE, 75.0),
+            (FILTER_DRIVE, 40.0),
+            (FILTER_ATTACK, 0.002),
+            (FILTER_DECAY, 0.25),
+            (FILTER_SUSTAIN_LEVEL, 0.0),
+            (AMP_ATTACK, 0.002),
+            (AMP_SUSTAIN_LEVEL, 90.0),
+            (AMP_RELEASE, 0.05),
+            (DISTORTION_SHAPE, 0.0),
+            (DISTORTION_DRIVE, 30.0),
+            (DISTORTION_MIX, 50.0),
+            (DISTORTION_POSITION, 1.0),
+        ],
+        routes: &[
+            (ModSource::FilterEnvelope, FILTER_CUTOFF, 0.55),
+            (ModSource::Velocity, FILTER_CUTOFF, 0.15),
+        ],
+    },
+    FactoryPreset {
+        name: "Pulse Reese",
+        category: Category::Bass,
+        settings: &[
+            (SAWTOOTH_AMP, 60.0),
+            (PULSE_AMP, 60.0),
+            (PULSE_WIDTH, 30.0),
+            (PULSE_WIDTH_MOD_AMP, 20.0),
+            (PULSE_WIDTH_MOD_FREQ, 0.3),
+            (PHASE_SHIFT_AMOUNT, 30.0),
+            (PHASE_SHIFT_MOD_FREQ, 0.2),
+            (SUB_AMP, 40.0),
+            (FILTER_CUTOFF, 1200.0),
+            (FILTER_RESONANCE, 20.0),
+            (AMP_ATTACK, 0.01),
+            (AMP_SUSTAIN_LEVEL, 100.0),
+            (AMP_RELEASE, 0.15),
+        ],
+        routes: &[(ModSource::Velocity, FILTER_CUTOFF, 0.2)],
+    },
+    // leads
+    FactoryPreset {
+        name: "Saw Lead",
+        category: Category::Lead,
+        settings: &[
+            (SAWTOOTH_AMP, 80.0),
+            (PULSE_AMP, 30.0),
+            (FILTER_CUTOFF, 3500.0),
+            (FILTER_RESONANCE, 25.0),
+            (PITCH_MOD_AMP, 30.0),
+            (PITCH_MOD_FREQ, 5.5),
+            (LFO_FADE_DELAY, 0.3),
+            (LFO_FADE_RISE, 0.5),
+            (AMP_ATTACK, 0.01),
+            (AMP_SUSTAIN_LEVEL, 90.0),
+            (AMP_RELEASE, 0.25),
+            (DELAY_BYPASS, 0.0),
+            (DELAY_TIME, 0.3),
+            (DELAY_FEEDBACK, 35.0),
+            (DELAY_MIX, 20.0),
+        ],
+        routes: &[
+            (ModSource::ModWheel, PITCH_MOD_AMP, 0.5),
+            (ModSource::Aftertouch, FILTER_CUTOFF, 0.2),
+        ],
+    },
+    FactoryPreset {
+        name: "Square Hero",
+        category: Category::Lead,
+        settings: &[
+            (SAWTOOTH_AMP, 0.0),
+            (PULSE_AMP, 85.0),
+            (PULSE_WIDTH, 50.0),
+            (PULSE_WIDTH_MOD_AMP, 15.0),
+            (PULSE_WIDTH_MOD_FREQ, 4.0),
+            (SUB_AMP, 30.0),
+            (FILTER_CUTOFF, 5000.0),
+            (FILTER_POLES, 2.0),
+            (AMP_ATTACK, 0.005),
+            (AMP_SUSTAIN_LEVEL, 100.0),
+            (AMP_RELEASE, 0.15),
+            (CHORUS_BYPASS, 0.0),
+            (CHORUS_MIX, 30.0),
+        ],
+        routes: &[(ModSource::ModWheel, PULSE_WIDTH_MOD_AMP, 0.4)],
+    },
+    FactoryPreset {
+        name: "Screamer",
+        category: Category::Lead,
+        settings: &[
+            (SAWTOOTH_AMP, 90.0),
+            (FILTER_CUTOFF, 2500.0),
+            (FILTER_RESONANCE, 45.0),
+            (FILTER_DRIVE, 60.0),
+            (DISTORTION_SHAPE, 3.0),
+            (DISTORTION_DRIVE, 70.0),
+            (DISTORTION_MIX, 80.0),
+            (AMP_ATTACK, 0.005),
+            (AMP_SUSTAIN_LEVEL, 85.0),
+            (AMP_RELEASE, 0.3),
+            (REVERB_BYPASS, 0.0),
+            (REVERB_MIX, 15.0),
+        ],
+        routes: &[
+            (ModSource::FilterEnvelope, FILTER_CUTOFF, 0.2),
+            (ModSource::Velocity, DISTORTION_DRIVE, 0.3),
+        ],
+    },
+    // pads
+    FactoryPreset {
+        name: "Warm Strings",
+        category: Category::Pad,
+        settings: &[
+            (SAWTOOTH_AMP, 70.0),
+            (PULSE_AMP, 40.0),
+            (PULSE_WIDTH_MOD_AMP, 25.0),
+            (PULSE_WIDTH_MOD_FREQ, 0.4),
+            (PHASE_SHIFT_AMOUNT, 40.0),
+            (FILTER_CUTOFF, 2200.0),
+            (FILTER_POLES, 2.0),
+            (AMP_ATTACK, 0.6),
+            (AMP_DECAY, 1.0),
+            (AMP_SUSTAIN_LEVEL, 85.0),
+            (AMP_RELEASE, 1.0),
+            (CHORUS_BYPASS, 0.0),
+            (CHORUS_RATE, 0.4),
+            (CHORUS_DEPTH, 60.0),
+            (CHORUS_MIX, 50.0),
+            (REVERB_BYPASS, 0.0),
+            (REVERB_SIZE, 80.0),
+            (REVERB_MIX, 35.0),
+        ],
+        routes: &[(ModSource::ModWheel, FILTER_CUTOFF, 0.25)],
+    },
+    FactoryPreset {
+        name: "Glass Table",
+        category: Category::Pad,
+        settings: &[
+            (SAWTOOTH_AMP, 0.0),
+            (WAVETABLE_AMP, 80.0),
+            (WAVETABLE_POSITION, 20.0),
+            (SINE_AMP, 30.0),
+            (FILTER_CUTOFF, 6000.0),
+            (AMP_ATTACK, 0.8),
+            (AMP_SUSTAIN_LEVEL, 80.0),
+            (AMP_RELEASE, 1.0),
+            (REVERB_BYPASS, 0.0),
+            (REVERB_SIZE, 90.0),
+            (REVERB_DAMPING, 30.0),
+            (REVERB_MIX, 45.0),
+        ],
+        routes: &[
+            (ModSource::CutoffLfo, WAVETABLE_POSITION, 0.3),
+            (ModSource::ModWheel, WAVETABLE_POSITION, 0.5),
+        ],
+    },
+    FactoryPreset {
+        name: "Slow Sweep",
+        category: Category::Pad,
+        settings: &[
+            (SAWTOOTH_AMP, 70.0),
+            (SUB_AMP, 30.0),
+            (NOISE_AMP, 5.0),
+            (NOISE_COLOR, 1.0),
+            (FILTER_CUTOFF, 400.0),
+            (FILTER_RESONANCE, 50.0),
+            (FILTER_CUTOFF_MOD_AMP, 30.0),
+            (FILTER_CUTOFF_MOD_FREQ, 0.08),
+            (FILTER_CUTOFF_MOD_SHAPE, 2.0),
+            (AMP_ATTACK, 1.0),
+            (AMP_SUSTAIN_LEVEL, 90.0),
+            (AMP_RELEASE, 1.0),
+            (DELAY_BYPASS, 0.0),
+            (DELAY_TIME, 0.5),
+            (DELAY_FEEDBACK, 50.0),
+            (DELAY_MIX, 25.0),
+        ],
+        routes: &[],
+    },
+    // plucks
+    FactoryPreset {
+        name: "Pizzicato",
+        category: Category::Pluck,
+        settings: &[
+            (SAWTOOTH_AMP, 80.0),
+            (FILTER_CUTOFF, 300.0),
+            (FILTER_ATTACK, 0.001),
+            (FILTER_DECAY, 0.15),
+            (FILTER_SUSTAIN_LEVEL, 0.0),
+            (AMP_ATTACK, 0.001),
+            (AMP_DECAY, 0.4),
+            (AMP_SUSTAIN_LEVEL, 0.0),
+            (AMP_RELEASE, 0.3),
+            (REVERB_BYPASS, 0.0),
+            (REVERB_MIX, 20.0),
+        ],
+        routes: &[
+            (ModSource::FilterEnvelope, FILTER_CUTOFF, 0.5),
+            (ModSource::Key, FILTER_CUTOFF, 0.1),
+        ],
+    },
+    FactoryPreset {
+        name: "Bell Pluck",
+        category: Category::Pluck,
+        settings: &[
+            (SAWTOOTH_AMP, 0.0),
+            (SINE_AMP, 80.0),
+            (PULSE_AMP, 25.0),
+            (PULSE_WIDTH, 15.0),
+            (FILTER_CUTOFF, 8000.0),
+            (AMP_ATTACK, 0.001),
+            (AMP_DECAY, 1.2),
+            (AMP_SUSTAIN_LEVEL, 0.0),
+            (AMP_RELEASE, 1.0),
+            (DELAY_BYPASS, 0.0),
+            (DELAY_SYNC, 1.0),
+            (DELAY_FEEDBACK, 30.0),
+            (DELAY_MIX, 20.0),
+        ],
+        routes: &[(ModSource::AmpEnvelope, PULSE_AMP, 0.2)],
+    },
+    FactoryPreset {
+        name: "Muted Guitar",
+        category: Category::Pluck,
+        settings: &[
+            (SAWTOOTH_AMP, 60.0),
+            (PULSE_AMP, 50.0),
+            (PULSE_WIDTH, 35.0),
+            (FILTER_CUTOFF, 600.0),
+            (FILTER_RESONANCE, 30.0),
+            (FILTER_POLES, 2.0),
+            (FILTER_ATTACK, 0.001),
+            (FILTER_DECAY, 0.08),
+            (FILTER_SUSTAIN_LEVEL, 10.0),
+            (AMP_ATTACK, 0.001),
+            (AMP_DECAY, 0.2),
+            (AMP_SUSTAIN_LEVEL, 10.0),
+            (AMP_RELEASE, 0.1),
+            (DISTORTION_SHAPE, 3.0),
+            (DISTORTION_DRIVE, 20.0),
+            (DISTORTION_MIX, 40.0),
+        ],
+        routes: &[
+            (ModSource::FilterEnvelope, FILTER_CUTOFF, 0.35),
+            (ModSource::Velocity, FILTER_CUTOFF, 0.2),
+        ],
+    },
+    // effects
+    FactoryPreset {
+        name: "Wind Tunnel",
+        category: Category::Fx,
+        settings: &[
+            (SAWTOOTH_AMP, 0.0),
+            (NOISE_AMP, 80.0),
+            (NOISE_COLOR, 1.0),
+            (FILTER_CUTOFF, 900.0),
+            (FILTER_RESONANCE, 70.0),
+            (FILTER_POLES, 2.0),
+            (FILTER_CUTOFF_MOD_AMP, 35.0),
+            (FILTER_CUTOFF_MOD_FREQ, 0.15),
+            (AMP_ATTACK, 0.8),
+            (AMP_SUSTAIN_LEVEL, 100.0),
+            (AMP_RELEASE, 1.0),
+            (REVERB_BYPASS, 0.0),
+            (REVERB_SIZE, 85.0),
+            (REVERB_MIX, 40.0),
+        ],
+        routes: &[(ModSource::ModWheel, FILTER_RESONANCE, 0.25)],
+    },
+    FactoryPreset {
+        name: "Robot Chatter",
+        category: Category::Fx,
+        settings: &[
+            (SAWTOOTH_AMP, 50.0),
+            (PULSE_AMP, 60.0),
+            (FILTER_CUTOFF, 1500.0),
+            (FILTER_RESONANCE, 60.0),
+            (FILTER_CUTOFF_MOD_SHAPE, 4.0),
+            (FILTER_CUTOFF_MOD_AMP, 30.0),
+            (FILTER_CUTOFF_MOD_FREQ, 8.0),
+            (CRUSH_BITS, 6.0),
+            (CRUSH_RATE, 8000.0),
+            (CRUSH_MIX, 70.0),
+            (CRUSH_POSITION, 1.0),
+            (AMP_ATTACK, 0.005),
+            (AMP_SUSTAIN_LEVEL, 90.0),
+            (AMP_RELEASE, 0.2),
+        ],
+        routes: &[],
+    },
+    FactoryPreset {
+        name: "Space Drop",
+        category: Category::Fx,
+        settings: &[
+            (SAWTOOTH_AMP, 60.0),
+            (SINE_AMP, 50.0),
+            (PITCH_MOD_SHAPE, 3.0),
+            (PITCH_MOD_AMP, 100.0),
+            (PITCH_MOD_FREQ, 0.5),
+            (FILTER_CUTOFF, 3000.0),
+            (FILTER_RESONANCE, 40.0),
+            (AMP_ATTACK, 0.01),
+            (AMP_SUSTAIN_LEVEL, 100.0),
+            (AMP_RELEASE, 1.0),
+            (DELAY_BYPASS, 0.0),
+            (DELAY_TIME, 0.45),
+            (DELAY_FEEDBACK, 70.0),
+            (DELAY_MIX, 40.0),
+            (REVERB_BYPASS, 0.0),
+            (REVERB_SIZE, 95.0),
+            (REVERB_MIX, 40.0),
+        ],
+        routes: &[
+            (ModSource::FilterEnvelope, FILTER_CUTOFF, -0.3),
+            (ModSource::PitchLfo, FILTER_CUTOFF, 0.2),
+        ],
+    },
+];
+
+/// The factory presets of one category, as listed by the editor's preset browser.
+#[allow(dead_code)]
+pub fn presets_in(category: Category) -> impl Iterator<Item = &'static FactoryPreset> {
+    FACTORY_PRESETS.iter().filter(move |preset| preset.category == category)
+}
+
+/// Patches of all factory presets, which fill the first programs of a new instance.
+pub fn factory_bank() -> Vec<Patch> {
+    FACTORY_PRESETS.iter().map(FactoryPreset::patch).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dsp::modulation::{ is_mod_destination, NUM_MOD_SLOTS };
+
+    #[test]
+    fn presets_are_valid() {
+        assert!(FACTORY_PRESETS.len() <= NUM_PROGRAMS as usize);
+        for preset in FACTORY_PRESETS {
+            for &(index, plain) in preset.settings {
+                let value = PARAMETERS[index].normalize(plain);
+                assert!((0.0..=1.0).contains(&value), "{}: parameter {}", preset.name, index);
+            }
+            assert!(preset.routes.len() <= NUM_MOD_SLOTS, "{}", preset.name);
+            for &(source, destination, amount) in preset.routes {
+                assert_ne!(source, ModSource::None, "{}", preset.name);
+                let message = format!("{}: route to {}", preset.name, destination);
+                assert!(is_mod_destination(destination), "{}", message);
+                assert!((-1.0..=1.0).contains(&amount), "{}", message);
+            }
+        }
+    }
+}
@@ -0,0 +1,381 @@
+//! Standard VST2 preset files, `.fxp` for a single program and `.fxb` for a bank, as exchanged
+//! between hosts.
+//!
+//! Both come in two variants. The parameter variant only holds the normalized parameter values,
+//! which any host understands, while the chunk variant embeds the preset or bank chunk of this
+//! plugin and so carries the complete state. Files are tagged with the unique ID of the plugin,
+//! and files of other plugins are refused. All numbers are big-endian.
+
+use std::convert::TryInto;
+
+use super::{ chunk, migration, Patch, PresetError, Setup };
+use crate::{ PLUGIN_VERSION, UNIQUE_ID };
+
+const CHUNK_MAGIC: &[u8; 4] = b"CcnK";
+const PROGRAM_MAGIC: &[u8; 4] = b"FxCk";
+const PROGRAM_CHUNK_MAGIC: &[u8; 4] = b"FPCh";
+const BANK_MAGIC: &[u8; 4] = b"FxBk";
+const BANK_CHUNK_MAGIC: &[u8; 4] = b"FBCh";
+
+const PROGRAM_VERSION: i32 = 1;
+/// Banks from version 2 on store the current program.
+const BANK_VERSION: i32 = 2;
+/// Program names are stored zero-terminated in a fixed size field.
+const NAME_LENGTH: usize = 28;
+const BANK_RESERVED_LENGTH: usize = 124;
+
+/// Chosen by the user when saving a file from the editor.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FxFormat {
+    /// A list of normalized parameter values.
+    Parameters,
+    /// The preset or bank chunk of this plugin.
+    Chunk,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FxKind {
+    Program,
+    Bank,
+}
+
+/// Tell a program file from a bank file.
+pub fn kind(data: &[u8]) -> Result<FxKind, PresetError> {
+    let mut reader = Reader { data };
+    if reader.bytes(4).map_err(|_| PresetError::WrongFormat)? != CHUNK_MAGIC {
+        return Err(PresetError::WrongFormat);
+    }
+    reader.i32()?;
+    match reader.bytes(4)? {
+        magic if magic == PROGRAM_MAGIC || magic == PROGRAM_CHUNK_MAGIC => Ok(FxKind::Program),
+        magic if magic == BANK_MAGIC || magic == BANK_CHUNK_MAGIC => Ok(FxKind::Bank),
+        _ => Err(PresetError::WrongFormat),
+    }
+}
+
+pub fn write_fxp(patch: &Patch, setup: &Setup, format: FxFormat) -> Vec<u8> {
+    let mut writer = Writer { data: Vec::new() };
+    write_program(&mut writer, patch, setup, format);
+    writer.data
+}
+
+/// Read a program file. As a parameter program only holds the name and the parameters, the rest
+/// of the patch is taken from `base`, and no setup is returned. Parameter programs of the first
+/// release are migrated, with the parameters added since at their defaults.
+pub fn read_fxp(data: &[u8], base: &Patch) -> Result<(Patch, Option<Setup>), PresetError> {
+    read_program(&mut Reader { data }, base)
+}
+
+pub fn write_fxb(programs: &[Patch], current: usize, setup: &Setup, format: FxFormat) -> Vec<u8> {
+    let mut writer = Writer { data: Vec::new() };
+    let magic = match format {
+        FxFormat::Parameters => BANK_MAGIC,
+        FxFormat::Chunk => BANK_CHUNK_MAGIC,
+    };
+    let start = writer.begin(magic, BANK_VERSION);
+    writer.i32(programs.len() as i32);
+    writer.i32(current as i32);
+    writer.data.extend_from_slice(&[0; BANK_RESERVED_LENGTH]);
+
+    match format {
+        FxFormat::Parameters => {
+            for patch in programs {
+                write_program(&mut writer, patch, setup, format);
+            }
+        }
+        FxFormat::Chunk => {
+            let chunk = chunk::write_bank(programs, current, setup);
+            writer.i32(chunk.len() as i32);
+            writer.data.extend_from_slice(&chunk);
+        }
+    }
+    writer.end(start);
+    writer.data
+}
+
+/// Read a bank file along with the number of its current program. The programs of a parameter
+/// bank are completed from the patches in `base` at the same position.
+pub fn read_fxb(
+    data: &[u8],
+    base: &[Patch]
+) -> Result<(Vec<Patch>, usize, Option<Setup>), PresetError> {
+    let (magic, version, mut reader) = open(&mut Reader { data })?;
+    if version > BANK_VERSION {
+        return Err(PresetError::UnsupportedVersion(version as u16));
+    }
+    let count = reader.i32()?.max(0) as usize;
+    let current = if version >= 2 { reader.i32()?.max(0) as usize } else { 0 };
+    reader.bytes(if version >= 2 { BANK_RESERVED_LENGTH } else { BANK_RESERVED_LENGTH + 4 })?;
+
+    if &magic == BANK_CHUNK_MAGIC {
+        let (programs, current, setup) = chunk::read_bank(reader.sized_bytes()?)?;
+        return Ok((programs, current, Some(setup)));
+    }
+    if &magic != BANK_MAGIC {
+        return Err(PresetError::WrongFormat);
+    }
+
+    let mut programs = Vec::new();
+    let default_patch = Patch::default();
+    for index in 0..count {
+        let base = base.get(index).unwrap_or(&default_patch);
+        programs.push(read_program(&mut reader, base)?.0);
+    }
+    let current = current.min(programs.len().saturating_sub(1));
+    Ok((programs, current, None))
+}
+
+fn write_program(writer: &mut Writer, patch: &Patch, setup: &Setup, format: FxFormat) {
+    let magic = match format {
+        FxFormat::Parameters => PROGRAM_MAGIC,
+        FxFormat::Chunk => PROGRAM_CHUNK_MAGIC,
+    };
+    let start = writer.begin(magic, PROGRAM_VERSION);
+    writer.i32(patch.parameters.len() as i32);
+    writer.name(&patch.name);
+
+    match format {
+        FxFormat::Parameters => {
+            patch.parameters.iter().for_each(|value| writer.f32(*value));
+        }
+        FxFormat::Chunk => {
+            let chunk = chunk::write_preset(patch, setup);
+            writer.i32(chunk.len() as i32);
+            writer.data.extend_from_slice(&chunk);
+        }
+    }
+    writer.end(start);
+}
+
+fn read_program(reader: &mut Reader, base: &Patch) -> Result<(Patch, Option<Setup>), PresetError> {
+    let (magic, version, mut reader) = open(reader)?;
+    if version > PROGRAM_VERSION {
+        return Err(PresetError::UnsupportedVersion(version as u16));
+    }
+    let count = reader.i32()?.max(0) as usize;
+    let name = reader.name()?;
+
+    if &magic == PROGRAM_CHUNK_MAGIC {
+        let (patch, setup) = chunk::read_preset(reader.sized_bytes()?)?;
+        return Ok((patch, Some(setup)));
+    }
+    if &magic != PROGRAM_MAGIC {
+        return Err(PresetError::WrongFormat);
+    }
+
+    // the layout is told by the number of values, extra ones are ignored
+    let values = (0..count).map(|_| reader.f32()).collect::<Result<Vec<_>, _>>()?;
+    let (layout, ids) = migration::ids_by_index(count);
+    let parameters = migration::migrate(layout, ids.into_iter().zip(values))?;
+    Ok((Patch { name, parameters, ..base.clone() }, None))
+}
+
+/// Read the common header of programs and banks, returning the magic number of the contents, the
+/// format version and the rest of the program or bank.
+fn open<'a>(reader: &mut Reader<'a>) -> Result<([u8; 4], i32, Reader<'a>), PresetError> {
+    if reader.bytes(4).map_err(|_| PresetError::WrongFormat)? != CHUNK_MAGIC {
+        return Err(PresetError::WrongFormat);
+    }
+    let mut contents = Reader { data: reader.sized_bytes()? };
+    let magic = contents.bytes(4)?.try_into().unwrap();
+    let version = contents.i32()?;
+    let id = contents.i32()?;
+    if id != UNIQUE_ID {
+        return Err(PresetError::WrongPlugin(id));
+    }
+    contents.i32()?; // version of the plugin that wrote the file
+    Ok((magic, version, contents))
+}
+
+struct Writer {
+    data: Vec<u8>,
+}
+
+impl Writer {
+    /// Start a program or bank. Returns the position of its size, to be filled in by `end`.
+    fn begin(&mut self, magic: &[u8; 4], version: i32) -> usize {
+        self.data.extend_from_slice(CHUNK_MAGIC);
+        let start = self.data.len();
+        self.i32(0);
+        self.data.extend_from_slice(magic);
+        self.i32(version);
+        self.i32(UNIQUE_ID);
+        self.i32(PLUGIN_VERSION);
+        start
+    }
+
+    fn end(&mut self, start: usize) {
+        let size = (self.data.len() - start - 4) as i32;
+        self.data[start..start + 4].copy_from_slice(&size.to_be_bytes());
+    }
+
+    fn i32(&mut self, value: i32) {
+        self.data.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn f32(&mut self, value: f32) {
+        self.data.extend_from_slice(&value.to_be_bytes());
+    }
+
+    /// Write a name, cut off at a character boundary to leave room for the terminating zero.
+    fn name(&mut self, name: &str) {
+        let mut length = name.len().min(NAME_LENGTH - 1);
+        while !name.is_char_boundary(length) {
+            length -= 1;
+        }
+        let mut field = [0; NAME_LENGTH];
+        field[..length].copy_from_slice(&name.as_bytes()[..length]);
+        self.data.extend_from_slice(&field);
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], PresetError> {
+        if count > self.data.len() {
+            return Err(PresetError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(count);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    /// Read bytes preceded by their count.
+    fn sized_bytes(&mut self) -> Result<&'a [u8], PresetError> {
+        let size = self.i32()?;
+        if size < 0 {
+            return Err(PresetError::Invalid("negative size"));
+        }
+        self.bytes(size as usize)
+    }
+
+    fn i32(&mut self) -> Result<i32, PresetError> {
+        Ok(i32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn f32(&mut self) -> Result<f32, PresetError> {
+        Ok(f32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn name(&mut self) -> Result<String, PresetError> {
+        let field = self.bytes(NAME_LENGTH)?;
+        let length = field.iter().position(|&byte| byte == 0).unwrap_or(NAME_LENGTH);
+        Ok(String::from_utf8_lossy(&field[..length]).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use crate::dsp::{ modulation::{ ModRoute, ModSource }, mpe::MpeSettings };
+    use crate::preset::factory::factory_bank;
+
+    fn test_setup() -> Setup {
+        Setup { mpe: MpeSettings { enabled: true, ..MpeSettings::default() }, ..Setup::default() }
+    }
+
+    fn test_patch() -> Patch {
+        let mut patch = factory_bank().remove(1);
+        patch.parameters[FILTER_RESONANCE] = 0.123;
+        patch.mod_routes[3] = ModRoute {
+            source: ModSource::Slide,
+            destination: WAVETABLE_POSITION,
+            amount: -0.5,
+            via: ModSource::Velocity,
+        };
+        patch
+    }
+
+    /// A parameter program laid out field by field, as another host would write it.
+    #[test]
+    fn writes_standard_program_layout() {
+        let patch = test_patch();
+        let mut expected = b"CcnK".to_vec();
+        expected.extend_from_slice(&(48 + 4 * NUM_PARAMETERS).to_be_bytes());
+        expected.extend_from_slice(b"FxCk");
+        expected.extend_from_slice(&1i32.to_be_bytes());
+        expected.extend_from_slice(&[0xe2, 0xbb, 0x54, 0x48]);
+        expected.extend_from_slice(&PLUGIN_VERSION.to_be_bytes());
+        expected.extend_from_slice(&NUM_PARAMETERS.to_be_bytes());
+        let mut name = [0; 28];
+        name[..patch.name.len()].copy_from_slice(patch.name.as_bytes());
+        expected.extend_from_slice(&name);
+        for value in patch.parameters.iter() {
+            expected.extend_from_slice(&value.to_be_bytes());
+        }
+
+        assert_eq!(write_fxp(&patch, &test_setup(), FxFormat::Parameters), expected);
+    }
+
+    #[test]
+    fn programs_round_trip() {
+        let patch = test_patch();
+        for &format in [FxFormat::Parameters, FxFormat::Chunk].iter() {
+            let data = write_fxp(&patch, &test_setup(), format);
+            assert_eq!(kind(&data).unwrap(), FxKind::Program);
+            let (read, setup) = read_fxp(&data, &Patch::default()).unwrap();
+            assert_eq!(write_fxp(&read, &test_setup(), format), data);
+
+            match format {
+                FxFormat::Parameters => {
+                    assert_eq!(read.name, patch.name);
+                    assert_eq!(read.parameters, patch.parameters);
+                    assert_eq!(setup, None);
+                }
+                FxFormat::Chunk => {
+                    assert_eq!(read, patch);
+                    assert_eq!(setup, Some(test_setup()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn banks_round_trip() {
+        let mut programs = factory_bank();
+        programs[2] = test_patch();
+        for &format in [FxFormat::Parameters, FxFormat::Chunk].iter() {
+            let data = write_fxb(&programs, 2, &test_setup(), format);
+            assert_eq!(kind(&data).unwrap(), FxKind::Bank);
+            let (read, current, _) = read_fxb(&data, &programs).unwrap();
+            assert_eq!(read, programs);
+            assert_eq!(current, 2);
+            assert_eq!(write_fxb(&read, current, &test_setup(), format), data);
+        }
+    }
+
+    #[test]
+    fn cuts_long_names() {
+        let name = "Ünïcödé names are cut at characters".to_string();
+        let patch = Patch { name, ..test_patch() };
+        let data = write_fxp(&patch, &Setup::default(), FxFormat::Parameters);
+        let (read, _) = read_fxp(&data, &Patch::default()).unwrap();
+        assert!(read.name.len() < NAME_LENGTH);
+        assert!(patch.name.starts_with(&read.name));
+    }
+
+    #[test]
+    fn refuses_other_plugins() {
+        let mut data = write_fxp(&test_patch(), &Setup::default(), FxFormat::Parameters);
+        data[16..20].copy_from_slice(&0x4162_4364i32.to_be_bytes());
+        match read_fxp(&data, &Patch::default()) {
+            Err(PresetError::WrongPlugin(id)) => assert_eq!(id, 0x4162_4364),
+            _ => panic!("accepted a program of another plugin"),
+        }
+    }
+
+    #[test]
+    fn refuses_truncated_files() {
+        for &format in [FxFormat::Parameters, FxFormat::Chunk].iter() {
+            let data = write_fxb(&factory_bank(), 0, &Setup::default(), format);
+            for length in (0..data.len()).step_by(7) {
+                assert!(read_fxb(&data[..length], &[]).is_err());
+            }
+        }
+    }
+}
@@ -0,0 +1,222 @@
+//! Mapping of saved parameter values onto the current parameter layout.
+//!
+//! Patches store parameter values under the stable identifier of the parameter rather than its
+//! index, so parameters can be added and reordered without breaking saved patches. Parameters
+//! missing from a patch keep their default, and values of parameters that no longer exist are
+//! dropped.
+//!
+//! Whenever the meaning of the value of an existing parameter changes, e.g. because its range or
+//! taper changes, `LAYOUT_VERSION` is increased and `migrate` learns to convert values saved with
+//! the previous layout. Patches carry the layout version they were saved with.
+
+use std::f32::consts::PI;
+
+use super::PresetError;
+use crate::*;
+use crate::parameters::{ default_values, PARAMETERS };
+
+/// Layout version of patches saved by this version of the plugin.
+///
+/// 1. The first release, with 31 parameters that were only ever saved by the host, by index.
+/// 2. Parameter ranges and tapers of the parameter table, plain envelope times up to 1 s for the
+///    attack and release and 2 s for the decay, LFO frequencies in hertz and a working filter.
+pub const LAYOUT_VERSION: u16 = 2;
+
+/// Identifiers of the parameters of the first release, by index, for patches that store their
+/// values by index only.
+pub const FIRST_RELEASE_IDS: [&str; 31] = [
+    "noise_amp",
+    "noise_color",
+    "sine_amp",
+    "sine_octave",
+    "pulse_amp",
+    "pulse_width",
+    "pulse_width_mod_amp",
+    "pulse_width_mod_freq",
+    "sawtooth_amp",
+    "sawtooth_shape",
+    "phase_shift_mod_shape",
+    "phase_shift_amount",
+    "phase_shift_mod_freq",
+    "pitch_mod_shape",
+    "pitch_mod_amp",
+    "pitch_mod_freq",
+    "amp_attack",
+    "amp_decay",
+    "amp_sustain_level",
+    "amp_release",
+    "filter_attack",
+    "filter_decay",
+    "filter_sustain_level",
+    "filter_release",
+    "filter_cutoff",
+    "filter_resonance",
+    "filter_poles",
+    "filter_drive",
+    "filter_cutoff_mod_shape",
+    "filter_cutoff_mod_amp",
+    "filter_cutoff_mod_freq",
+];
+
+/// Index of the parameter with the given identifier, if it still exists.
+pub fn index_of(id: &str) -> Option<usize> {
+    PARAMETERS.iter().position(|info| info.id == id)
+}
+
+/// Identifiers of a list of values saved by index, along with the layout they were saved with.
+/// Only the first release saved exactly 31 values, later versions save at least the 71 parameters
+/// of layout 2.
+pub fn ids_by_index(count: usize) -> (u16, Vec<&'static str>) {
+    if count == FIRST_RELEASE_IDS.len() {
+        (1, FIRST_RELEASE_IDS.to_vec())
+    } else {
+        (2, PARAMETERS.iter().map(|info| info.id).collect())
+    }
+}
+
+/// Map normalized values saved with the given layout version, along with the identifiers of their
+/// parameters, onto the current parameters, by index.
+pub fn migrate<'a>(
+    version: u16,
+    saved: impl IntoIterator<Item = (&'a str, f32)>
+) -> Result<Vec<f32>, PresetError> {
+    if version == 0 || version > LAYOUT_VERSION {
+        return Err(PresetError::UnsupportedVersion(version));
+    }
+    let mut values = default_values();
+    for (id, value) in saved {
+        // parameters that no longer exist are dropped, broken values keep the default
+        let index = match index_of(id) {
+            Some(index) if value.is_finite() => index,
+            _ => continue,
+        };
+        let mut value = value.clamp(0.0, 1.0);
+        if version < 2 {
+            value = upgrade_from_1(index, value);
+        }
+        values[index] = value;
+    }
+    Ok(values)
+}
+
+/// Convert a value saved by the first release, which mapped all values linearly.
+fn upgrade_from_1(index: usize, value: f32) -> f32 {
+    match index {
+        AMP_ATTACK => convert(index, value * 1.0),
+        AMP_DECAY => convert(index, value * 2.0),
+        AMP_RELEASE => convert(index, value * 1.0),
+        // the LFOs took their frequency in radians per second, hertz and hundreds of hertz
+        PULSE_WIDTH_MOD_FREQ => convert(index, value / (2.0 * PI)),
+        PHASE_SHIFT_MOD_FREQ => convert(index, value),
+        PITCH_MOD_FREQ => convert(index, value * 100.0),
+        // the filter had no effect yet, so patches keep sounding the same with it wide open
+        FILTER_ATTACK..=FILTER_CUTOFF_MOD_FREQ => PARAMETERS[index].default_value(),
+        _ => value,
+    }
+}
+
+/// Normalized value of a plain value, limited to the range of the parameter.
+fn convert(index: usize, plain: f32) -> f32 {
+    let info = &PARAMETERS[index];
+    info.normalize(plain.clamp(info.min, info.max)).clamp(0.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dsp::modulation::ModSource;
+    use crate::preset::{ chunk, fx, text, Patch };
+
+    const FIRST_RELEASE_FXP: &[u8] = include_bytes!("../../tests/fixtures/first_release.fxp");
+    const CHUNK_VERSION_1: &[u8] = include_bytes!("../../tests/fixtures/chunk_version_1.bin");
+    const TEXT_WITHOUT_VERSION: &str =
+        include_str!("../../tests/fixtures/text_without_version.toml");
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-4, "{} is not {}", actual, expected);
+    }
+
+    #[test]
+    fn identifiers_are_unique_and_stable() {
+        for (index, info) in PARAMETERS.iter().enumerate() {
+            assert_eq!(index_of(info.id), Some(index));
+        }
+        for (index, id) in FIRST_RELEASE_IDS.iter().enumerate() {
+            assert_eq!(PARAMETERS[index].id, *id);
+        }
+    }
+
+    #[test]
+    fn reads_first_release_programs() {
+        let (patch, setup) = fx::read_fxp(FIRST_RELEASE_FXP, &Patch::default()).unwrap();
+        let plain = |index: usize| PARAMETERS[index].plain(patch.parameters[index]);
+        assert_eq!(patch.name, "Old Brass");
+        assert_eq!(setup, None);
+
+        assert_close(patch.parameters[SAWTOOTH_AMP], 0.8);
+        assert_close(patch.parameters[AMP_SUSTAIN_LEVEL], 0.6);
+        assert_close(plain(AMP_ATTACK), 0.2);
+        assert_close(plain(AMP_DECAY), 1.0);
+        assert_close(plain(AMP_RELEASE), 0.3);
+        assert_close(plain(PULSE_WIDTH_MOD_FREQ), 0.5 / (2.0 * PI));
+        assert_close(plain(PHASE_SHIFT_MOD_FREQ), 0.2);
+        assert_close(plain(PITCH_MOD_FREQ), 5.0);
+
+        // the filter starts out open and parameters added since keep their defaults
+        let defaults = default_values();
+        for index in (FILTER_ATTACK..=FILTER_CUTOFF_MOD_FREQ).chain(SUB_AMP..defaults.len()) {
+            assert_eq!(patch.parameters[index], defaults[index]);
+        }
+    }
+
+    #[test]
+    fn reads_version_1_chunks() {
+        let (patch, _) = chunk::read_preset(CHUNK_VERSION_1).unwrap();
+        assert_eq!(patch.name, "Chunk Version 1");
+        assert_eq!(patch.metadata.author, "Tester");
+        let route = patch.mod_routes[2];
+        assert_eq!(route.source, ModSource::ModWheel);
+        assert_eq!(route.destination, DELAY_MIX);
+        assert_eq!(route.via, ModSource::Velocity);
+
+        // version 1 chunks already had the current layout
+        let glass_table = crate::preset::factory::factory_bank().remove(7);
+        assert_eq!(glass_table.name, "Glass Table");
+        assert_eq!(patch.parameters, glass_table.parameters);
+
+        let data = chunk::write_preset(&patch, &Default::default());
+        assert_eq!(chunk::read_preset(&data).unwrap().0, patch);
+    }
+
+    #[test]
+    fn reads_text_patches_without_version() {
+        let patch = text::read_patch(TEXT_WITHOUT_VERSION).unwrap();
+        let (chunk_patch, _) = chunk::read_preset(CHUNK_VERSION_1).unwrap();
+        assert_eq!(patch.name, "Text Without Version");
+        assert_eq!(patch, Patch { name: patch.name.clone(), ..chunk_patch });
+    }
+
+    #[test]
+    fn refuses_newer_layouts() {
+        match migrate(LAYOUT_VERSION + 1, Vec::new()) {
+            Err(PresetError::UnsupportedVersion(version)) => assert_eq!(version, 3),
+            _ => panic!("accepted a newer layout"),
+        }
+        let newer = text::write_patch(&Patch::default())
+            .replacen("version = 2", "version = 3", 1);
+        assert!(text::read_patch(&newer).is_err());
+    }
+
+    #[test]
+    fn keeps_current_values_and_drops_unknown_ones() {
+        let saved = vec![
+            ("sawtooth_amp", 0.25),
+            ("removed_parameter", 0.5),
+            ("sine_amp", f32::NAN),
+        ];
+        let values = migrate(LAYOUT_VERSION, saved).unwrap();
+        let mut expected = default_values();
+        expected[SAWTOOTH_AMP] = 0.25;
+        assert_eq!(values, expected);
+    }
+}
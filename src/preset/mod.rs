@@ -0,0 +1,113 @@
+//! Patches and their storage.
+//!
+//! A `Patch` holds everything that makes up a sound: the value of every parameter, along with the
+//! state that isn't exposed to the host as parameters, like the modulation matrix and the tuning.
+//! Settings that belong to the setup rather than to the sound, like MPE, oversampling and the
+//! patches in the morph slots, are kept apart in `Setup`, so they stay in place when switching
+//! between patches.
+
+use std::{ fmt, io, path::PathBuf };
+
+use crate::dsp::{
+    effects::{ EffectKind, DEFAULT_EFFECT_ORDER, NUM_EFFECTS },
+    modulation::{ ModRoute, NUM_MOD_SLOTS },
+    morph::NUM_MORPH_SLOTS,
+    mpe::MpeSettings,
+    oversampling::OversamplingSettings,
+};
+use crate::parameters::default_values;
+use crate::tuning::Tuning;
+
+pub mod chunk;
+pub mod factory;
+pub mod fx;
+pub mod migration;
+pub mod random;
+pub mod text;
+
+pub const INIT_PATCH_NAME: &str = "Init";
+
+#[derive(Debug)]
+pub enum PresetError {
+    /// The data ends in the middle of a value.
+    Truncated,
+    /// The data doesn't start with the expected magic number.
+    WrongFormat,
+    /// The data was written by a newer version of the plugin.
+    UnsupportedVersion(u16),
+    Invalid(&'static str),
+    /// A malformed patch file, with the line number and a description of the problem.
+    Syntax(usize, &'static str),
+    /// A preset file carries the unique ID of another plugin.
+    WrongPlugin(i32),
+    Io(io::Error),
+}
+
+impl fmt::Display for PresetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PresetError::Truncated => write!(f, "Preset data is truncated"),
+            PresetError::WrongFormat => write!(f, "Data is not a preset of this plugin"),
+            PresetError::UnsupportedVersion(version) =>
+                write!(f, "Preset format version {} is not supported", version),
+            PresetError::Invalid(message) => write!(f, "Invalid preset: {}", message),
+            PresetError::Syntax(line, message) =>
+                write!(f, "Invalid patch file, line {}: {}", line, message),
+            PresetError::WrongPlugin(id) =>
+                write!(f, "Preset belongs to another plugin with ID {:08x}", id),
+            PresetError::Io(error) => write!(f, "Failed to access preset file: {}", error),
+        }
+    }
+}
+
+impl From<io::Error> for PresetError {
+    fn from(error: io::Error) -> Self {
+        PresetError::Io(error)
+    }
+}
+
+/// Descriptive information about a patch, which doesn't affect the sound.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    pub author: String,
+    pub category: String,
+    pub tags: Vec<String>,
+    pub notes: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Patch {
+    pub name: String,
+    pub metadata: Metadata,
+    /// Normalized value of every parameter, by index.
+    pub parameters: Vec<f32>,
+    pub mod_routes: [ModRoute; NUM_MOD_SLOTS],
+    pub effect_order: [EffectKind; NUM_EFFECTS],
+    /// File the wavetable is loaded from, `None` for the built-in table.
+    pub wavetable_path: Option<PathBuf>,
+    pub tuning: Tuning,
+}
+
+impl Default for Patch {
+    /// The init patch, a plain sawtooth through the open filter.
+    fn default() -> Self {
+        Self {
+            name: INIT_PATCH_NAME.to_string(),
+            metadata: Metadata::default(),
+            parameters: default_values(),
+            mod_routes: [ModRoute::default(); NUM_MOD_SLOTS],
+            effect_order: DEFAULT_EFFECT_ORDER,
+            wavetable_path: None,
+            tuning: Tuning::default(),
+        }
+    }
+}
+
+/// Settings of the plugin instance that are stored with the host project, but not with a patch.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Setup {
+    pub mpe: MpeSettings,
+    pub oversampling: OversamplingSettings,
+    /// Patches loaded into the morph slots A and B.
+    pub morph_slots: [Option<Patch>; NUM_MORPH_SLOTS],
+}
@@ -0,0 +1,279 @@
+//! Random patches for sound design.
+//!
+//! A full randomization draws every parameter from a musically useful range, e.g. envelopes that
+//! don't take seconds to start and filters that aren't closed, and makes sure that at least one
+//! oscillator is heard. A variation only nudges the continuous parameters of a patch by up to a
+//! given percentage of their range, and leaves stepped ones like waveform selectors alone.
+//!
+//! Parameters can be locked one by one or by section, and keep their value either way. The morph
+//! position and everything but the parameters, like the modulation matrix and the tuning, are
+//! never touched. Every run is driven by a seed, so a good result can be reproduced.
+
+use rand::{ rngs::StdRng, Rng, SeedableRng };
+
+use crate::*;
+use crate::parameters::PARAMETERS;
+use crate::preset::Patch;
+
+/// Groups of parameters that can be locked together.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Section {
+    Oscillators,
+    Pan,
+    Modulation,
+    AmpEnvelope,
+    Filter,
+    Distortion,
+    Effects,
+}
+
+impl Section {
+    /// Label of the section's lock in the editor.
+    #[allow(dead_code)]
+    pub fn name(&self) -> &'static str {
+        match self {
+            Section::Oscillators => "Oscillators",
+            Section::Pan => "Pan",
+            Section::Modulation => "Modulation",
+            Section::AmpEnvelope => "Amp envelope",
+            Section::Filter => "Filter",
+            Section::Distortion => "Distortion",
+            Section::Effects => "Effects",
+        }
+    }
+
+    /// Section of a parameter, `None` for the morph position, which isn't randomized.
+    pub fn of(index: usize) -> Option<Self> {
+        Some(match index {
+            PULSE_WIDTH_MOD_AMP | PULSE_WIDTH_MOD_FREQ => Section::Modulation,
+            NOISE_AMP..=SAWTOOTH_SHAPE | SUB_AMP..=SUB_SHAPE => Section::Oscillators,
+            WAVETABLE_AMP | WAVETABLE_POSITION => Section::Oscillators,
+            NOISE_PAN..=SUB_PAN | WAVETABLE_PAN => Section::Pan,
+            PHASE_SHIFT_MOD_SHAPE..=PITCH_MOD_FREQ => Section::Modulation,
+            FILTER_CUTOFF_MOD_SHAPE..=FILTER_CUTOFF_MOD_FREQ => Section::Modulation,
+            PULSE_WIDTH_MOD_SYNC..=LFO_FADE_RISE => Section::Modulation,
+            AMP_ATTACK..=AMP_RELEASE => Section::AmpEnvelope,
+            FILTER_ATTACK..=FILTER_DRIVE => Section::Filter,
+            DISTORTION_SHAPE..=CRUSH_POSITION => Section::Distortion,
+            CHORUS_BYPASS..=REVERB_MIX => Section::Effects,
+            _ => {
+                return None;
+            }
+        })
+    }
+}
+
+/// Every section, in the order the editor lists their locks.
+#[allow(dead_code)]
+pub const SECTIONS: [Section; 7] = [
+    Section::Oscillators,
+    Section::Pan,
+    Section::Modulation,
+    Section::AmpEnvelope,
+    Section::Filter,
+    Section::Distortion,
+    Section::Effects,
+];
+
+/// Plain value ranges that keep random patches playable. Parameters not listed here are drawn
+/// from their full range.
+const USEFUL_RANGES: &[(usize, f32, f32)] = &[
+    (NOISE_AMP, 0.0, 30.0),
+    (PULSE_WIDTH, 10.0, 90.0),
+    (PITCH_MOD_AMP, 0.0, 10.0),
+    (PITCH_MOD_FREQ, 2.0, 8.0),
+    (AMP_ATTACK, 0.001, 0.5),
+    (AMP_DECAY, 0.05, 1.5),
+    (AMP_SUSTAIN_LEVEL, 30.0, 100.0),
+    (AMP_RELEASE, 0.02, 0.8),
+    (FILTER_CUTOFF, 200.0, 12000.0),
+    (FILTER_RESONANCE, 0.0, 70.0),
+    (FILTER_DRIVE, 0.0, 50.0),
+    (FILTER_CUTOFF_MOD_FREQ, 0.05, 8.0),
+    (LFO_FADE_DELAY, 0.0, 0.5),
+    (LFO_FADE_RISE, 0.0, 1.0),
+    (CHORUS_MIX, 0.0, 50.0),
+    (DELAY_FEEDBACK, 0.0, 60.0),
+    (DELAY_MIX, 0.0, 40.0),
+    (REVERB_MIX, 0.0, 40.0),
+    (DISTORTION_DRIVE, 0.0, 40.0),
+    (DISTORTION_MIX, 0.0, 60.0),
+    (CRUSH_BITS, 4.0, 16.0),
+    (CRUSH_RATE, 2000.0, 44100.0),
+    (CRUSH_MIX, 0.0, 30.0),
+];
+
+/// Levels of the oscillators, of which at least one is turned up in a random patch.
+const OSCILLATOR_LEVELS: [usize; 6] =
+    [NOISE_AMP, SINE_AMP, PULSE_AMP, SAWTOOTH_AMP, SUB_AMP, WAVETABLE_AMP];
+/// Normalized level from which an oscillator is considered audible.
+const AUDIBLE_LEVEL: f32 = 0.3;
+
+/// Picked by the editor's randomize controls.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Randomization {
+    /// A new patch, drawn from the useful ranges.
+    Full,
+    /// The current patch, with continuous parameters moved by up to this percentage of their
+    /// range in either direction.
+    Variation(f32),
+}
+
+/// Parameters that keep their value when randomizing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockMask {
+    locked: Vec<bool>,
+}
+
+impl Default for LockMask {
+    fn default() -> Self {
+        Self { locked: vec![false; NUM_PARAMETERS as usize] }
+    }
+}
+
+impl LockMask {
+    pub fn is_locked(&self, index: usize) -> bool {
+        index == MORPH || self.locked.get(index).copied().unwrap_or(true)
+    }
+
+    pub fn set_locked(&mut self, index: usize, locked: bool) {
+        if let Some(lock) = self.locked.get_mut(index) {
+            *lock = locked;
+        }
+    }
+
+    /// Lock or unlock every parameter of a section.
+    pub fn set_section_locked(&mut self, section: Section, locked: bool) {
+        for (index, lock) in self.locked.iter_mut().enumerate() {
+            if Section::of(index) == Some(section) {
+                *lock = locked;
+            }
+        }
+    }
+
+    /// Whether every parameter of a section is locked, for the editor to show.
+    #[allow(dead_code)]
+    pub fn is_section_locked(&self, section: Section) -> bool {
+        (0..self.locked.len())
+            .filter(|&index| Section::of(index) == Some(section))
+            .all(|index| self.locked[index])
+    }
+}
+
+/// Randomize the unlocked parameters of `patch`. The same seed, patch and locks always give the
+/// same result.
+pub fn randomize(patch: &Patch, mode: Randomization, locks: &LockMask, seed: u64) -> Patch {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut patch = patch.clone();
+    for (index, value) in patch.parameters.iter_mut().enumerate() {
+        // every parameter draws a number, so locks don't change the values of the others
+        let random = rng.gen::<f32>();
+        if locks.is_locked(index) {
+            continue;
+        }
+        let info = &PARAMETERS[index];
+        match mode {
+            Randomization::Full => {
+                let (low, high) = useful_range(index);
+                *value = info.quantize(low + (high - low) * random);
+            }
+            Randomization::Variation(percent) if info.steps == 0 => {
+                let offset = (random * 2.0 - 1.0) * percent / 100.0;
+                *value = (*value + offset).clamp(0.0, 1.0);
+            }
+            Randomization::Variation(_) => (),
+        }
+    }
+
+    if mode == Randomization::Full {
+        let levels = &mut patch.parameters;
+        let audible = OSCILLATOR_LEVELS.iter().any(|&index| levels[index] >= AUDIBLE_LEVEL);
+        if !audible && !locks.is_locked(SAWTOOTH_AMP) {
+            levels[SAWTOOTH_AMP] = rng.gen_range(0.5..=1.0);
+        }
+        patch.name = format!("Random {}", seed);
+    }
+    patch
+}
+
+/// Normalized bounds of the useful range of a parameter.
+fn useful_range(index: usize) -> (f32, f32) {
+    let info = &PARAMETERS[index];
+    match USEFUL_RANGES.iter().find(|(parameter, ..)| *parameter == index) {
+        Some(&(_, low, high)) => (info.normalize(low), info.normalize(high)),
+        None => (0.0, 1.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preset::factory::factory_bank;
+
+    #[test]
+    fn seeds_reproduce_patches() {
+        let patch = factory_bank().remove(3);
+        for &mode in [Randomization::Full, Randomization::Variation(10.0)].iter() {
+            let first = randomize(&patch, mode, &LockMask::default(), 42);
+            assert_eq!(randomize(&patch, mode, &LockMask::default(), 42), first);
+            assert_ne!(randomize(&patch, mode, &LockMask::default(), 43), first);
+            assert_eq!(first.mod_routes, patch.mod_routes);
+            assert_eq!(first.parameters[MORPH], patch.parameters[MORPH]);
+        }
+    }
+
+    #[test]
+    fn full_randomization_stays_playable() {
+        let patch = Patch::default();
+        for seed in 0..200 {
+            let random = randomize(&patch, Randomization::Full, &LockMask::default(), seed);
+            let p = &random.parameters;
+            assert!(OSCILLATOR_LEVELS.iter().any(|&index| p[index] >= AUDIBLE_LEVEL));
+            for &(index, low, high) in USEFUL_RANGES.iter() {
+                let plain = PARAMETERS[index].plain(p[index]);
+                assert!(plain >= low * 0.999 && plain <= high * 1.001, "{} {}", index, plain);
+            }
+            for (index, value) in p.iter().enumerate() {
+                assert!((0.0..=1.0).contains(value));
+                assert_eq!(PARAMETERS[index].quantize(*value), *value);
+            }
+        }
+    }
+
+    #[test]
+    fn variations_stay_close() {
+        let patch = factory_bank().remove(6);
+        let varied = randomize(&patch, Randomization::Variation(5.0), &LockMask::default(), 7);
+        let pairs = patch.parameters.iter().zip(varied.parameters.iter());
+        for (index, (before, after)) in pairs.enumerate() {
+            assert!((after - before).abs() <= 0.05 + 1e-6);
+            if PARAMETERS[index].steps > 0 {
+                assert_eq!(after, before);
+            }
+        }
+        assert_eq!(varied.name, patch.name);
+    }
+
+    #[test]
+    fn locked_parameters_keep_their_values() {
+        let patch = factory_bank().remove(9);
+        let mut locks = LockMask::default();
+        locks.set_section_locked(Section::Filter, true);
+        locks.set_locked(PULSE_WIDTH, true);
+        assert!(locks.is_section_locked(Section::Filter));
+        assert!(!locks.is_section_locked(Section::Oscillators));
+
+        let unlocked = randomize(&patch, Randomization::Full, &LockMask::default(), 5);
+        let random = randomize(&patch, Randomization::Full, &locks, 5);
+        for index in 0..patch.parameters.len() {
+            if locks.is_locked(index) {
+                assert_eq!(random.parameters[index], patch.parameters[index]);
+            } else if index != SAWTOOTH_AMP {
+                assert_eq!(random.parameters[index], unlocked.parameters[index]);
+            }
+        }
+        assert!(locks.is_locked(FILTER_CUTOFF));
+        assert!(!locks.is_locked(FILTER_CUTOFF_MOD_AMP));
+    }
+}
@@ -0,0 +1,563 @@
+//! Human-readable patch files.
+//!
+//! Patches are written as a small subset of TOML, with every parameter keyed by its stable
+//! identifier rather than its index, so files diff cleanly and survive parameters being added or
+//! reordered. Parameters hold their normalized value, as seen by the host, as of the layout
+//! version given at the top of the file. Files without a version were written with layout 2 and
+//! key parameters by their display name, which is still understood, also when writing files by
+//! hand. Unknown keys are ignored, and anything missing from a file keeps its init patch value.
+//!
+//! Only what is written here is understood when reading: comments, `key = value` pairs with bare or
+//! quoted keys, `[table]` and `[[array of tables]]` headers, strings, numbers, and arrays of those
+//! on a single line.
+
+use std::path::PathBuf;
+
+use super::{ migration::{ self, LAYOUT_VERSION }, Patch, PresetError };
+use crate::dsp::{
+    effects::{ is_valid_effect_order, EffectKind, NUM_EFFECTS },
+    modulation::{ ModRoute, ModSource, NUM_MOD_SLOTS },
+};
+use crate::parameters::PARAMETERS;
+use crate::tuning::{ Tuning, TuningError };
+
+/// Scale degree placeholder for unmapped keys, as in Scala keyboard mappings.
+const UNMAPPED_KEY: &str = "x";
+
+pub fn write_patch(patch: &Patch) -> String {
+    let mut text = String::new();
+    let metadata = &patch.metadata;
+    line(&mut text, "version", &LAYOUT_VERSION.to_string());
+    line(&mut text, "name", &quote(&patch.name));
+    line(&mut text, "author", &quote(&metadata.author));
+    line(&mut text, "category", &quote(&metadata.category));
+    line(&mut text, "tags", &array(metadata.tags.iter().map(|tag| quote(tag))));
+    line(&mut text, "notes", &quote(&metadata.notes));
+    if let Some(path) = &patch.wavetable_path {
+        line(&mut text, "wavetable", &quote(&path.to_string_lossy()));
+    }
+    let effect_order = patch.effect_order.iter().map(|kind| quote(kind.name()));
+    line(&mut text, "effect_order", &array(effect_order));
+
+    text.push_str("\n[parameters]\n");
+    for (info, value) in PARAMETERS.iter().zip(patch.parameters.iter()) {
+        line(&mut text, info.id, &value.to_string());
+    }
+
+    for route in patch.mod_routes.iter().filter(|route| route.source != ModSource::None) {
+        text.push_str("\n[[modulation]]\n");
+        line(&mut text, "source", &quote(route.source.name()));
+        line(&mut text, "destination", &quote(PARAMETERS[route.destination].id));
+        line(&mut text, "amount", &route.amount.to_string());
+        line(&mut text, "via", &quote(route.via.name()));
+    }
+
+    let scale = &patch.tuning.scale;
+    let keyboard = &patch.tuning.keyboard;
+    text.push_str("\n[tuning]\n");
+    line(&mut text, "description", &quote(&scale.description));
+    line(&mut text, "cents", &array(scale.cents.iter().map(|cents| cents.to_string())));
+    line(&mut text, "first_note", &keyboard.first_note.to_string());
+    line(&mut text, "last_note", &keyboard.last_note.to_string());
+    line(&mut text, "middle_note", &keyboard.middle_note.to_string());
+    line(&mut text, "reference_note", &keyboard.reference_note.to_string());
+    line(&mut text, "reference_freq", &keyboard.reference_freq.to_string());
+    line(&mut text, "octave_degree", &keyboard.octave_degree.to_string());
+    let mapping = keyboard.mapping.iter().map(|degree| match degree {
+        Some(degree) => degree.to_string(),
+        None => quote(UNMAPPED_KEY),
+    });
+    line(&mut text, "mapping", &array(mapping));
+    text
+}
+
+pub fn read_patch(text: &str) -> Result<Patch, PresetError> {
+    let mut patch = Patch::default();
+    let mut version = LAYOUT_VERSION;
+    let mut parameters = Vec::new();
+    for table in parse(text)? {
+        match table.name.as_str() {
+            "" => version = read_root(&table, &mut patch)?,
+            "parameters" => {
+                // parameters that were removed or renamed are skipped
+                for entry in table.entries.iter() {
+                    if let Some(index) = parameter(&entry.key) {
+                        parameters.push((PARAMETERS[index].id, entry.number()?));
+                    }
+                }
+            }
+            "modulation" => {
+                let routes = patch.mod_routes.iter();
+                let slot = routes.take_while(|route| route.source != ModSource::None).count();
+                if slot >= NUM_MOD_SLOTS {
+                    return Err(PresetError::Syntax(table.line, "too many modulation routes"));
+                }
+                patch.mod_routes[slot] = read_route(&table)?;
+            }
+            "tuning" => {
+                patch.tuning = read_tuning(&table)?;
+            }
+            _ => (),
+        }
+    }
+    patch.parameters = migration::migrate(version, parameters)?;
+    Ok(patch)
+}
+
+/// Read the unnamed root table, returning the layout version of the file.
+fn read_root(table: &Table, patch: &mut Patch) -> Result<u16, PresetError> {
+    let mut version = LAYOUT_VERSION;
+    for entry in table.entries.iter() {
+        match entry.key.as_str() {
+            "version" => version = entry.number()?,
+            "name" => patch.name = entry.string()?,
+            "author" => patch.metadata.author = entry.string()?,
+            "category" => patch.metadata.category = entry.string()?,
+            "tags" => {
+                let tags = entry.array()?.iter().map(|value| value.string(entry.line));
+                patch.metadata.tags = tags.collect::<Result<_, _>>()?;
+            }
+            "notes" => patch.metadata.notes = entry.string()?,
+            "wavetable" => patch.wavetable_path = Some(PathBuf::from(entry.string()?)),
+            "effect_order" => {
+                let mut order = patch.effect_order;
+                let kinds = entry.array()?;
+                if kinds.len() != NUM_EFFECTS {
+                    return Err(PresetError::Syntax(entry.line, "incomplete effect order"));
+                }
+                for (kind, value) in order.iter_mut().zip(kinds.iter()) {
+                    let name = value.string(entry.line)?;
+                    *kind = find(&name, EffectKind::from_index, EffectKind::name).ok_or(
+                        PresetError::Syntax(entry.line, "unknown effect")
+                    )?;
+                }
+                if !is_valid_effect_order(&order) {
+                    return Err(PresetError::Syntax(entry.line, "effects appear more than once"));
+                }
+                patch.effect_order = order;
+            }
+            _ => (),
+        }
+    }
+    Ok(version)
+}
+
+fn read_route(table: &Table) -> Result<ModRoute, PresetError> {
+    let mut route = ModRoute::default();
+    for entry in table.entries.iter() {
+        match entry.key.as_str() {
+            "source" => route.source = mod_source(entry)?,
+            "via" => route.via = mod_source(entry)?,
+            "destination" => {
+                route.destination = parameter(&entry.string()?).ok_or(
+                    PresetError::Syntax(entry.line, "unknown modulation destination")
+                )?;
+            }
+            "amount" => route.amount = entry.number::<f32>()?.clamp(-1.0, 1.0),
+            _ => (),
+        }
+    }
+    Ok(route)
+}
+
+fn read_tuning(table: &Table) -> Result<Tuning, PresetError> {
+    let Tuning { mut scale, mut keyboard } = Tuning::default();
+    for entry in table.entries.iter() {
+        match entry.key.as_str() {
+            "description" => scale.description = entry.string()?,
+            "cents" => {
+                let cents = entry.array()?.iter().map(|value| value.number(entry.line));
+                scale.cents = cents.collect::<Result<_, _>>()?;
+            }
+            "first_note" => keyboard.first_note = entry.number()?,
+            "last_note" => keyboard.last_note = entry.number()?,
+            "middle_note" => keyboard.middle_note = entry.number()?,
+            "reference_note" => keyboard.reference_note = entry.number()?,
+            "reference_freq" => keyboard.reference_freq = entry.number()?,
+            "octave_degree" => keyboard.octave_degree = entry.number()?,
+            "mapping" => {
+                let mut mapping = Vec::new();
+                for value in entry.array()? {
+                    mapping.push(match value {
+                        Value::String(text) if text == UNMAPPED_KEY => None,
+                        value => Some(value.number(entry.line)?),
+                    });
+                }
+                keyboard.mapping = mapping;
+            }
+            _ => (),
+        }
+    }
+
+    Tuning::new(scale, keyboard).map_err(|error| match error {
+        TuningError::Invalid(message) => PresetError::Syntax(table.line, message),
+        _ => PresetError::Syntax(table.line, "the reference note of the tuning is unmapped"),
+    })
+}
+
+fn mod_source(entry: &Entry) -> Result<ModSource, PresetError> {
+    find(&entry.string()?, ModSource::from_index, ModSource::name).ok_or(
+        PresetError::Syntax(entry.line, "unknown modulation source")
+    )
+}
+
+/// Index of the parameter with the given identifier or display name.
+fn parameter(key: &str) -> Option<usize> {
+    migration::index_of(key).or_else(|| PARAMETERS.iter().position(|info| info.name == key))
+}
+
+/// Look up an enum variant by its name, given the conversion from its index.
+fn find<T>(name: &str, from_index: fn(usize) -> Option<T>, name_of: fn(&T) -> &str) -> Option<T> {
+    (0..)
+        .map(from_index)
+        .take_while(Option::is_some)
+        .flatten()
+        .find(|variant| name_of(variant) == name)
+}
+
+fn line(text: &mut String, key: &str, value: &str) {
+    text.push_str(key);
+    text.push_str(" = ");
+    text.push_str(value);
+    text.push('\n');
+}
+
+fn quote(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04X}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn array(values: impl Iterator<Item = String>) -> String {
+    format!("[{}]", values.collect::<Vec<_>>().join(", "))
+}
+
+enum Value {
+    String(String),
+    /// Numbers are kept as written, so they can be parsed with the precision of their use.
+    Number(String),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn string(&self, line: usize) -> Result<String, PresetError> {
+        match self {
+            Value::String(text) => Ok(text.clone()),
+            _ => Err(PresetError::Syntax(line, "expected a string")),
+        }
+    }
+
+    fn number<T: std::str::FromStr>(&self, line: usize) -> Result<T, PresetError> {
+        match self {
+            Value::Number(text) =>
+                text.parse().map_err(|_| PresetError::Syntax(line, "number out of range")),
+            _ => Err(PresetError::Syntax(line, "expected a number")),
+        }
+    }
+}
+
+struct Entry {
+    key: String,
+    value: Value,
+    line: usize,
+}
+
+impl Entry {
+    fn string(&self) -> Result<String, PresetError> {
+        self.value.string(self.line)
+    }
+
+    fn number<T: std::str::FromStr>(&self) -> Result<T, PresetError> {
+        self.value.number(self.line)
+    }
+
+    fn array(&self) -> Result<&[Value], PresetError> {
+        match &self.value {
+            Value::Array(values) => Ok(values),
+            _ => Err(PresetError::Syntax(self.line, "expected an array")),
+        }
+    }
+}
+
+/// The entries following a table header, or those at the start of the file for the unnamed root
+/// table. Every header of an array of tables starts a table of its own.
+struct Table {
+    name: String,
+    entries: Vec<Entry>,
+    line: usize,
+}
+
+fn parse(text: &str) -> Result<Vec<Table>, PresetError> {
+    let mut tables = vec![Table { name: String::new(), entries: Vec::new(), line: 1 }];
+    for (index, text) in text.lines().enumerate() {
+        let mut cursor = Cursor { rest: text, line: index + 1 };
+        if cursor.at_end() {
+            continue;
+        }
+
+        if cursor.eat("[") {
+            let array = cursor.eat("[");
+            let name = cursor.key()?;
+            cursor.expect("]")?;
+            if array {
+                cursor.expect("]")?;
+            }
+            tables.push(Table { name, entries: Vec::new(), line: cursor.line });
+        } else {
+            let key = cursor.key()?;
+            cursor.expect("=")?;
+            let value = cursor.value()?;
+            let table = tables.last_mut().unwrap();
+            table.entries.push(Entry { key, value, line: cursor.line });
+        }
+
+        if !cursor.at_end() {
+            return Err(PresetError::Syntax(cursor.line, "unexpected text at the end of the line"));
+        }
+    }
+    Ok(tables)
+}
+
+/// Position within a single line of a patch file.
+struct Cursor<'a> {
+    rest: &'a str,
+    line: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn error(&self, message: &'static str) -> PresetError {
+        PresetError::Syntax(self.line, message)
+    }
+
+    /// Whether only whitespace and comments are left.
+    fn at_end(&mut self) -> bool {
+        self.rest = self.rest.trim_start();
+        self.rest.is_empty() || self.rest.starts_with('#')
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.rest = self.rest.trim_start();
+        match self.rest.strip_prefix(token) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn expect(&mut self, token: &'static str) -> Result<(), PresetError> {
+        if self.eat(token) { Ok(()) } else { Err(self.error("missing bracket or equals sign")) }
+    }
+
+    fn key(&mut self) -> Result<String, PresetError> {
+        self.rest = self.rest.trim_start();
+        if self.rest.starts_with('"') {
+            return self.string();
+        }
+        let length = self.rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+            .unwrap_or(self.rest.len());
+        if length == 0 {
+            return Err(self.error("missing key"));
+        }
+        let (key, rest) = self.rest.split_at(length);
+        self.rest = rest;
+        Ok(key.to_string())
+    }
+
+    fn value(&mut self) -> Result<Value, PresetError> {
+        self.rest = self.rest.trim_start();
+        if self.rest.starts_with('"') {
+            return Ok(Value::String(self.string()?));
+        }
+        if self.eat("[") {
+            let mut values = Vec::new();
+            while !self.eat("]") {
+                values.push(self.value()?);
+                if !self.eat(",") {
+                    self.expect("]")?;
+                    break;
+                }
+            }
+            return Ok(Value::Array(values));
+        }
+
+        let length = self.rest
+            .find(|c: char| c.is_whitespace() || c == ',' || c == ']' || c == '#')
+            .unwrap_or(self.rest.len());
+        let (number, rest) = self.rest.split_at(length);
+        if number.parse::<f64>().is_err() {
+            return Err(self.error("expected a string, number or array"));
+        }
+        self.rest = rest;
+        Ok(Value::Number(number.to_string()))
+    }
+
+    fn string(&mut self) -> Result<String, PresetError> {
+        let mut chars = self.rest[1..].char_indices();
+        let mut text = String::new();
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.rest = &self.rest[index + 2..];
+                    return Ok(text);
+                }
+                '\\' =>
+                    text.push(match chars.next() {
+                        Some((_, '"')) => '"',
+                        Some((_, '\\')) => '\\',
+                        Some((_, 'n')) => '\n',
+                        Some((_, 't')) => '\t',
+                        Some((_, 'r')) => '\r',
+                        Some((_, 'u')) => {
+                            let digits: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                            u32::from_str_radix(&digits, 16)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .ok_or(self.error("invalid unicode escape"))?
+                        }
+                        _ => {
+                            return Err(self.error("invalid escape sequence"));
+                        }
+                    }),
+                c => text.push(c),
+            }
+        }
+        Err(self.error("unterminated string"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use crate::preset::factory::factory_bank;
+    use crate::tuning::scala::{ self, Scale };
+
+    fn test_patch() -> Patch {
+        let mut patch = factory_bank().remove(1);
+        patch.metadata.tags = vec!["acid".to_string(), "quote \" and \\".to_string()];
+        patch.metadata.notes = "Two\nlines\tand a bell \u{7}".to_string();
+        patch.wavetable_path = Some(PathBuf::from("tables/Ünïcödé.wav"));
+        patch.effect_order = [EffectKind::Reverb, EffectKind::Chorus, EffectKind::Delay];
+        patch.mod_routes[2] = ModRoute {
+            source: ModSource::Slide,
+            destination: WAVETABLE_POSITION,
+            amount: -0.123_456_79,
+            via: ModSource::Aftertouch,
+        };
+        patch.tuning.scale = Scale { description: "Just".to_string(), cents: vec![203.91, 1200.0] };
+        patch.tuning.keyboard.mapping = vec![Some(0), None, Some(1)];
+        patch.tuning.keyboard.reference_freq = 432.1;
+        patch
+    }
+
+    /// Parameters are told apart by their names alone in files without a version.
+    #[test]
+    fn parameter_names_are_unique() {
+        for (index, info) in PARAMETERS.iter().enumerate() {
+            let unique = PARAMETERS[..index].iter().all(|other| other.name != info.name);
+            assert!(unique, "{}", info.name);
+        }
+    }
+
+    #[test]
+    fn patches_round_trip() {
+        let patch = test_patch();
+        let text = write_patch(&patch);
+        let read = read_patch(&text).unwrap();
+        assert_eq!(read, patch);
+        assert_eq!(write_patch(&read), text);
+    }
+
+    #[test]
+    fn reads_hand_written_patches() {
+        let text = r#"
+            # parameters are found by identifier or name in any order, unknown ones are skipped
+            name = "Hand made"   # trailing comment
+            tags = [ "a", "b", ]
+
+            [parameters]
+            filter_resonance = 0.5
+            "Removed parameter" = 1
+            "Cutoff" = 2.5
+
+            [[modulation]]
+            source = "Velocity"
+            destination = "Resonance"
+            amount = 0.25
+        "#;
+
+        let patch = read_patch(text).unwrap();
+        assert_eq!(patch.name, "Hand made");
+        assert_eq!(patch.metadata.tags, vec!["a", "b"]);
+        assert_eq!(patch.parameters[FILTER_RESONANCE], 0.5);
+        assert_eq!(patch.parameters[FILTER_CUTOFF], 1.0);
+        assert_eq!(patch.parameters[SAWTOOTH_AMP], Patch::default().parameters[SAWTOOTH_AMP]);
+        assert_eq!(patch.mod_routes[0].source, ModSource::Velocity);
+        assert_eq!(patch.mod_routes[0].destination, FILTER_RESONANCE);
+        assert_eq!(patch.mod_routes[0].via, ModSource::None);
+        assert_eq!(patch.tuning, Tuning::default());
+    }
+
+    #[test]
+    fn reports_the_line_of_errors() {
+        let cases = [
+            ("name = \"unterminated", 1),
+            ("\n\n[parameters\n", 3),
+            ("\nname = 12", 2),
+            ("[parameters]\n\"Cutoff\" = \"high\"", 2),
+            ("effect_order = [\"Chorus\", \"Chorus\", \"Delay\"]", 1),
+            ("\n[[modulation]]\nsource = \"Sunshine\"", 3),
+            ("tags = [\"a\" \"b\"]", 1),
+        ];
+        for (text, line) in cases.iter() {
+            match read_patch(text) {
+                Err(PresetError::Syntax(error, _)) => assert_eq!(error, *line, "{}", text),
+                _ => panic!("accepted {}", text),
+            }
+        }
+    }
+
+    /// Hand-edited tunings are held to the same limits as those in preset chunks.
+    #[test]
+    fn rejects_unplayable_tunings() {
+        let many = |count: usize| vec!["100.0"; count].join(", ");
+        let tunings = [
+            "cents = []".to_string(),
+            "cents = [100, inf, 1200]".to_string(),
+            "cents = [100, 1e400, 1200]".to_string(),
+            format!("cents = [{}]", many(scala::MAX_SCALE_SIZE + 1)),
+            "mapping = [2147483647]".to_string(),
+            "mapping = [0, -2147483648]".to_string(),
+            "octave_degree = 2147483647".to_string(),
+            format!("mapping = [{}]", vec!["0"; scala::MAX_MAP_SIZE + 1].join(", ")),
+            "reference_note = 128".to_string(),
+            "first_note = 200".to_string(),
+            "reference_freq = 0".to_string(),
+            "mapping = [\"x\"]".to_string(),
+        ];
+        for tuning in tunings.iter() {
+            let text = format!("name = \"Tuned\"\n\n[tuning]\n{}\n", tuning);
+            match read_patch(&text) {
+                Err(PresetError::Syntax(line, _)) => assert_eq!(line, 3, "{}", tuning),
+                result => panic!("{} gave {:?}", tuning, result.map(|patch| patch.tuning)),
+            }
+        }
+
+        let text = format!("[tuning]\ncents = [{}]\nmapping = [0, 1, \"x\"]", many(12));
+        assert!(read_patch(&text).is_ok());
+    }
+}
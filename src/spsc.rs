@@ -0,0 +1,152 @@
+//! Bounded single-producer single-consumer queue, which hands events over to the audio processing
+//! thread without locking or allocating.
+//!
+//! The queue is a ring of preallocated slots. The producer only ever advances the head and the
+//! consumer only ever advances the tail, so each side can work with plain atomic loads and stores.
+//! A full queue rejects new items instead of growing, and either side keeps working after the
+//! other one was dropped.
+
+use std::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::{ atomic::{ AtomicUsize, Ordering }, Arc },
+};
+
+struct Ring<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    /// Number of items pushed so far, only written by the producer.
+    head: AtomicUsize,
+    /// Number of items popped so far, only written by the consumer.
+    tail: AtomicUsize,
+}
+
+// Every slot is either written by the producer or read by the consumer, never both at once.
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+impl<T> Ring<T> {
+    fn slot(&self, position: usize) -> *mut MaybeUninit<T> {
+        self.slots[position % self.slots.len()].get()
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        let head = *self.head.get_mut();
+        let mut tail = *self.tail.get_mut();
+        while tail != head {
+            unsafe { (*self.slot(tail)).assume_init_drop() };
+            tail = tail.wrapping_add(1);
+        }
+    }
+}
+
+/// Sending end of a queue created with `channel`.
+pub struct Producer<T> {
+    ring: Arc<Ring<T>>,
+}
+
+/// Receiving end of a queue created with `channel`.
+pub struct Consumer<T> {
+    ring: Arc<Ring<T>>,
+}
+
+/// Create a queue that holds up to `capacity` items.
+pub fn channel<T: Send>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    assert!(capacity > 0, "queue capacity must not be zero");
+    let ring = Arc::new(Ring {
+        slots: (0..capacity).map(|_| UnsafeCell::new(MaybeUninit::uninit())).collect(),
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+    });
+    (Producer { ring: Arc::clone(&ring) }, Consumer { ring })
+}
+
+impl<T> Producer<T> {
+    /// Append an item, or hand it back if the queue is full.
+    pub fn push(&mut self, item: T) -> Result<(), T> {
+        let ring = &*self.ring;
+        let head = ring.head.load(Ordering::Relaxed);
+        if head.wrapping_sub(ring.tail.load(Ordering::Acquire)) == ring.slots.len() {
+            return Err(item);
+        }
+        unsafe { (*ring.slot(head)).write(item) };
+        ring.head.store(head.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+}
+
+impl<T> Consumer<T> {
+    /// Take the oldest item, if there is one.
+    pub fn pop(&mut self) -> Option<T> {
+        let ring = &*self.ring;
+        let tail = ring.tail.load(Ordering::Relaxed);
+        if tail == ring.head.load(Ordering::Acquire) {
+            return None;
+        }
+        let item = unsafe { (*ring.slot(tail)).assume_init_read() };
+        ring.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn rejects_items_when_full() {
+        let (mut producer, mut consumer) = channel(2);
+        assert_eq!(producer.push(1), Ok(()));
+        assert_eq!(producer.push(2), Ok(()));
+        assert_eq!(producer.push(3), Err(3));
+        assert_eq!(consumer.pop(), Some(1));
+        assert_eq!(producer.push(3), Ok(()));
+        assert_eq!(consumer.pop(), Some(2));
+        assert_eq!(consumer.pop(), Some(3));
+        assert_eq!(consumer.pop(), None);
+    }
+
+    #[test]
+    fn drops_items_left_in_the_queue() {
+        let item = Arc::new(());
+        let (mut producer, mut consumer) = channel(4);
+        for _ in 0..3 {
+            producer.push(Arc::clone(&item)).unwrap();
+        }
+        drop(consumer.pop());
+        drop(consumer);
+        assert_eq!(Arc::strong_count(&item), 3);
+        assert!(producer.push(Arc::clone(&item)).is_ok());
+        drop(producer);
+        assert_eq!(Arc::strong_count(&item), 1);
+    }
+
+    /// Items arrive in order, each exactly once, while both threads race around a small ring.
+    #[test]
+    fn passes_items_between_threads() {
+        const COUNT: usize = 200_000;
+        let (mut producer, mut consumer) = channel(16);
+        let sender = thread::spawn(move || {
+            for mut item in 0..COUNT {
+                while let Err(rejected) = producer.push(item) {
+                    item = rejected;
+                    thread::yield_now();
+                }
+            }
+        });
+
+        let mut expected = 0;
+        while expected < COUNT {
+            match consumer.pop() {
+                Some(item) => {
+                    assert_eq!(item, expected);
+                    expected += 1;
+                }
+                None => thread::yield_now(),
+            }
+        }
+        sender.join().unwrap();
+        assert_eq!(consumer.pop(), None);
+    }
+}
@@ -0,0 +1,176 @@
+//! Microtuning.
+//!
+//! A tuning combines a Scala scale with a keyboard mapping, which places the scale on the MIDI
+//! keys and sets the reference pitch. Both default to 12-tone equal temperament with A4 at 440 Hz.
+//! Since evaluating a tuning is expensive, the frequency of every MIDI note is precomputed into a
+//! `TuningTable` whenever the tuning changes, and the audio thread only ever reads from the table.
+//! MIDI Tuning Standard messages retune that table directly.
+
+use std::{ fmt, io };
+
+pub mod mts;
+pub mod scala;
+use scala::{ KeyboardMapping, Scale, MAX_MAP_SIZE, MAX_SCALE_SIZE };
+
+pub const NUM_NOTES: usize = 128;
+
+#[derive(Debug)]
+pub enum TuningError {
+    Io(io::Error),
+    /// A malformed file, with the line number and a description of the problem.
+    Syntax(usize, &'static str),
+    /// A scale or keyboard mapping with values the synth can't play, with a description of the
+    /// problem.
+    Invalid(&'static str),
+    /// The reference note of the keyboard mapping has no scale degree assigned.
+    UnmappedReference,
+}
+
+impl fmt::Display for TuningError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TuningError::Io(error) => write!(f, "Failed to read tuning file: {}", error),
+            TuningError::Syntax(line, message) =>
+                write!(f, "Invalid tuning file, line {}: {}", line, message),
+            TuningError::Invalid(message) => write!(f, "Invalid tuning: {}", message),
+            TuningError::UnmappedReference =>
+                write!(f, "The reference note of the keyboard mapping is unmapped"),
+        }
+    }
+}
+
+impl From<io::Error> for TuningError {
+    fn from(error: io::Error) -> Self {
+        TuningError::Io(error)
+    }
+}
+
+/// Frequency of every MIDI note in Hz. Unmapped notes have a frequency of 0 and are not played.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TuningTable {
+    frequencies: [f32; NUM_NOTES],
+}
+
+impl Default for TuningTable {
+    fn default() -> Self {
+        Tuning::default().table()
+    }
+}
+
+impl TuningTable {
+    pub fn frequency(&self, note: u8) -> f32 {
+        self.frequencies[note as usize % NUM_NOTES]
+    }
+
+    pub fn set_frequency(&mut self, note: u8, frequency: f32) {
+        self.frequencies[note as usize % NUM_NOTES] = frequency;
+    }
+
+    pub fn is_mapped(&self, note: u8) -> bool {
+        self.frequency(note) > 0.0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tuning {
+    pub scale: Scale,
+    pub keyboard: KeyboardMapping,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Self { scale: Scale::equal_temperament(), keyboard: KeyboardMapping::default() }
+    }
+}
+
+impl Tuning {
+    /// Combine a scale and a keyboard mapping, checking that both are within the supported limits
+    /// and that the reference note can be tuned. Every way of loading a tuning goes through here.
+    pub fn new(scale: Scale, keyboard: KeyboardMapping) -> Result<Self, TuningError> {
+        if scale.cents.is_empty() {
+            return Err(TuningError::Invalid("scale has no notes"));
+        }
+        if scale.cents.len() > MAX_SCALE_SIZE {
+            return Err(TuningError::Invalid("scale has too many notes"));
+        }
+        if !scale.cents.iter().all(|cents| cents.is_finite()) {
+            return Err(TuningError::Invalid("invalid pitch"));
+        }
+
+        if keyboard.mapping.len() > MAX_MAP_SIZE {
+            return Err(TuningError::Invalid("keyboard mapping too large"));
+        }
+        let notes = [
+            keyboard.first_note,
+            keyboard.last_note,
+            keyboard.middle_note,
+            keyboard.reference_note,
+        ];
+        if notes.iter().any(|&note| note as usize >= NUM_NOTES) {
+            return Err(TuningError::Invalid("note out of range"));
+        }
+        let octave = Some(&keyboard.octave_degree);
+        let mut degrees = keyboard.mapping.iter().flatten().chain(octave);
+        if !degrees.all(|&degree| scala::is_degree(f64::from(degree))) {
+            return Err(TuningError::Invalid("scale degree out of range"));
+        }
+        if !keyboard.reference_freq.is_finite() || keyboard.reference_freq <= 0.0 {
+            return Err(TuningError::Invalid("invalid reference frequency"));
+        }
+
+        if keyboard.key_degree(keyboard.reference_note, scale.cents.len()).is_none() {
+            return Err(TuningError::UnmappedReference);
+        }
+        Ok(Self { scale, keyboard })
+    }
+
+    pub fn set_reference_pitch(&mut self, freq: f64) {
+        if freq > 0.0 {
+            self.keyboard.reference_freq = freq;
+        }
+    }
+
+    pub fn table(&self) -> TuningTable {
+        let size = self.scale.cents.len();
+        let cents = |note| {
+            self.keyboard.key_degree(note, size).map(|degree| self.scale.degree_cents(degree))
+        };
+        let reference = cents(self.keyboard.reference_note).unwrap_or(0.0);
+
+        let mut frequencies = [0.0; NUM_NOTES];
+        for (note, frequency) in frequencies.iter_mut().enumerate() {
+            if let Some(cents) = cents(note as u8) {
+                let ratio = ((cents - reference) / 1200.0).exp2();
+                *frequency = (self.keyboard.reference_freq * ratio) as f32;
+            }
+            // far out pitches can't be played
+            if !frequency.is_finite() || *frequency <= 0.0 {
+                *frequency = 0.0;
+            }
+        }
+        TuningTable { frequencies }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pitches too far out for an `f32` frequency leave their notes unmapped.
+    #[test]
+    fn leaves_unplayable_notes_unmapped() {
+        let cents = vec![1e6, -1e6, 1e300, 1200.0];
+        let scale = Scale { description: "Wide".to_string(), cents };
+        let keyboard = KeyboardMapping { reference_note: 60, ..KeyboardMapping::default() };
+        let tuning = Tuning::new(scale, keyboard).unwrap();
+        let table = tuning.table();
+        for note in 0..NUM_NOTES as u8 {
+            let frequency = table.frequency(note);
+            assert!(frequency.is_finite() && frequency >= 0.0, "note {}: {}", note, frequency);
+        }
+        assert!(!table.is_mapped(61));
+        assert!(!table.is_mapped(62));
+        assert!(!table.is_mapped(63));
+        assert!(table.is_mapped(64));
+    }
+}
@@ -0,0 +1,243 @@
+//! MIDI Tuning Standard system exclusive messages.
+//!
+//! Single note tuning changes (with and without bank select), bulk tuning dumps and scale/octave
+//! tuning in the 1 and 2 byte forms are supported, in both their realtime and non-realtime
+//! variants. Messages are applied directly to the tuning table of the audio thread, so notes that
+//! are already sounding are retuned immediately. The synth holds a single tuning, so device IDs,
+//! tuning programs and banks are ignored, as are channel masks as long as they address any
+//! channel. Scale/octave tunings only retune the notes the keyboard mapping plays, while single
+//! note changes and bulk dumps set exactly the notes they list.
+//!
+//! MTS retuning is a live performance control driven by external tools, and isn't stored with the
+//! patch. The next change of the patch's tuning replaces it.
+
+use super::{ TuningTable, NUM_NOTES };
+
+const SYSEX_START: u8 = 0xf0;
+const SYSEX_END: u8 = 0xf7;
+const UNIVERSAL_NON_REALTIME: u8 = 0x7e;
+const UNIVERSAL_REALTIME: u8 = 0x7f;
+const MIDI_TUNING: u8 = 0x08;
+
+const BULK_DUMP: u8 = 0x01;
+const SINGLE_NOTE: u8 = 0x02;
+const SINGLE_NOTE_BANK: u8 = 0x07;
+const SCALE_OCTAVE_1_BYTE: u8 = 0x08;
+const SCALE_OCTAVE_2_BYTE: u8 = 0x09;
+
+/// Frequency data meaning "leave this note unchanged".
+const NO_CHANGE: [u8; 3] = [0x7f, 0x7f, 0x7f];
+
+/// Length of the tuning name in a bulk dump.
+const NAME_LENGTH: usize = 16;
+
+/// Frequency of 12-tone equal temperament, which scale/octave tunings are relative to.
+fn equal_temperament(semitones: f64) -> f64 {
+    440.0 * ((semitones - 69.0) / 12.0).exp2()
+}
+
+/// Apply a tuning message to `table`. Returns `false` for anything other than a supported and
+/// well formed MTS message.
+pub fn apply_sysex(table: &mut TuningTable, payload: &[u8]) -> bool {
+    let data = payload.strip_prefix(&[SYSEX_START]).unwrap_or(payload);
+    let data = data.strip_suffix(&[SYSEX_END]).unwrap_or(data);
+    // data bytes never have the high bit set
+    if data.iter().any(|&byte| byte & 0x80 != 0) {
+        return false;
+    }
+
+    match data {
+        [UNIVERSAL_NON_REALTIME | UNIVERSAL_REALTIME, _device, MIDI_TUNING, message @ ..] =>
+            match message {
+                [BULK_DUMP, _program, dump @ ..] => apply_bulk_dump(table, data, dump),
+                [SINGLE_NOTE, _program, count, changes @ ..] |
+                [SINGLE_NOTE_BANK, _, _program, count, changes @ ..] => {
+                    apply_note_changes(table, *count as usize, changes)
+                }
+                [SCALE_OCTAVE_1_BYTE, ff, gg, hh, offsets @ ..] if
+                    offsets.len() >= 12 && addresses_channel([*ff, *gg, *hh])
+                => {
+                    let mut cents = [0.0; 12];
+                    for (cents, &offset) in cents.iter_mut().zip(offsets.iter()) {
+                        *cents = f64::from(offset) - 64.0;
+                    }
+                    apply_octave(table, &cents);
+                    true
+                }
+                [SCALE_OCTAVE_2_BYTE, ff, gg, hh, offsets @ ..] if
+                    offsets.len() >= 24 && addresses_channel([*ff, *gg, *hh])
+                => {
+                    let mut cents = [0.0; 12];
+                    for (cents, offset) in cents.iter_mut().zip(offsets.chunks_exact(2)) {
+                        let value = (u16::from(offset[0]) << 7) | u16::from(offset[1]);
+                        *cents = ((f64::from(value) - 8192.0) / 8192.0) * 100.0;
+                    }
+                    apply_octave(table, &cents);
+                    true
+                }
+                _ => false,
+            }
+        _ => false,
+    }
+}
+
+/// Whether the channel mask of a scale/octave message selects any channel.
+fn addresses_channel(masks: [u8; 3]) -> bool {
+    masks.iter().any(|&mask| mask & 0x7f != 0)
+}
+
+/// Retune `count` notes, each given as a key followed by three bytes of frequency data.
+fn apply_note_changes(table: &mut TuningTable, count: usize, changes: &[u8]) -> bool {
+    if changes.len() < count * 4 {
+        return false;
+    }
+    for change in changes.chunks_exact(4).take(count) {
+        set_frequency(table, change[0], [change[1], change[2], change[3]]);
+    }
+    true
+}
+
+/// Retune every note from a bulk dump, which holds the tuning name, the frequency data of all
+/// notes and a checksum over the whole message. `message` is the message without the SysEx
+/// framing, and `dump` the part of it following the tuning program.
+fn apply_bulk_dump(table: &mut TuningTable, message: &[u8], dump: &[u8]) -> bool {
+    if dump.len() != NAME_LENGTH + NUM_NOTES * 3 + 1 {
+        return false;
+    }
+    let (checked, checksum) = message.split_at(message.len() - 1);
+    if checked.iter().fold(0, |sum, byte| sum ^ byte) & 0x7f != checksum[0] {
+        return false;
+    }
+    let frequencies = &dump[NAME_LENGTH..NAME_LENGTH + NUM_NOTES * 3];
+    for (key, data) in frequencies.chunks_exact(3).enumerate() {
+        set_frequency(table, key as u8, [data[0], data[1], data[2]]);
+    }
+    true
+}
+
+/// Set the frequency of `key` from three bytes of frequency data: a semitone followed by a 14 bit
+/// fraction of a semitone.
+fn set_frequency(table: &mut TuningTable, key: u8, data: [u8; 3]) {
+    if key as usize >= NUM_NOTES || data == NO_CHANGE {
+        return;
+    }
+    let fraction = f64::from((u16::from(data[1]) << 7) | u16::from(data[2])) / 16384.0;
+    let frequency = equal_temperament(f64::from(data[0]) + fraction);
+    table.set_frequency(key, frequency as f32);
+}
+
+/// Tune every note by the cents offset of its pitch class from equal temperament. Notes left
+/// unmapped by the keyboard mapping stay silent.
+fn apply_octave(table: &mut TuningTable, cents: &[f64; 12]) {
+    for note in 0..NUM_NOTES {
+        if !table.is_mapped(note as u8) {
+            continue;
+        }
+        let semitones = (note as f64) + cents[note % 12] / 100.0;
+        table.set_frequency(note as u8, equal_temperament(semitones) as f32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f64) {
+        assert!((f64::from(actual) / expected - 1.0).abs() < 1e-5, "{} != {}", actual, expected);
+    }
+
+    fn sysex(message: &[u8]) -> Vec<u8> {
+        let mut data = vec![SYSEX_START, UNIVERSAL_REALTIME, 0x7f, MIDI_TUNING];
+        data.extend_from_slice(message);
+        data.push(SYSEX_END);
+        data
+    }
+
+    #[test]
+    fn single_note_changes_retune_listed_keys() {
+        let mut table = TuningTable::default();
+        let changes = [60, 61, 0x40, 0x00, 62, 0x7f, 0x7f, 0x7f, 100, 10, 0, 0];
+        assert!(apply_sysex(&mut table, &sysex(&[&[SINGLE_NOTE, 0, 3][..], &changes].concat())));
+        assert_close(table.frequency(60), equal_temperament(61.5));
+        assert_close(table.frequency(62), equal_temperament(62.0));
+        assert_close(table.frequency(100), equal_temperament(10.0));
+
+        let change = [SINGLE_NOTE_BANK, 1, 0, 1, 69, 57, 0, 0];
+        assert!(apply_sysex(&mut table, &sysex(&change)));
+        assert_close(table.frequency(69), 220.0);
+        assert_close(table.frequency(60), equal_temperament(61.5));
+    }
+
+    #[test]
+    fn bulk_dumps_retune_every_key() {
+        let mut message = vec![UNIVERSAL_NON_REALTIME, 0x7f, MIDI_TUNING, BULK_DUMP, 0];
+        message.extend_from_slice(b"Quarter tones   ");
+        for key in 0..NUM_NOTES as u8 {
+            let data = if key == 64 { NO_CHANGE } else { [key / 2, (key % 2) << 6, 0] };
+            message.extend_from_slice(&data);
+        }
+        let checksum = message.iter().fold(0, |sum, byte| sum ^ byte) & 0x7f;
+        message.push(checksum);
+
+        let mut table = TuningTable::default();
+        let mut corrupt = message.clone();
+        corrupt[100] ^= 1;
+        assert!(!apply_sysex(&mut table, &corrupt));
+        assert!(!apply_sysex(&mut table, &message[..message.len() - 2]));
+        assert_eq!(table, TuningTable::default());
+
+        assert!(apply_sysex(&mut table, &message));
+        assert_close(table.frequency(0), equal_temperament(0.0));
+        assert_close(table.frequency(69), equal_temperament(34.5));
+        assert_close(table.frequency(64), equal_temperament(64.0));
+    }
+
+    #[test]
+    fn octave_tunings_keep_unmapped_keys_silent() {
+        let mut table = TuningTable::default();
+        table.set_frequency(61, 0.0);
+        let mut offsets = [64; 12];
+        offsets[0] = 64 - 14;
+        let mut message = vec![SCALE_OCTAVE_1_BYTE, 0x03, 0x7f, 0x7f];
+        message.extend_from_slice(&offsets);
+        assert!(apply_sysex(&mut table, &sysex(&message)));
+        assert_close(table.frequency(60), equal_temperament(59.86));
+        assert_close(table.frequency(72), equal_temperament(71.86));
+        assert_close(table.frequency(62), equal_temperament(62.0));
+        assert!(!table.is_mapped(61));
+
+        // +50 cents on every E in the 2 byte form
+        let mut message = vec![SCALE_OCTAVE_2_BYTE, 0, 0, 1];
+        for pitch_class in 0..12 {
+            let offset: u16 = if pitch_class == 4 { 8192 + 4096 } else { 8192 };
+            message.extend_from_slice(&[(offset >> 7) as u8, (offset & 0x7f) as u8]);
+        }
+        assert!(apply_sysex(&mut table, &sysex(&message)));
+        assert_close(table.frequency(64), equal_temperament(64.5));
+        assert_close(table.frequency(60), equal_temperament(60.0));
+        assert!(!table.is_mapped(61));
+    }
+
+    #[test]
+    fn ignores_malformed_messages() {
+        let mut table = TuningTable::default();
+        for message in [
+            // a change is missing
+            sysex(&[SINGLE_NOTE, 0, 2, 60, 61, 0, 0]),
+            // data bytes with the high bit set
+            sysex(&[SINGLE_NOTE, 0, 1, 60, 0x81, 0, 0]),
+            // too few offsets
+            sysex(&[SCALE_OCTAVE_1_BYTE, 0x7f, 0x7f, 0x7f, 64, 64, 64]),
+            sysex(&[&[SCALE_OCTAVE_2_BYTE, 0x7f, 0x7f, 0x7f][..], &[0x40; 23]].concat()),
+            // no channel addressed
+            sysex(&[&[SCALE_OCTAVE_1_BYTE, 0, 0, 0][..], &[0; 12]].concat()),
+            // not a tuning message
+            vec![SYSEX_START, UNIVERSAL_REALTIME, 0x7f, 0x04, 0x01, 0, 0, SYSEX_END],
+            vec![SYSEX_START, 0x41, 0x10, SYSEX_END],
+            vec![],
+        ] {
+            assert!(!apply_sysex(&mut table, &message), "{:?}", message);
+        }
+        assert_eq!(table, TuningTable::default());
+    }
+}
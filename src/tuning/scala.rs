@@ -0,0 +1,280 @@
+//! Parsers for the Scala scale (.scl) and keyboard mapping (.kbm) file formats, as described at
+//! https://www.huygens-fokker.org/scala/scl_format.html and
+//! https://www.huygens-fokker.org/scala/help.htm#mappings
+
+use std::{ fs, path::Path };
+
+use super::{ TuningError, NUM_NOTES };
+
+/// Largest number of notes in a scale.
+pub const MAX_SCALE_SIZE: usize = 1024;
+/// Largest number of entries in a keyboard mapping, enough for one entry per key.
+pub const MAX_MAP_SIZE: usize = NUM_NOTES;
+/// Largest scale degree a keyboard mapping may name, which keeps the degree of every key within
+/// the range of an `i32`.
+pub const MAX_DEGREE: i32 = (MAX_SCALE_SIZE * NUM_NOTES) as i32;
+
+/// A scale as read from a .scl file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scale {
+    pub description: String,
+    /// Pitch of every scale degree above the root in cents. The last degree is the period of the
+    /// scale, usually an octave.
+    pub cents: Vec<f64>,
+}
+
+impl Scale {
+    /// 12-tone equal temperament.
+    pub fn equal_temperament() -> Self {
+        Self {
+            description: "12-TET".to_string(),
+            cents: (1..=12).map(|step| f64::from(step) * 100.0).collect(),
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, TuningError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, TuningError> {
+        let mut lines = data_lines(text);
+
+        let (_, description) = lines.next().ok_or(TuningError::Syntax(0, "missing description"))?;
+        let (line, count) = lines.next().ok_or(TuningError::Syntax(0, "missing note count"))?;
+        let count: usize = first_token(count)
+            .parse()
+            .map_err(|_| TuningError::Syntax(line, "invalid note count"))?;
+        if count == 0 {
+            return Err(TuningError::Syntax(line, "scale has no notes"));
+        }
+        if count > MAX_SCALE_SIZE {
+            return Err(TuningError::Syntax(line, "too many notes"));
+        }
+
+        let mut cents = Vec::with_capacity(count);
+        for _ in 0..count {
+            let (line, note) = lines.next().ok_or(TuningError::Syntax(line, "missing notes"))?;
+            let pitch = parse_pitch(first_token(note)).ok_or(
+                TuningError::Syntax(line, "invalid pitch")
+            )?;
+            cents.push(pitch);
+        }
+
+        Ok(Self { description: description.trim().to_string(), cents })
+    }
+
+    /// Pitch of any scale degree in cents relative to the root. Degrees outside the first period
+    /// are transposed by whole periods.
+    pub fn degree_cents(&self, degree: i32) -> f64 {
+        let size = self.cents.len() as i32;
+        let period = self.cents[self.cents.len() - 1];
+        let step = degree.rem_euclid(size) as usize;
+        let root = f64::from(degree.div_euclid(size)) * period;
+        if step == 0 {
+            root
+        } else {
+            root + self.cents[step - 1]
+        }
+    }
+}
+
+/// Assignment of scale degrees to MIDI keys as read from a .kbm file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyboardMapping {
+    pub first_note: u8,
+    pub last_note: u8,
+    /// Key on which the root of the scale, and the first mapping entry, is placed.
+    pub middle_note: u8,
+    pub reference_note: u8,
+    /// Frequency of `reference_note` in Hz.
+    pub reference_freq: f64,
+    /// Scale degree by which the mapping is transposed on every repetition, 0 for the size of
+    /// the scale.
+    pub octave_degree: i32,
+    /// Scale degree of every key in one repetition of the pattern, `None` for unmapped keys. An
+    /// empty mapping places consecutive scale degrees on consecutive keys.
+    pub mapping: Vec<Option<i32>>,
+}
+
+impl Default for KeyboardMapping {
+    /// Linear mapping with the root on middle C and A4 tuned to 440 Hz.
+    fn default() -> Self {
+        Self {
+            first_note: 0,
+            last_note: 127,
+            middle_note: 60,
+            reference_note: 69,
+            reference_freq: 440.0,
+            octave_degree: 0,
+            mapping: Vec::new(),
+        }
+    }
+}
+
+impl KeyboardMapping {
+    pub fn from_file(path: &Path) -> Result<Self, TuningError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, TuningError> {
+        let mut lines = data_lines(text);
+        let mut header = [0.0; 7];
+        let mut last_line = 0;
+        for value in header.iter_mut() {
+            let (line, text) = lines
+                .next()
+                .ok_or(TuningError::Syntax(last_line, "missing header"))?;
+            *value = first_token(text)
+                .parse()
+                .map_err(|_| TuningError::Syntax(line, "invalid header value"))?;
+            last_line = line;
+        }
+        let [map_size, first_note, last_note, middle_note, reference_note, reference_freq, octave] =
+            header;
+
+        let note = |value: f64| {
+            if (0.0..=127.0).contains(&value) && value.fract() == 0.0 {
+                Ok(value as u8)
+            } else {
+                Err(TuningError::Syntax(last_line, "note out of range"))
+            }
+        };
+        let is_count = |value: f64| value >= 0.0 && value.fract() == 0.0;
+        if !is_count(map_size) || !reference_freq.is_finite() || reference_freq <= 0.0 {
+            return Err(TuningError::Syntax(last_line, "invalid header value"));
+        }
+        if !is_degree(octave) {
+            return Err(TuningError::Syntax(last_line, "invalid header value"));
+        }
+        if map_size > MAX_MAP_SIZE as f64 {
+            return Err(TuningError::Syntax(last_line, "mapping too large"));
+        }
+
+        // missing entries at the end of the mapping are unmapped
+        let mut mapping = vec![None; map_size as usize];
+        for entry in mapping.iter_mut() {
+            let (line, text) = match lines.next() {
+                Some(line) => line,
+                None => {
+                    break;
+                }
+            };
+            *entry = match first_token(text) {
+                "x" | "X" => None,
+                degree => match degree.parse() {
+                    Ok(degree) if is_degree(f64::from(degree)) => Some(degree),
+                    _ => {
+                        return Err(TuningError::Syntax(line, "invalid mapping"));
+                    }
+                },
+            };
+        }
+
+        Ok(Self {
+            first_note: note(first_note)?,
+            last_note: note(last_note)?,
+            middle_note: note(middle_note)?,
+            reference_note: note(reference_note)?,
+            reference_freq,
+            octave_degree: octave as i32,
+            mapping,
+        })
+    }
+
+    /// Scale degree played by `key`, or `None` if the key is unmapped.
+    pub fn key_degree(&self, key: u8, scale_size: usize) -> Option<i32> {
+        if key < self.first_note || key > self.last_note {
+            return None;
+        }
+        let offset = i32::from(key) - i32::from(self.middle_note);
+        if self.mapping.is_empty() {
+            return Some(offset);
+        }
+
+        let size = self.mapping.len() as i32;
+        let octave_degree = if self.octave_degree == 0 {
+            scale_size as i32
+        } else {
+            self.octave_degree
+        };
+        self.mapping[offset.rem_euclid(size) as usize].map(
+            |degree| offset.div_euclid(size) * octave_degree + degree
+        )
+    }
+}
+
+/// Whether `value` is a whole scale degree that a keyboard mapping may name.
+pub fn is_degree(value: f64) -> bool {
+    value.fract() == 0.0 && value.abs() <= f64::from(MAX_DEGREE)
+}
+
+/// Non-comment lines with their line numbers, counting from 1.
+fn data_lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.starts_with('!'))
+        .map(|(index, line)| (index + 1, line))
+}
+
+/// Everything after the first value on a line is a comment.
+fn first_token(line: &str) -> &str {
+    line.split_whitespace().next().unwrap_or("")
+}
+
+/// Parse a pitch given in cents, which always contain a period, or as a ratio.
+fn parse_pitch(token: &str) -> Option<f64> {
+    if token.contains('.') {
+        return token.parse().ok().filter(|cents: &f64| cents.is_finite());
+    }
+    let (numerator, denominator): (u64, u64) = match token.split_once('/') {
+        Some((numerator, denominator)) => (numerator.parse().ok()?, denominator.parse().ok()?),
+        None => (token.parse().ok()?, 1),
+    };
+    if numerator == 0 || denominator == 0 {
+        return None;
+    }
+    Some(1200.0 * ((numerator as f64) / (denominator as f64)).log2())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(map_size: &str, entries: &str) -> Result<KeyboardMapping, TuningError> {
+        KeyboardMapping::parse(&format!("{}\n0\n127\n60\n69\n440.0\n12\n{}", map_size, entries))
+    }
+
+    #[test]
+    fn parses_files() {
+        let scale = Scale::parse("! comment\nQuarter comma meantone\n 2\n193.157\n2/1 octave\n");
+        let scale = scale.unwrap();
+        assert_eq!(scale.description, "Quarter comma meantone");
+        assert_eq!(scale.cents, vec![193.157, 1200.0]);
+
+        let keyboard = mapping("3", "0\nx\n").unwrap();
+        assert_eq!(keyboard.mapping, vec![Some(0), None, None]);
+        assert_eq!(keyboard.octave_degree, 12);
+        assert_eq!(keyboard.key_degree(63, 12), Some(12));
+        assert_eq!(keyboard.key_degree(64, 12), None);
+    }
+
+    #[test]
+    fn rejects_oversized_and_malformed_files() {
+        let notes = "100.0\n".repeat(MAX_SCALE_SIZE + 1);
+        let scale = format!("Too large\n{}\n{}", MAX_SCALE_SIZE + 1, notes);
+        assert!(matches!(Scale::parse(&scale), Err(TuningError::Syntax(2, _))));
+        assert!(Scale::parse(&format!("Huge\n{}\n", usize::MAX)).is_err());
+
+        assert!(mapping(&MAX_MAP_SIZE.to_string(), "").is_ok());
+        for map_size in ["129", "1e12", "2.5", "-1", "NaN", "inf"].iter() {
+            assert!(
+                matches!(mapping(map_size, ""), Err(TuningError::Syntax(7, _))),
+                "map size {}",
+                map_size
+            );
+        }
+        assert!(matches!(mapping("2", "0\n2147483647\n"), Err(TuningError::Syntax(9, _))));
+        assert!(KeyboardMapping::parse("1\n0\n127\n60\n69\n440.0\n1e10\n0\n").is_err());
+        assert!(Scale::parse("Huge steps\n2\n1.0e400\n1200.0\n").is_err());
+    }
+}
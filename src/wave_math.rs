@@ -3,19 +3,6 @@ use std::f32::consts::PI;
 pub const TAU: f32 = PI * 2.0;
 
 
-pub fn parameter_to_u8(value:f32, steps:i32) -> u8 {
-    (value * steps as f32).round() as u8
-}
-/// Convert the midi note's pitch into the equivalent frequency.
-///
-/// This function assumes A4 is 440hz.
-pub fn midi_pitch_to_freq(pitch: u8) -> f32 {
-    const A4_PITCH: i8 = 69;
-    const A4_FREQ: f32 = 440.0;
-    // Midi notes can be 0-127
-    (f32::from((pitch as i8) - A4_PITCH) / 12.0).exp2() * A4_FREQ
-}
-
 pub fn generate_sine_wave(time: f32, base_freq: f32, amp: f32) -> f32 {
     (time * TAU * base_freq).sin() * amp
 }